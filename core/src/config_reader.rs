@@ -2,15 +2,20 @@ use anyhow::{anyhow, Result};
 use async_openai::types::FunctionObject;
 use serde::Deserialize;
 use serde_yaml::{self, Value};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-use crate::{state_key, LLMConfig};
+use crate::{
+    config_key, Budget, Condition, ContextWindow, ErrorPolicy, LLMConfig, StepAction, WorkflowConfig, WorkflowStep, ModelPricing, ResponseFormatConfig, SummarizationConfig,
+    HttpToolConfig, McpServerConfig, McpTransport, ProviderConfig, SandboxConfig, ToolCallMode, ToolExecutionConfig, ToolSettings, WindowStrategy,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigReader;
 
 impl ConfigReader {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new<P: AsRef<Path>>(path: P) -> Result<LLMConfig> {
         let config_str = fs::read_to_string(path)?;
         let config: Value = serde_yaml::from_str(&config_str)?;
@@ -24,6 +29,10 @@ impl ConfigReader {
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow!("Missing or invalid id field"))?;
         
+        let description = orchestrator.get("description")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+
         let system_prompt = orchestrator.get("system_prompt")
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow!("Missing or invalid system_prompt field"))?;
@@ -41,29 +50,136 @@ impl ConfigReader {
             .ok_or_else(|| anyhow!("Missing or invalid max_tokens field"))?;
         
         // Extract tools if they exist
-        let functions = if let Some(tools) = orchestrator.get("tools") {
-            if let Some(tools_array) = tools.as_sequence() {
-                tools_array.iter()
-                    .map(|tool| parse_tool(tool))
-                    .collect::<Result<Vec<FunctionObject>>>()?
-            } else {
-                Vec::new()
-            }
-        } else {
-            Vec::new()
+        let tools_array = orchestrator.get("tools")
+            .and_then(|tools| tools.as_sequence())
+            .map(|tools| tools.as_slice())
+            .unwrap_or_default();
+
+        let functions = tools_array.iter()
+            .map(parse_tool)
+            .collect::<Result<Vec<FunctionObject>>>()?;
+
+        let mut tool_settings = functions.iter()
+            .zip(tools_array)
+            .map(|(function, tool)| (function.name.clone(), parse_tool_settings(tool)))
+            .collect::<HashMap<_, _>>();
+
+        // Pricing is shared by every agent in the file, keyed by model name
+        let pricing = match config.get("pricing") {
+            Some(pricing) => parse_pricing(pricing)?,
+            None => HashMap::new(),
         };
 
+        let budget = orchestrator.get("budget")
+            .map(parse_budget)
+            .transpose()?;
+
+        let context_window = orchestrator.get("context_window")
+            .map(parse_context_window)
+            .transpose()?
+            .unwrap_or_default();
+
+        let summarization = orchestrator.get("summarization")
+            .map(parse_summarization)
+            .transpose()?;
+
+        let response_format = orchestrator.get("response_format")
+            .map(parse_response_format)
+            .transpose()?;
+
+        let tool_execution = orchestrator.get("tool_execution")
+            .map(parse_tool_execution)
+            .transpose()?
+            .unwrap_or_default();
+
+        let error_policy = orchestrator.get("error_policy")
+            .map(parse_error_policy)
+            .transpose()?
+            .unwrap_or_default();
+
+        let builtin_tools = match orchestrator.get("builtin_tools") {
+            Some(tools) => parse_builtin_tools(tools)?,
+            None => Vec::new(),
+        };
+        // Built-in tools take the same settings as the tools defined here
+        let builtin_tools = builtin_tools.into_iter()
+            .map(|(name, settings)| {
+                if let Some(settings) = settings {
+                    tool_settings.insert(name.clone(), settings);
+                }
+                name
+            })
+            .collect();
+
+        let sandbox = orchestrator.get("sandbox")
+            .map(parse_sandbox)
+            .transpose()?
+            .unwrap_or_default();
+
+        let http_tools = orchestrator.get("http_tools")
+            .map(parse_http_tools)
+            .transpose()?
+            .unwrap_or_default();
+
+        let mcp_servers = match orchestrator.get("mcp_servers") {
+            Some(servers) => servers.as_sequence()
+                .ok_or_else(|| anyhow!("mcp_servers must be a list"))?
+                .iter()
+                .map(parse_mcp_server)
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        let provider = orchestrator.get("provider")
+            .map(parse_provider)
+            .transpose()?
+            .unwrap_or_default();
+
         Ok(LLMConfig {
-            id: state_key!(id),
+            id: config_key(id).hash(),
+            name: id.to_string(),
+            description,
             system_prompt: system_prompt.to_string(),
             openai_model: model.to_string(),
             openai_temperature: temperature as f32,
             openai_max_tokens: max_tokens as u16,
             functions,
+            tool_settings,
+            pricing,
+            budget,
+            context_window,
+            summarization,
+            response_format,
+            tool_execution,
+            error_policy,
+            builtin_tools,
+            sandbox,
+            http_tools,
+            mcp_servers,
+            provider,
         })
     }
 }
 
+impl ConfigReader {
+    /// The workflows of the config file, in the order they are defined.
+    pub fn workflows<P: AsRef<Path>>(path: P) -> Result<Vec<WorkflowConfig>> {
+        let config: Value = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+        let Some(workflows) = config.get("workflows") else {
+            return Ok(Vec::new());
+        };
+
+        workflows.as_mapping()
+            .ok_or_else(|| anyhow!("workflows must be a mapping of workflow name to workflow"))?
+            .iter()
+            .map(|(name, workflow)| {
+                let name = name.as_str().ok_or_else(|| anyhow!("Workflow name must be a string"))?;
+                parse_workflow(name, workflow)
+            })
+            .collect()
+    }
+}
+
 fn parse_tool(tool: &Value) -> Result<FunctionObject> {
     let name = tool.get("name")
         .and_then(|v| v.as_str())
@@ -91,4 +207,479 @@ fn parse_tool(tool: &Value) -> Result<FunctionObject> {
         parameters: Some(parameters),
         strict: Some(strict),
     })
-} 
\ No newline at end of file
+}
+
+fn parse_tool_settings(tool: &Value) -> ToolSettings {
+    let requires_approval = tool.get("requires_approval")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    ToolSettings { requires_approval }
+}
+
+/// Entries are either a tool name or a mapping with the name and its settings.
+fn parse_builtin_tools(tools: &Value) -> Result<Vec<(String, Option<ToolSettings>)>> {
+    tools.as_sequence()
+        .ok_or_else(|| anyhow!("builtin_tools must be a list"))?
+        .iter()
+        .map(|tool| match tool.as_str() {
+            Some(name) => Ok((name.to_string(), None)),
+            None => {
+                let name = tool.get("name")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Invalid entry in builtin_tools"))?;
+                Ok((name.to_string(), Some(parse_tool_settings(tool))))
+            }
+        })
+        .collect()
+}
+
+fn parse_pricing(pricing: &Value) -> Result<HashMap<String, ModelPricing>> {
+    let table = pricing.as_mapping()
+        .ok_or_else(|| anyhow!("pricing must be a mapping of model name to prices"))?;
+
+    table.iter()
+        .map(|(model, prices)| {
+            let model = model.as_str()
+                .ok_or_else(|| anyhow!("Pricing model name must be a string"))?;
+
+            let prompt = prices.get("prompt")
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| anyhow!("Missing or invalid prompt price for {}", model))?;
+
+            let completion = prices.get("completion")
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| anyhow!("Missing or invalid completion price for {}", model))?;
+
+            Ok((model.to_string(), ModelPricing {
+                prompt_per_million: prompt,
+                completion_per_million: completion,
+            }))
+        })
+        .collect()
+}
+
+fn parse_budget(budget: &Value) -> Result<Budget> {
+    let max_total_tokens = match budget.get("max_total_tokens") {
+        Some(v) => Some(v.as_u64().ok_or_else(|| anyhow!("Invalid max_total_tokens in budget"))?),
+        None => None,
+    };
+
+    let max_cost = match budget.get("max_cost") {
+        Some(v) => Some(v.as_f64().ok_or_else(|| anyhow!("Invalid max_cost in budget"))?),
+        None => None,
+    };
+
+    Ok(Budget { max_total_tokens, max_cost })
+}
+
+fn parse_context_window(window: &Value) -> Result<ContextWindow> {
+    let strategy = window.get("strategy")
+        .and_then(|v| v.as_str())
+        .unwrap_or("full");
+
+    let strategy = match strategy {
+        "full" => WindowStrategy::Full,
+        "last_turns" => {
+            let turns = window.get("turns")
+                .and_then(|v| v.as_u64())
+                .ok_or_else(|| anyhow!("last_turns strategy requires a turns field"))?;
+            WindowStrategy::LastTurns { turns: turns as usize }
+        }
+        "token_budget" => {
+            let max_tokens = window.get("max_tokens")
+                .and_then(|v| v.as_u64())
+                .ok_or_else(|| anyhow!("token_budget strategy requires a max_tokens field"))?;
+            WindowStrategy::TokenBudget { max_tokens: max_tokens as usize }
+        }
+        other => return Err(anyhow!("Unknown context_window strategy: {}", other)),
+    };
+
+    let pin_first_turn = window.get("pin_first_turn")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    Ok(ContextWindow { strategy, pin_first_turn })
+}
+
+fn parse_summarization(summarization: &Value) -> Result<SummarizationConfig> {
+    let model = summarization.get("model")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing or invalid summarization model field"))?;
+
+    let threshold_turns = summarization.get("threshold_turns")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow!("Missing or invalid summarization threshold_turns field"))?;
+
+    let keep_recent_turns = summarization.get("keep_recent_turns")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+
+    if keep_recent_turns >= threshold_turns {
+        return Err(anyhow!("summarization keep_recent_turns must be lower than threshold_turns"));
+    }
+
+    let max_tokens = summarization.get("max_tokens")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1000);
+
+    let prompt = summarization.get("prompt")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    Ok(SummarizationConfig {
+        model: model.to_string(),
+        threshold_turns: threshold_turns as usize,
+        keep_recent_turns: keep_recent_turns as usize,
+        max_tokens: max_tokens as u16,
+        prompt,
+    })
+}
+
+fn parse_response_format(format: &Value) -> Result<ResponseFormatConfig> {
+    let name = format.get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing or invalid response_format name field"))?;
+
+    let description = format.get("description")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    let schema = format.get("schema")
+        .ok_or_else(|| anyhow!("Missing response_format schema field"))?;
+
+    let strict = format.get("strict")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let max_retries = format.get("max_retries")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(2);
+
+    let native = format.get("native")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+
+    Ok(ResponseFormatConfig {
+        name: name.to_string(),
+        description,
+        schema: serde_json::to_value(schema)?,
+        strict,
+        max_retries: max_retries as usize,
+        native,
+    })
+}
+
+fn parse_error_policy(policy: &Value) -> Result<ErrorPolicy> {
+    let defaults = ErrorPolicy::default();
+    let field = |name: &str, default: u64| -> Result<u64> {
+        match policy.get(name) {
+            Some(v) => v.as_u64().ok_or_else(|| anyhow!("Invalid error_policy {} field", name)),
+            None => Ok(default),
+        }
+    };
+
+    Ok(ErrorPolicy {
+        retries: field("retries", defaults.retries as u64)? as usize,
+        retry_delay_ms: field("retry_delay_ms", defaults.retry_delay_ms)?,
+        skip: match policy.get("skip") {
+            Some(v) => v.as_bool().ok_or_else(|| anyhow!("Invalid error_policy skip field"))?,
+            None => defaults.skip,
+        },
+    })
+}
+
+fn parse_tool_execution(execution: &Value) -> Result<ToolExecutionConfig> {
+    let defaults = ToolExecutionConfig::default();
+    let field = |name: &str, default: u64| -> Result<u64> {
+        match execution.get(name) {
+            Some(v) => v.as_u64().ok_or_else(|| anyhow!("Invalid tool_execution {} field", name)),
+            None => Ok(default),
+        }
+    };
+
+    let max_concurrency = field("max_concurrency", defaults.max_concurrency as u64)?;
+    if max_concurrency == 0 {
+        return Err(anyhow!("tool_execution max_concurrency must be at least 1"));
+    }
+
+    Ok(ToolExecutionConfig {
+        max_concurrency: max_concurrency as usize,
+        timeout_secs: field("timeout_secs", defaults.timeout_secs)?,
+        max_rounds: field("max_rounds", defaults.max_rounds as u64)? as usize,
+    })
+}
+
+fn parse_sandbox(sandbox: &Value) -> Result<SandboxConfig> {
+    let defaults = SandboxConfig::default();
+    let field = |name: &str, default: u64| -> Result<u64> {
+        match sandbox.get(name) {
+            Some(v) => v.as_u64().ok_or_else(|| anyhow!("Invalid sandbox {} field", name)),
+            None => Ok(default),
+        }
+    };
+
+    let root = match sandbox.get("root") {
+        Some(v) => v.as_str().ok_or_else(|| anyhow!("Invalid sandbox root field"))?.to_string(),
+        None => defaults.root,
+    };
+
+    let timeout_secs = field("timeout_secs", defaults.timeout_secs)?;
+    let max_output_bytes = field("max_output_bytes", defaults.max_output_bytes as u64)? as usize;
+
+    let allowed_commands = match sandbox.get("allowed_commands") {
+        Some(commands) => parse_string_list(commands, "sandbox allowed_commands")?,
+        None => defaults.allowed_commands,
+    };
+
+    Ok(SandboxConfig { root, timeout_secs, max_output_bytes, allowed_commands })
+}
+
+fn parse_http_tools(http: &Value) -> Result<HttpToolConfig> {
+    let defaults = HttpToolConfig::default();
+    let field = |name: &str, default: u64| -> Result<u64> {
+        match http.get(name) {
+            Some(v) => v.as_u64().ok_or_else(|| anyhow!("Invalid http_tools {} field", name)),
+            None => Ok(default),
+        }
+    };
+
+    let allowed_domains = match http.get("allowed_domains") {
+        Some(domains) => parse_string_list(domains, "http_tools allowed_domains")?,
+        None => defaults.allowed_domains,
+    };
+
+    let denied_domains = match http.get("denied_domains") {
+        Some(domains) => parse_string_list(domains, "http_tools denied_domains")?,
+        None => defaults.denied_domains,
+    };
+
+    let max_response_bytes = field("max_response_bytes", defaults.max_response_bytes as u64)? as usize;
+    let max_redirects = field("max_redirects", defaults.max_redirects as u64)? as usize;
+    let timeout_secs = field("timeout_secs", defaults.timeout_secs)?;
+
+    let extract_text = match http.get("extract_text") {
+        Some(v) => v.as_bool().ok_or_else(|| anyhow!("Invalid http_tools extract_text field"))?,
+        None => defaults.extract_text,
+    };
+
+    Ok(HttpToolConfig {
+        allowed_domains,
+        denied_domains,
+        max_response_bytes,
+        max_redirects,
+        timeout_secs,
+        extract_text,
+    })
+}
+
+fn parse_mcp_server(server: &Value) -> Result<McpServerConfig> {
+    let name = server.get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("MCP server missing name"))?;
+
+    let transport = match server.get("transport").and_then(|v| v.as_str()) {
+        Some("stdio") => {
+            let command = server.get("command")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("MCP server {} missing command", name))?;
+
+            let args = match server.get("args") {
+                Some(args) => parse_string_list(args, "MCP server args")?,
+                None => Vec::new(),
+            };
+
+            let env = match server.get("env") {
+                Some(env) => env.as_mapping()
+                    .ok_or_else(|| anyhow!("MCP server {} env must be a mapping", name))?
+                    .iter()
+                    .map(|(key, value)| match (key.as_str(), value.as_str()) {
+                        (Some(key), Some(value)) => Ok((key.to_string(), value.to_string())),
+                        _ => Err(anyhow!("Invalid env entry for MCP server {}", name)),
+                    })
+                    .collect::<Result<HashMap<_, _>>>()?,
+                None => HashMap::new(),
+            };
+
+            McpTransport::Stdio { command: command.to_string(), args, env }
+        }
+        Some("http") => {
+            let url = server.get("url")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("MCP server {} missing url", name))?;
+
+            McpTransport::Http { url: url.to_string() }
+        }
+        Some(other) => return Err(anyhow!("Unknown transport {} for MCP server {}", other, name)),
+        None => return Err(anyhow!("MCP server {} missing transport", name)),
+    };
+
+    let tool_prefix = server.get("tool_prefix")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    let requires_approval = server.get("requires_approval")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let timeout_secs = match server.get("timeout_secs") {
+        Some(v) => v.as_u64().ok_or_else(|| anyhow!("Invalid timeout_secs for MCP server {}", name))?,
+        None => 30,
+    };
+
+    Ok(McpServerConfig {
+        name: name.to_string(),
+        transport,
+        tool_prefix,
+        requires_approval,
+        timeout_secs,
+    })
+}
+
+fn parse_provider(provider: &Value) -> Result<ProviderConfig> {
+    let kind = provider.get("type")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("provider missing type"))?;
+
+    let base_url = provider.get("base_url")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    let api_key_env = provider.get("api_key_env")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    let tool_calls = match provider.get("tool_calls").and_then(|v| v.as_str()) {
+        Some("auto") | None => ToolCallMode::Auto,
+        Some("native") => ToolCallMode::Native,
+        Some("prompt") => ToolCallMode::Prompt,
+        Some(other) => return Err(anyhow!("Unknown tool_calls mode {}", other)),
+    };
+
+    match kind {
+        "default" => Ok(ProviderConfig::Default),
+        "openai" => Ok(ProviderConfig::OpenAi { base_url, api_key_env }),
+        "anthropic" => Ok(ProviderConfig::Anthropic { base_url, api_key_env }),
+        "ollama" => Ok(ProviderConfig::Ollama { base_url, tool_calls }),
+        "llama_cpp" => Ok(ProviderConfig::LlamaCpp { base_url, api_key_env, tool_calls }),
+        other => Err(anyhow!("Unknown provider type {}", other)),
+    }
+}
+
+fn parse_string_list(list: &Value, field: &str) -> Result<Vec<String>> {
+    list.as_sequence()
+        .ok_or_else(|| anyhow!("{} must be a list", field))?
+        .iter()
+        .map(|item| item.as_str()
+            .map(|item| item.to_string())
+            .ok_or_else(|| anyhow!("Invalid entry in {}", field)))
+        .collect()
+}
+
+fn parse_workflow(name: &str, workflow: &Value) -> Result<WorkflowConfig> {
+    let steps = workflow.get("steps")
+        .ok_or_else(|| anyhow!("Workflow {} missing steps", name))?;
+
+    Ok(WorkflowConfig {
+        name: name.to_string(),
+        description: workflow.get("description").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        agent: workflow.get("agent").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        steps: parse_workflow_steps(steps, name)?,
+    })
+}
+
+fn parse_workflow_steps(steps: &Value, parent: &str) -> Result<Vec<WorkflowStep>> {
+    let steps = steps.as_sequence()
+        .ok_or_else(|| anyhow!("Steps of {} must be a list", parent))?
+        .iter()
+        .map(|step| parse_workflow_step(step, parent))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, step) in steps.iter().enumerate() {
+        if steps[..i].iter().any(|other| other.id == step.id) {
+            return Err(anyhow!("Step id {} is used twice in {}", step.id, parent));
+        }
+    }
+    Ok(steps)
+}
+
+fn parse_workflow_step(step: &Value, parent: &str) -> Result<WorkflowStep> {
+    let id = step.get("id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Step in {} missing id", parent))?;
+    // Progress is recorded by paths such as `outer[2]/inner`
+    if id.is_empty() || id.contains(['/', '[', ']']) {
+        return Err(anyhow!("Invalid step id {:?} in {}", id, parent));
+    }
+    let text = |section: &Value, name: &str| section.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let required = |section: &Value, name: &str| text(section, name)
+        .ok_or_else(|| anyhow!("Step {} missing {}", id, name));
+
+    let action = if let Some(llm) = step.get("llm") {
+        StepAction::Llm { agent: text(llm, "agent"), prompt: required(llm, "prompt")?, output: text(llm, "output") }
+    } else if let Some(tool) = step.get("tool") {
+        let arguments = match tool.get("arguments") {
+            Some(arguments) => serde_json::to_value(arguments)?,
+            None => serde_json::json!({}),
+        };
+        StepAction::Tool { agent: text(tool, "agent"), name: required(tool, "name")?, arguments, output: text(tool, "output") }
+    } else if let Some(branch) = step.get("branch") {
+        let condition = branch.get("if").ok_or_else(|| anyhow!("Step {} missing if", id))?;
+        let arm = |name: &str| match branch.get(name) {
+            Some(steps) => parse_workflow_steps(steps, id),
+            None => Ok(Vec::new()),
+        };
+        StepAction::Branch { condition: parse_condition(condition)?, then: arm("then")?, otherwise: arm("else")? }
+    } else if let Some(repeat) = step.get("loop") {
+        let until = repeat.get("until").ok_or_else(|| anyhow!("Step {} missing until", id))?;
+        let max_iterations = repeat.get("max_iterations")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| anyhow!("Step {} missing max_iterations", id))?;
+        let steps = repeat.get("steps").ok_or_else(|| anyhow!("Step {} missing steps", id))?;
+        StepAction::Loop { until: parse_condition(until)?, max_iterations: max_iterations as usize, steps: parse_workflow_steps(steps, id)? }
+    } else if let Some(map) = step.get("map") {
+        let steps = map.get("steps").ok_or_else(|| anyhow!("Step {} missing steps", id))?;
+        StepAction::Map {
+            over: required(map, "over")?,
+            item: text(map, "as").unwrap_or_else(|| "item".to_string()),
+            steps: parse_workflow_steps(steps, id)?,
+            collect: text(map, "collect"),
+            output: text(map, "output"),
+        }
+    } else {
+        return Err(anyhow!("Step {} needs one of llm, tool, branch, loop or map", id));
+    };
+
+    Ok(WorkflowStep { id: id.to_string(), action })
+}
+
+fn parse_condition(condition: &Value) -> Result<Condition> {
+    let var = || condition.get("var")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow!("Condition missing var"));
+    let list = |conditions: &Value| -> Result<Vec<Condition>> {
+        conditions.as_sequence()
+            .ok_or_else(|| anyhow!("all and any take a list of conditions"))?
+            .iter()
+            .map(parse_condition)
+            .collect()
+    };
+
+    if let Some(name) = condition.get("exists") {
+        let name = name.as_str().ok_or_else(|| anyhow!("exists takes a variable name"))?;
+        Ok(Condition::Exists(name.to_string()))
+    } else if let Some(value) = condition.get("equals") {
+        Ok(Condition::Equals { var: var()?, value: serde_json::to_value(value)? })
+    } else if let Some(value) = condition.get("contains") {
+        Ok(Condition::Contains { var: var()?, value: serde_json::to_value(value)? })
+    } else if let Some(inner) = condition.get("not") {
+        Ok(Condition::Not(Box::new(parse_condition(inner)?)))
+    } else if let Some(conditions) = condition.get("all") {
+        Ok(Condition::All(list(conditions)?))
+    } else if let Some(conditions) = condition.get("any") {
+        Ok(Condition::Any(list(conditions)?))
+    } else {
+        Err(anyhow!("Condition needs one of exists, equals, contains, not, all or any"))
+    }
+}
@@ -9,10 +9,26 @@ use crate::CryptoHash;
 
 pub fn blake3_hash(input: &[u8]) -> CryptoHash {
     let hash = blake3::hash(input);
-    CryptoHash::new(hash.as_bytes().clone())
+    CryptoHash::new(*hash.as_bytes())
 }
 
 pub fn encrypt(text: &str, key: &str) -> Result<String> {
+    Ok(URL_SAFE_NO_PAD.encode(encrypt_bytes(text.as_bytes(), key)?))
+}
+
+pub fn decrypt(encrypted: &str, key: &str) -> Result<String> {
+    // Decode the base64 input
+    let encrypted_bytes = URL_SAFE_NO_PAD
+        .decode(encrypted)
+        .map_err(|e| anyhow!("invalid base64: {}", e))?;
+
+    // Convert back to string
+    String::from_utf8(decrypt_bytes(&encrypted_bytes, key)?)
+        .map_err(|e| anyhow!("invalid utf8: {}", e))
+}
+
+/// Encrypts `data` with a key derived from `key`; the output starts with the nonce.
+pub fn encrypt_bytes(data: &[u8], key: &str) -> Result<Vec<u8>> {
     // Create a key from the provided secret
     let key_bytes = blake3::hash(key.as_bytes());
     let cipher = XSalsa20Poly1305::new(key_bytes.as_bytes().into());
@@ -20,41 +36,32 @@ pub fn encrypt(text: &str, key: &str) -> Result<String> {
     // Generate a random 24-byte nonce
     let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
 
-    // Encrypt the text
+    // Encrypt the data
     let ciphertext = cipher
-        .encrypt(&nonce, text.as_bytes())
+        .encrypt(&nonce, data)
         .map_err(|e| anyhow!("encryption failed: {}", e))?;
 
-    // Combine nonce and ciphertext and encode as base64
+    // Combine nonce and ciphertext
     let mut combined = nonce.to_vec();
     combined.extend(ciphertext);
-    Ok(URL_SAFE_NO_PAD.encode(combined))
+    Ok(combined)
 }
 
-pub fn decrypt(encrypted: &str, key: &str) -> Result<String> {
+pub fn decrypt_bytes(encrypted: &[u8], key: &str) -> Result<Vec<u8>> {
     // Create a key from the provided secret
     let key_bytes = blake3::hash(key.as_bytes());
     let cipher = XSalsa20Poly1305::new(key_bytes.as_bytes().into());
 
-    // Decode the base64 input
-    let encrypted_bytes = URL_SAFE_NO_PAD
-        .decode(encrypted)
-        .map_err(|e| anyhow!("invalid base64: {}", e))?;
-
     // Split into nonce and ciphertext
-    if encrypted_bytes.len() < 24 {
+    if encrypted.len() < 24 {
         return Err(anyhow!("invalid encrypted data"));
     }
-    let (nonce, ciphertext) = encrypted_bytes.split_at(24);
+    let (nonce, ciphertext) = encrypted.split_at(24);
 
-    // Decrypt the text
-    let plaintext = cipher
+    // Decrypt the data
+    cipher
         .decrypt(nonce.into(), ciphertext)
-        .map_err(|e| anyhow!("decryption failed: {}", e))?;
-
-    // Convert back to string
-    String::from_utf8(plaintext)
-        .map_err(|e| anyhow!("invalid utf8: {}", e))
+        .map_err(|e| anyhow!("decryption failed: {}", e))
 }
 
 #[cfg(test)]
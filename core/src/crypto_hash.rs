@@ -1,3 +1,4 @@
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
@@ -22,10 +23,6 @@ impl CryptoHash {
         self.hash
     }
 
-    pub fn to_string(&self) -> String {
-        hex::encode(self.hash())
-    }
-
     pub fn from_string(str: &str) -> Result<Self> {
         let hash = hex::decode(str)?;
         Ok(
@@ -36,6 +33,12 @@ impl CryptoHash {
     }
 }
 
+impl fmt::Display for CryptoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.hash()))
+    }
+}
+
 impl Hash for CryptoHash {
     fn hash<H: Hasher>(&self, state: &mut H) {
         state.write(&self.hash());
@@ -49,6 +52,6 @@ mod tests {
     #[test]
     fn test_crypto_hash() {
         let hash = CryptoHash::random();
-        println!("{}", hash.to_string());
+        println!("{}", hash);
     }
 }
\ No newline at end of file
@@ -0,0 +1,111 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto_hash::CryptoHash;
+
+/// Who wrote a state entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Author {
+    User,
+    Model,
+    Tool,
+    /// The runtime itself: configs, usage, bookkeeping.
+    #[default]
+    System,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// Unix timestamp in milliseconds; 0 for entries written before metadata.
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub author: Author,
+    /// The instruction whose diff wrote the entry, e.g. `llm_instruction[3]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instruction: Option<String>,
+    /// Id of a sub-state the entry refers to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_state: Option<CryptoHash>,
+}
+
+impl EntryMeta {
+    pub fn new(author: Author) -> Self {
+        let now = unix_millis();
+        Self { created_at: now, updated_at: now, author, instruction: None, sub_state: None }
+    }
+}
+
+/// A stored value with its metadata.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredEntry<T>", bound(deserialize = "T: Deserialize<'de>"))]
+pub struct Entry<T> {
+    pub value: T,
+    pub meta: EntryMeta,
+}
+
+/// States written before metadata stored bare values.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntry<T> {
+    Entry { value: T, meta: EntryMeta },
+    Bare(T),
+}
+
+impl<T> From<StoredEntry<T>> for Entry<T> {
+    fn from(stored: StoredEntry<T>) -> Self {
+        match stored {
+            StoredEntry::Entry { value, meta } => Entry { value, meta },
+            StoredEntry::Bare(value) => Entry { value, meta: EntryMeta::default() },
+        }
+    }
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{State, StateDiff, StateKey};
+
+    #[test]
+    fn test_diff_writes_metadata() {
+        let question = StateKey::indexed("llm", "user_message", 0);
+        let answer = StateKey::indexed("llm", "assistant_message", 0);
+        let usage = StateKey::indexed("llm", "usage", 0);
+        let branch = StateKey::single("llm", "branch");
+
+        let mut state = State::<String>::default();
+        let mut diff = StateDiff::by(Author::User);
+        diff.insert(question.clone(), "hi".to_string());
+        let mut response = StateDiff::by(Author::Model);
+        response.insert(answer.clone(), "hello".to_string());
+        response.insert_by(Author::System, usage.clone(), "12 tokens".to_string());
+        response.insert(branch.clone(), "main".to_string());
+        response.refer(&branch, CryptoHash::default());
+        diff.merge(response);
+        diff.set_instruction("llm_instruction[0]");
+        diff.apply(&mut state).unwrap();
+
+        let meta = |state: &State<String>, key: &StateKey| state.meta(key).unwrap().clone();
+        assert_eq!(meta(&state, &question).author, Author::User);
+        assert_eq!(meta(&state, &answer).author, Author::Model);
+        assert_eq!(meta(&state, &usage).author, Author::System);
+        assert_eq!(meta(&state, &answer).instruction.as_deref(), Some("llm_instruction[0]"));
+        assert_eq!(meta(&state, &branch).sub_state, Some(CryptoHash::default()));
+        assert!(meta(&state, &question).created_at > 0);
+
+        // Updates keep the creation time
+        let created_at = meta(&state, &question).created_at;
+        state.storage.get_mut(&question.hash()).unwrap().meta.created_at = created_at - 1;
+        let mut edit = StateDiff::by(Author::User);
+        edit.update(question.clone(), "hi again".to_string());
+        edit.apply(&mut state).unwrap();
+        assert_eq!(meta(&state, &question).created_at, created_at - 1);
+        assert!(meta(&state, &question).updated_at >= created_at);
+        assert_eq!(meta(&state, &question).instruction, None);
+    }
+}
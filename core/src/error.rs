@@ -0,0 +1,22 @@
+/// Errors of instructions and state updates, whatever their original type.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Why a runtime stopped executing its queue.
+#[derive(Debug, thiserror::Error)]
+pub enum RuntimeError {
+    #[error("cannot prepare {instruction}: {source}")]
+    Prepare { instruction: String, source: BoxError },
+    /// An infallible instruction failed, which halts the queue.
+    #[error("{instruction} failed: {source}")]
+    Halted { instruction: String, source: BoxError },
+    /// A fallible instruction still failed after its retries. The failure
+    /// is recorded in state.
+    #[error("{instruction} failed after {attempts} attempts: {source}")]
+    Failed { instruction: String, attempts: usize, source: BoxError },
+    /// The runtime cannot go on, whichever instruction comes next, e.g.
+    /// because the budget is used up.
+    #[error("queue stopped: {0}")]
+    Stopped(BoxError),
+    #[error("cannot update state: {0}")]
+    State(BoxError),
+}
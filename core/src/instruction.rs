@@ -1,13 +1,18 @@
-use crate::{crypto_hash::CryptoHash, state::State};
+use crate::{crypto_hash::CryptoHash, error::BoxError, state::State};
 
 pub trait Instruction<T>: Clone + Send + Sync + 'static {
     const INSTRUCTION_NAME: &'static str;
+    /// Failures of fallible instructions are retried, recorded in state and
+    /// possibly skipped, as their error policy says. Infallible instructions
+    /// halt the queue on the first failure.
     const FALLIBLE: bool;
 
-    type Error;
+    type Error: Into<BoxError>;
 
     fn parse_from(value: T, system_config_hash: CryptoHash) -> Self;
     fn parse_into(&self) -> T;
+    /// The config passed to `parse_from`.
+    fn system_config_hash(&self) -> CryptoHash;
 
     fn prepare(&mut self, state: &State<T>) -> Result<(), Self::Error>;
 }
@@ -15,10 +20,14 @@ pub trait Instruction<T>: Clone + Send + Sync + 'static {
 /// A macro that creates a state key by hashing the provided string and optional index.
 ///
 /// This macro uses blake3_hash to create a CryptoHash from the combined input.
+/// Such keys are not indexed and cannot be listed or decoded; new entries
+/// should use [`StateKey`](crate::StateKey). The macro remains for reading
+/// states written before typed keys.
 ///
 /// # Examples
 ///
 /// ```
+/// # use waterfall_core::state_key;
 /// let key = state_key!("user_message");
 /// let indexed_key = state_key!("user_message", 5);
 /// ```
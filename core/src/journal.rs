@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto_hash::CryptoHash;
+use crate::entry::unix_millis;
+use crate::instruction::Instruction;
+use crate::state::{State, StateDiff};
+use crate::state_key::StateKey;
+
+/// One executed instruction and what it wrote.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry<T: Clone> {
+    pub sequence: usize,
+    /// `INSTRUCTION_NAME` of the instruction.
+    pub instruction: String,
+    pub system_config_hash: CryptoHash,
+    /// The instruction as written by `parse_into`.
+    pub input: T,
+    /// `State::root` before the instruction ran.
+    pub state_root: CryptoHash,
+    /// Every diff the instruction applied, merged.
+    pub diff: StateDiff<T>,
+    pub recorded_at: u64,
+}
+
+impl<T: Clone> JournalEntry<T> {
+    /// The instruction again, as `parse_from` reads it back.
+    pub fn instruction<IX: Instruction<T>>(&self) -> Result<IX> {
+        if self.instruction != IX::INSTRUCTION_NAME {
+            return Err(anyhow!("Journal entry {} holds a {}, not a {}", self.sequence, self.instruction, IX::INSTRUCTION_NAME));
+        }
+        Ok(IX::parse_from(self.input.clone(), self.system_config_hash.clone()))
+    }
+}
+
+/// The instructions a runtime executed, in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal<T: Clone> {
+    pub entries: Vec<JournalEntry<T>>,
+}
+
+impl<T: Clone> Default for Journal<T> {
+    fn default() -> Self {
+        Self { entries: Vec::new() }
+    }
+}
+
+impl<T: Clone> Journal<T> {
+    pub fn record<IX: Instruction<T>>(&mut self, instruction: &IX, state_root: CryptoHash, diff: StateDiff<T>) {
+        self.entries.push(JournalEntry {
+            sequence: self.entries.len(),
+            instruction: IX::INSTRUCTION_NAME.to_string(),
+            system_config_hash: instruction.system_config_hash(),
+            input: instruction.parse_into(),
+            state_root,
+            diff,
+            recorded_at: unix_millis(),
+        });
+    }
+
+    /// Appends the entries of `other`, numbered after those already here.
+    pub fn extend(&mut self, other: Journal<T>) {
+        for mut entry in other.entries {
+            entry.sequence = self.entries.len();
+            self.entries.push(entry);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T: Clone + Serialize> Journal<T> {
+    /// Applies the recorded diffs to `state` without executing anything.
+    /// `state` must start where the journal started, and stops matching the
+    /// journal if it was changed between instructions, e.g. by switching
+    /// branches; the replay then fails at the next entry.
+    pub fn replay(&self, state: &mut State<T>) -> Result<()> {
+        for entry in self.entries.iter() {
+            let root = state.root()?;
+            if root != entry.state_root {
+                return Err(anyhow!(
+                    "State diverged from the journal before entry {} ({}): root {} instead of {}",
+                    entry.sequence, entry.instruction, root, entry.state_root
+                ));
+            }
+            entry.diff.apply(state)?;
+        }
+        Ok(())
+    }
+
+    /// One JSON entry per line.
+    pub fn to_jsonl(&self) -> Result<String> {
+        let mut jsonl = String::new();
+        for entry in self.entries.iter() {
+            jsonl.push_str(&serde_json::to_string(entry)?);
+            jsonl.push('\n');
+        }
+        Ok(jsonl)
+    }
+}
+
+impl<T: Clone + DeserializeOwned> Journal<T> {
+    pub fn from_jsonl(input: &str) -> Result<Self> {
+        let entries = input.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| serde_json::from_str(line)
+                .map_err(|e| anyhow!("Invalid journal entry on line {}: {}", number + 1, e)))
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// An entry that a replayed instruction wrote differently than recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Divergence<T> {
+    pub hash: CryptoHash,
+    pub key: Option<StateKey>,
+    /// `None` when the entry was not written, or deleted.
+    pub recorded: Option<T>,
+    pub replayed: Option<T>,
+}
+
+/// The entries `replayed` leaves different from `recorded`, in key order.
+pub fn diverging_writes<T: Clone + Serialize>(recorded: &StateDiff<T>, replayed: &StateDiff<T>) -> Result<Vec<Divergence<T>>> {
+    diverging_writes_by(recorded, replayed, T::clone)
+}
+
+/// `diverging_writes` comparing the values as `compared` maps them, e.g.
+/// without timestamps that differ on every run.
+pub fn diverging_writes_by<T: Clone + Serialize>(recorded: &StateDiff<T>, replayed: &StateDiff<T>, compared: impl Fn(&T) -> T) -> Result<Vec<Divergence<T>>> {
+    let recorded_writes = recorded.written();
+    let replayed_writes = replayed.written();
+
+    let mut hashes: Vec<_> = recorded_writes.keys().chain(replayed_writes.keys()).copied().collect();
+    hashes.sort_by_key(|hash| hash.hash());
+    hashes.dedup();
+
+    let mut divergences = Vec::new();
+    for hash in hashes {
+        let before = recorded_writes.get(hash).copied().flatten();
+        let after = replayed_writes.get(hash).copied().flatten();
+        if serde_json::to_value(before.map(&compared))? == serde_json::to_value(after.map(&compared))? {
+            continue;
+        }
+        divergences.push(Divergence {
+            hash: hash.clone(),
+            key: replayed.keys.get(hash).or_else(|| recorded.keys.get(hash)).cloned(),
+            recorded: before.cloned(),
+            replayed: after.cloned(),
+        });
+    }
+    divergences.sort_by_key(|divergence| divergence.key.as_ref().map(ToString::to_string));
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Author, LLMConfig, ModelPricing};
+    use std::collections::HashSet;
+
+    #[derive(Clone)]
+    struct Say(String, CryptoHash);
+
+    impl Instruction<String> for Say {
+        const INSTRUCTION_NAME: &'static str = "say";
+        const FALLIBLE: bool = false;
+        type Error = anyhow::Error;
+
+        fn parse_from(value: String, system_config_hash: CryptoHash) -> Self {
+            Say(value, system_config_hash)
+        }
+
+        fn parse_into(&self) -> String {
+            self.0.clone()
+        }
+
+        fn system_config_hash(&self) -> CryptoHash {
+            self.1.clone()
+        }
+
+        fn prepare(&mut self, _state: &State<String>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn say(state: &mut State<String>, journal: &mut Journal<String>, index: usize, text: &str) {
+        let mut diff = StateDiff::by(Author::User);
+        diff.insert(StateKey::indexed("chat", "said", index), text.to_string());
+        let root = state.root().unwrap();
+        diff.apply(state).unwrap();
+        journal.record(&Say(text.to_string(), CryptoHash::default()), root, diff);
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let mut state = State::<String>::default();
+        state.insert(StateKey::single("chat", "config"), "agent".to_string()).unwrap();
+        let start = state.clone();
+        let mut journal = Journal::default();
+        say(&mut state, &mut journal, 0, "hello");
+        say(&mut state, &mut journal, 1, "again");
+
+        let journal = Journal::<String>::from_jsonl(&journal.to_jsonl().unwrap()).unwrap();
+        let say_again: Say = journal.entries[1].instruction().unwrap();
+        assert_eq!(say_again.0, "again");
+
+        let mut replayed = start.clone();
+        journal.replay(&mut replayed).unwrap();
+        assert_eq!(replayed.root().unwrap(), state.root().unwrap());
+        assert_eq!(replayed.meta(&StateKey::indexed("chat", "said", 1)).unwrap().author, Author::User);
+
+        // A state that does not start where the journal did is refused
+        let error = journal.replay(&mut State::default()).unwrap_err();
+        assert!(error.to_string().contains("before entry 0"));
+
+        let mut other = StateDiff::new();
+        other.insert(StateKey::indexed("chat", "said", 1), "bye".to_string());
+        let divergences = diverging_writes(&journal.entries[1].diff, &other).unwrap();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!((divergences[0].recorded.as_deref(), divergences[0].replayed.as_deref()), (Some("again"), Some("bye")));
+    }
+
+    #[test]
+    fn test_root_ignores_map_order() {
+        let config = LLMConfig {
+            pricing: (0..16)
+                .map(|i| (format!("model-{}", i), ModelPricing { prompt_per_million: i as f64, completion_per_million: 1.0 }))
+                .collect(),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+
+        // Each deserialized map iterates in its own order
+        let roots = (0..8)
+            .map(|_| {
+                let mut state = State::<LLMConfig>::default();
+                state.insert(StateKey::single("llm", "config"), serde_json::from_str(&json).unwrap()).unwrap();
+                state.root().unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(roots.len(), 1);
+    }
+}
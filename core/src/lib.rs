@@ -2,14 +2,34 @@ mod crypto_hash;
 mod system_config;
 mod instruction;
 mod state;
+mod entry;
+mod state_key;
 mod runtime;
 mod crypto;
 mod config_reader;
+mod error;
+mod snapshot;
+mod journal;
+mod schedule;
+mod workflow;
 
 pub use crypto_hash::CryptoHash;
-pub use system_config::{RuntimeSystemConfig, LLMConfig};
+pub use system_config::{
+    RuntimeSystemConfig, LLMConfig, ModelPricing, Budget, ContextWindow, WindowStrategy,
+    SummarizationConfig, ResponseFormatConfig, ToolExecutionConfig, ToolSettings, SandboxConfig,
+    HttpToolConfig, McpServerConfig, McpTransport, ProviderConfig, ToolCallMode, ErrorPolicy,
+};
 pub use instruction::Instruction;
+pub use workflow::{Condition, StepAction, WorkflowConfig, WorkflowStep};
 pub use state::{State, StateDiff};
+pub use entry::{unix_millis, Author, Entry, EntryMeta};
+pub use state_key::{config_key, KeyId, StateKey};
 pub use runtime::Runtime;
-pub use crypto::{encrypt, decrypt, blake3_hash};
-pub use config_reader::ConfigReader;
\ No newline at end of file
+pub use journal::{diverging_writes, diverging_writes_by, Divergence, Journal, JournalEntry};
+pub use schedule::{ConflictPolicy, Dependency, Schedule, ScheduleError, Step, StepId, StepRunner};
+pub use error::{BoxError, RuntimeError};
+pub use crypto::{encrypt, decrypt, encrypt_bytes, decrypt_bytes, blake3_hash};
+pub use config_reader::ConfigReader;
+pub use snapshot::{
+    export_snapshot, import_snapshot, snapshot_header, SnapshotFormat, SnapshotHeader, SnapshotOptions, SNAPSHOT_VERSION,
+};
\ No newline at end of file
@@ -1,8 +1,11 @@
+use crate::error::RuntimeError;
 use crate::instruction::Instruction;
 
 #[async_trait::async_trait]
 pub trait Runtime<IX: Instruction<T>, T: Clone>: Clone + Send + Sync + 'static {
-    fn push_instruction(&mut self, instruction: IX);
-    async fn execute_one(&mut self, instruction: &IX) -> Result<(), anyhow::Error>;
-    async fn execute(&mut self) -> Result<(), anyhow::Error>;
+    /// Prepares `instruction` against the current state and queues it.
+    fn push_instruction(&mut self, instruction: IX) -> Result<(), RuntimeError>;
+    async fn execute_one(&mut self, instruction: &IX) -> Result<(), RuntimeError>;
+    /// Runs the queue until it is empty or an instruction stops it.
+    async fn execute(&mut self) -> Result<(), RuntimeError>;
 }
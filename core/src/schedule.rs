@@ -0,0 +1,372 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use crate::crypto_hash::CryptoHash;
+use crate::error::{BoxError, RuntimeError};
+use crate::instruction::Instruction;
+use crate::journal::Journal;
+use crate::state::{State, StateDiff};
+use crate::state_key::StateKey;
+
+/// Position of a step in its schedule.
+pub type StepId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dependency {
+    /// Waits until the step has applied its diff.
+    Step(StepId),
+    /// Waits until the key holds a value, whichever step writes it.
+    Key(StateKey),
+}
+
+/// What happens when a step wrote entries that another step wrote while it
+/// was running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    Fail,
+    /// Drops the diff of the step that finished last and runs it again on
+    /// the state that holds the other step's writes. The step starts over,
+    /// so whatever effects it has outside the state happen again.
+    Rerun,
+}
+
+#[derive(Debug, Clone)]
+pub struct Step<IX> {
+    pub instruction: IX,
+    pub after: Vec<Dependency>,
+}
+
+/// Executes a step for a schedule. The state is a copy taken when the step
+/// started; the scheduler applies the returned diff.
+#[async_trait::async_trait]
+pub trait StepRunner<IX: Instruction<T>, T: Clone>: Send + Sync + 'static {
+    async fn run_step(&self, instruction: IX, state: State<T>) -> Result<StateDiff<T>, RuntimeError>;
+
+    /// Combines `ours`, written by a step that started from `base`, with
+    /// `theirs`, written by a step applied meanwhile, for values that add
+    /// up such as counters. `None` leaves the two writes conflicting.
+    fn merge(&self, _base: Option<&T>, _ours: &T, _theirs: &T) -> Option<T> {
+        None
+    }
+
+    /// Called when the scheduler drops `diff`, written by a step for
+    /// `instruction`, to run the step again, so the runner can release what
+    /// it set aside for that run.
+    fn discard(&self, _instruction: &IX, _diff: &StateDiff<T>) {}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScheduleError {
+    #[error("step {step} depends on unknown step {dependency}")]
+    UnknownStep { step: StepId, dependency: StepId },
+    #[error("steps {0:?} depend on each other")]
+    Cycle(Vec<StepId>),
+    #[error("steps {steps:?} wait for keys no step wrote: {}", .keys.join(", "))]
+    Blocked { steps: Vec<StepId>, keys: Vec<String> },
+    #[error("steps {first} and {second} both wrote {}", .keys.join(", "))]
+    Conflict { first: StepId, second: StepId, keys: Vec<String> },
+    #[error("step {step}: {source}")]
+    Step { step: StepId, source: RuntimeError },
+    #[error("step did not finish: {0}")]
+    Aborted(BoxError),
+    #[error("cannot update state: {0}")]
+    State(BoxError),
+}
+
+/// Instructions and the steps or keys each waits for. Steps whose
+/// dependencies are met run concurrently.
+#[derive(Debug, Clone)]
+pub struct Schedule<IX> {
+    steps: Vec<Step<IX>>,
+    /// Most steps running at once; unlimited if unset.
+    pub max_concurrency: Option<usize>,
+    pub conflicts: ConflictPolicy,
+}
+
+impl<IX> Default for Schedule<IX> {
+    fn default() -> Self {
+        Self { steps: Vec::new(), max_concurrency: None, conflicts: ConflictPolicy::default() }
+    }
+}
+
+impl<IX> Schedule<IX> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, instruction: IX) -> StepId {
+        self.add_after(instruction, Vec::new())
+    }
+
+    pub fn add_after(&mut self, instruction: IX, after: Vec<Dependency>) -> StepId {
+        self.steps.push(Step { instruction, after });
+        self.steps.len() - 1
+    }
+
+    pub fn steps(&self) -> &[Step<IX>] {
+        &self.steps
+    }
+
+    /// Checks that step dependencies exist and do not form a cycle. Key
+    /// dependencies are only known to be met while running.
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        let mut waiting: HashMap<StepId, HashSet<StepId>> = HashMap::new();
+        for (step, scheduled) in self.steps.iter().enumerate() {
+            let mut after = HashSet::new();
+            for dependency in scheduled.after.iter() {
+                if let Dependency::Step(dependency) = dependency {
+                    if *dependency >= self.steps.len() {
+                        return Err(ScheduleError::UnknownStep { step, dependency: *dependency });
+                    }
+                    after.insert(*dependency);
+                }
+            }
+            waiting.insert(step, after);
+        }
+
+        // Repeatedly take away steps that wait for nothing left
+        loop {
+            let free: Vec<StepId> = waiting.iter().filter(|(_, after)| after.is_empty()).map(|(step, _)| *step).collect();
+            if free.is_empty() {
+                break;
+            }
+            for step in free {
+                waiting.remove(&step);
+                waiting.values_mut().for_each(|after| { after.remove(&step); });
+            }
+        }
+        if !waiting.is_empty() {
+            let mut cycle: Vec<StepId> = waiting.into_keys().collect();
+            cycle.sort();
+            return Err(ScheduleError::Cycle(cycle));
+        }
+        Ok(())
+    }
+
+    /// Runs every step and applies its diff to `state`, recording each in
+    /// `journal` in the order they were applied, which is also returned.
+    /// On error the steps still running are aborted; the diffs already
+    /// applied stay.
+    pub async fn run<T, R>(&self, runner: Arc<R>, state: &mut State<T>, journal: &mut Journal<T>) -> Result<Vec<StepId>, ScheduleError>
+    where
+        IX: Instruction<T>,
+        T: Clone + Serialize + Send + Sync + 'static,
+        R: StepRunner<IX, T>,
+    {
+        self.validate()?;
+
+        let limit = self.max_concurrency.unwrap_or(usize::MAX).max(1);
+        let mut pending: Vec<StepId> = (0..self.steps.len()).collect();
+        let mut done = HashSet::new();
+        // Names of the entries each applied step wrote, in the order applied
+        let mut applied: Vec<(StepId, HashMap<CryptoHash, String>)> = Vec::new();
+        let mut running = JoinSet::new();
+
+        loop {
+            let mut waiting = Vec::new();
+            for step in pending.drain(..) {
+                let ready = self.steps[step].after.iter().all(|dependency| match dependency {
+                    Dependency::Step(other) => done.contains(other),
+                    Dependency::Key(key) => state.contains(&key.hash()),
+                });
+                if !ready || running.len() >= limit {
+                    waiting.push(step);
+                    continue;
+                }
+
+                let runner = runner.clone();
+                let instruction = self.steps[step].instruction.clone();
+                let snapshot = state.clone();
+                let base = snapshot.clone();
+                let started = applied.len();
+                running.spawn(async move { (step, started, base, runner.run_step(instruction, snapshot).await) });
+            }
+            pending = waiting;
+
+            let Some(finished) = running.join_next().await else {
+                if pending.is_empty() {
+                    break;
+                }
+                let keys = pending.iter()
+                    .flat_map(|step| self.steps[*step].after.iter())
+                    .filter_map(|dependency| match dependency {
+                        Dependency::Key(key) if !state.contains(&key.hash()) => Some(key.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                return Err(ScheduleError::Blocked { steps: pending, keys });
+            };
+            let (step, started, base, result) = finished.map_err(|e| ScheduleError::Aborted(e.into()))?;
+            let mut diff = result.map_err(|source| ScheduleError::Step { step, source })?;
+
+            let written: HashMap<CryptoHash, String> = diff.written().into_keys()
+                .map(|hash| {
+                    let name = diff.keys.get(hash).map(ToString::to_string).unwrap_or_else(|| hash.to_string());
+                    (hash.clone(), name)
+                })
+                .collect();
+            // Entries written meanwhile conflict unless the runner merges them
+            let mut merged = HashMap::new();
+            for hash in written.keys().filter(|hash| applied[started..].iter().any(|(_, other)| other.contains_key(*hash))) {
+                let ours = diff.storage_insert.get(hash).or_else(|| diff.storage_update.get(hash));
+                if let Some(value) = ours.zip(state.get_hash(hash)).and_then(|(ours, theirs)| runner.merge(base.get_hash(hash), ours, theirs)) {
+                    merged.insert(hash.clone(), value);
+                }
+            }
+            let conflict = applied[started..].iter().find_map(|(other, other_written)| {
+                let mut keys: Vec<String> = written.iter()
+                    .filter(|(hash, _)| other_written.contains_key(*hash) && !merged.contains_key(*hash))
+                    .map(|(_, name)| name.clone())
+                    .collect();
+                keys.sort();
+                (!keys.is_empty()).then_some((*other, keys))
+            });
+            if let Some((other, keys)) = conflict {
+                match self.conflicts {
+                    ConflictPolicy::Fail => return Err(ScheduleError::Conflict { first: other, second: step, keys }),
+                    ConflictPolicy::Rerun => {
+                        runner.discard(&self.steps[step].instruction, &diff);
+                        pending.push(step);
+                        pending.sort();
+                        continue;
+                    }
+                }
+            }
+
+            for (hash, value) in merged {
+                match diff.storage_insert.get_mut(&hash) {
+                    Some(slot) => *slot = value,
+                    None => { diff.storage_update.insert(hash, value); }
+                }
+            }
+            let state_root = state.root().map_err(|e| ScheduleError::State(e.into()))?;
+            diff.apply(state).map_err(|e| ScheduleError::State(e.into()))?;
+            journal.record(&self.steps[step].instruction, state_root, diff);
+            applied.push((step, written));
+            done.insert(step);
+        }
+
+        Ok(applied.into_iter().map(|(step, _)| step).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Barrier;
+
+    /// Writes `value` to `key`, or with `value` empty, the values of the
+    /// other keys of the step joined.
+    #[derive(Clone)]
+    struct Write {
+        key: String,
+        value: String,
+        reads: Vec<String>,
+    }
+
+    impl Instruction<String> for Write {
+        const INSTRUCTION_NAME: &'static str = "write";
+        const FALLIBLE: bool = false;
+        type Error = anyhow::Error;
+
+        fn parse_from(value: String, _system_config_hash: CryptoHash) -> Self {
+            Write { key: value, value: String::new(), reads: Vec::new() }
+        }
+
+        fn parse_into(&self) -> String {
+            self.key.clone()
+        }
+
+        fn system_config_hash(&self) -> CryptoHash {
+            CryptoHash::default()
+        }
+
+        fn prepare(&mut self, _state: &State<String>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write(key: &str, value: &str) -> Write {
+        Write { key: key.to_string(), value: value.to_string(), reads: Vec::new() }
+    }
+
+    fn key(name: &str) -> StateKey {
+        StateKey::single("test", name)
+    }
+
+    /// Steps whose value starts with `sync` only finish once two of them
+    /// run at the same time.
+    struct Runner(Barrier);
+
+    #[async_trait::async_trait]
+    impl StepRunner<Write, String> for Runner {
+        async fn run_step(&self, write: Write, state: State<String>) -> Result<StateDiff<String>, RuntimeError> {
+            if write.value.starts_with("sync") {
+                self.0.wait().await;
+            }
+            let value = match write.reads.is_empty() {
+                true => write.value,
+                false => write.reads.iter().map(|name| state.get(&key(name)).cloned().unwrap_or_default()).collect::<Vec<_>>().join("+"),
+            };
+            let mut diff = StateDiff::new();
+            diff.insert(key(&write.key), value);
+            Ok(diff)
+        }
+    }
+
+    async fn run(schedule: &Schedule<Write>) -> (Result<Vec<StepId>, ScheduleError>, State<String>) {
+        let mut state = State::default();
+        let mut journal = Journal::default();
+        let runner = Arc::new(Runner(Barrier::new(2)));
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), schedule.run(runner, &mut state, &mut journal)).await.unwrap();
+        (result, state)
+    }
+
+    #[tokio::test]
+    async fn test_schedule_dependencies() {
+        let mut schedule = Schedule::new();
+        // Waits for a key written by a step added after it
+        schedule.add_after(Write { reads: vec!["sum".to_string()], ..write("report", "") }, vec![Dependency::Key(key("sum"))]);
+        let a = schedule.add(write("a", "sync 1"));
+        let b = schedule.add(write("b", "sync 2"));
+        schedule.add_after(Write { reads: vec!["a".to_string(), "b".to_string()], ..write("sum", "") }, vec![Dependency::Step(a), Dependency::Step(b)]);
+
+        // `a` and `b` only finish if they run concurrently
+        let (order, state) = run(&schedule).await;
+        assert_eq!(order.unwrap()[2..], [3, 0]);
+        assert_eq!(state.get(&key("report")).unwrap(), "sync 1+sync 2");
+    }
+
+    #[tokio::test]
+    async fn test_schedule_errors() {
+        let mut schedule = Schedule::new();
+        schedule.add(write("same", "sync 1"));
+        schedule.add(write("same", "sync 2"));
+        let (result, _) = run(&schedule).await;
+        assert!(matches!(result, Err(ScheduleError::Conflict { ref keys, .. }) if keys == &["test/same"]));
+
+        schedule.conflicts = ConflictPolicy::Rerun;
+        // A barrier of one lets the step run again on its own
+        let runner = Arc::new(Runner(Barrier::new(1)));
+        let mut state = State::default();
+        let mut journal = Journal::default();
+        assert_eq!(schedule.run(runner, &mut state, &mut journal).await.unwrap().len(), 2);
+        assert_eq!(journal.entries.len(), 2);
+
+        let mut cycle = Schedule::new();
+        cycle.add_after(write("a", "1"), vec![Dependency::Step(1)]);
+        cycle.add_after(write("b", "2"), vec![Dependency::Step(0)]);
+        assert!(matches!(cycle.validate(), Err(ScheduleError::Cycle(steps)) if steps == [0, 1]));
+
+        let mut blocked = Schedule::new();
+        blocked.add(write("a", "1"));
+        blocked.add_after(write("b", "2"), vec![Dependency::Key(key("never"))]);
+        let (result, state) = run(&blocked).await;
+        assert!(matches!(result, Err(ScheduleError::Blocked { steps, .. }) if steps == [1]));
+        assert_eq!(state.get(&key("a")).unwrap(), "1");
+    }
+}
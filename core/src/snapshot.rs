@@ -0,0 +1,238 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::crypto::{decrypt_bytes, encrypt_bytes};
+use crate::state::State;
+
+/// Version written by `export_snapshot`. Bumped when the layout of `State`
+/// changes in a way older readers cannot load.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+const SNAPSHOT_MAGIC: &str = "WATERFALL-SNAPSHOT";
+
+/// How the state is encoded inside a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    Cbor,
+    /// MessagePack with field names, the most compact of the three.
+    MessagePack,
+}
+
+impl fmt::Display for SnapshotFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SnapshotFormat::Json => "json",
+            SnapshotFormat::Cbor => "cbor",
+            SnapshotFormat::MessagePack => "msgpack",
+        })
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(SnapshotFormat::Json),
+            "cbor" => Ok(SnapshotFormat::Cbor),
+            "msgpack" => Ok(SnapshotFormat::MessagePack),
+            _ => Err(anyhow!("Unknown snapshot format {}; expected json, cbor or msgpack", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotOptions {
+    pub format: SnapshotFormat,
+    /// Gzip the encoded state.
+    pub compress: bool,
+    /// Encrypt the snapshot with `crypto::encrypt_bytes` under this key.
+    pub encryption_key: Option<String>,
+}
+
+/// The first line of a snapshot, readable without the key:
+/// `WATERFALL-SNAPSHOT 2 json gzip encrypted`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub format: SnapshotFormat,
+    pub compressed: bool,
+    pub encrypted: bool,
+}
+
+impl SnapshotHeader {
+    fn parse(line: &str) -> Result<Self> {
+        let mut parts = line.split(' ');
+        if parts.next() != Some(SNAPSHOT_MAGIC) {
+            return Err(anyhow!("Not a waterfall snapshot"));
+        }
+        let version = parts.next()
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("Snapshot header has no version"))?;
+        if version > SNAPSHOT_VERSION {
+            return Err(anyhow!("Snapshot version {} is newer than the supported version {}", version, SNAPSHOT_VERSION));
+        }
+        let format = parts.next().ok_or_else(|| anyhow!("Snapshot header has no format"))?.parse()?;
+
+        let mut header = Self { version, format, compressed: false, encrypted: false };
+        for flag in parts {
+            match flag {
+                "gzip" => header.compressed = true,
+                "encrypted" => header.encrypted = true,
+                _ => return Err(anyhow!("Unknown snapshot flag {}", flag)),
+            }
+        }
+        Ok(header)
+    }
+}
+
+impl fmt::Display for SnapshotHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", SNAPSHOT_MAGIC, self.version, self.format)?;
+        if self.compressed {
+            f.write_str(" gzip")?;
+        }
+        if self.encrypted {
+            f.write_str(" encrypted")?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes `state` with its sub-states. Forked states are flattened, so the
+/// snapshot holds every entry and loads without the states it shared with.
+pub fn export_snapshot<T: Serialize + Clone>(state: &State<T>, options: &SnapshotOptions) -> Result<Vec<u8>> {
+    let state = state.flatten();
+    let mut payload = match options.format {
+        SnapshotFormat::Json => serde_json::to_vec(&state)?,
+        SnapshotFormat::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&state, &mut bytes).map_err(|e| anyhow!("Cannot encode snapshot as CBOR: {}", e))?;
+            bytes
+        }
+        SnapshotFormat::MessagePack => rmp_serde::to_vec_named(&state)?,
+    };
+
+    if options.compress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload)?;
+        payload = encoder.finish()?;
+    }
+    if let Some(key) = &options.encryption_key {
+        payload = encrypt_bytes(&payload, key)?;
+    }
+
+    let header = SnapshotHeader {
+        version: SNAPSHOT_VERSION,
+        format: options.format,
+        compressed: options.compress,
+        encrypted: options.encryption_key.is_some(),
+    };
+    let mut snapshot = format!("{}\n", header).into_bytes();
+    snapshot.extend(payload);
+    Ok(snapshot)
+}
+
+/// Reads the header of a snapshot without decoding the state.
+pub fn snapshot_header(snapshot: &[u8]) -> Result<SnapshotHeader> {
+    Ok(split_snapshot(snapshot)?.0)
+}
+
+/// Decodes a snapshot written by `export_snapshot`. `key` is required when
+/// the snapshot is encrypted.
+pub fn import_snapshot<T: DeserializeOwned>(snapshot: &[u8], key: Option<&str>) -> Result<State<T>> {
+    let (header, payload) = split_snapshot(snapshot)?;
+
+    let mut payload = payload.to_vec();
+    if header.encrypted {
+        let key = key.ok_or_else(|| anyhow!("Snapshot is encrypted; a key is required"))?;
+        payload = decrypt_bytes(&payload, key).map_err(|e| anyhow!("Cannot decrypt snapshot: {}", e))?;
+    }
+    if header.compressed {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(payload.as_slice()).read_to_end(&mut decompressed)?;
+        payload = decompressed;
+    }
+
+    Ok(match header.format {
+        SnapshotFormat::Json => serde_json::from_slice(&payload)?,
+        SnapshotFormat::Cbor => ciborium::from_reader(payload.as_slice())
+            .map_err(|e| anyhow!("Cannot decode CBOR snapshot: {}", e))?,
+        SnapshotFormat::MessagePack => rmp_serde::from_slice(&payload)?,
+    })
+}
+
+fn split_snapshot(snapshot: &[u8]) -> Result<(SnapshotHeader, &[u8])> {
+    let end = snapshot.iter().position(|byte| *byte == b'\n')
+        .ok_or_else(|| anyhow!("Not a waterfall snapshot"))?;
+    let line = std::str::from_utf8(&snapshot[..end]).map_err(|_| anyhow!("Not a waterfall snapshot"))?;
+    Ok((SnapshotHeader::parse(line)?, &snapshot[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateKey;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut state = State::<String>::default();
+        state.insert(StateKey::indexed("llm", "user_message", 0), "hello".to_string()).unwrap();
+        let mut fork = state.fork(StateKey::named("llm", "branch", "retry").hash());
+        fork.insert(StateKey::indexed("llm", "user_message", 1), "again".to_string()).unwrap();
+        state.sub_states.insert(fork.id.clone(), fork);
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Cbor, SnapshotFormat::MessagePack] {
+            for (compress, encryption_key) in [(false, None), (true, Some("secret".to_string()))] {
+                let options = SnapshotOptions { format, compress, encryption_key: encryption_key.clone() };
+                let snapshot = export_snapshot(&state, &options).unwrap();
+                let header = snapshot_header(&snapshot).unwrap();
+                assert_eq!((header.format, header.compressed, header.encrypted), (format, compress, encryption_key.is_some()));
+
+                let restored = import_snapshot::<String>(&snapshot, encryption_key.as_deref()).unwrap();
+                assert_eq!(restored.iter().count(), 1);
+                assert!(restored.base.is_none());
+                let fork = restored.sub_states.values().next().unwrap();
+                assert_eq!(fork.get(&StateKey::indexed("llm", "user_message", 0)).unwrap(), "hello");
+                assert_eq!(fork.keys_in("llm").count(), 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_input() {
+        let state = State::<String>::default();
+        let options = SnapshotOptions { encryption_key: Some("secret".to_string()), ..Default::default() };
+        let snapshot = export_snapshot(&state, &options).unwrap();
+        assert!(import_snapshot::<String>(&snapshot, None).is_err());
+        assert!(import_snapshot::<String>(&snapshot, Some("wrong")).is_err());
+
+        let future = format!("{} {} json\n{{}}", SNAPSHOT_MAGIC, SNAPSHOT_VERSION + 1);
+        assert!(import_snapshot::<String>(future.as_bytes(), None).is_err());
+        assert!(import_snapshot::<String>(b"{}", None).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_version_1() {
+        // Before entry metadata, values were stored bare
+        let message = StateKey::indexed("llm", "user_message", 0);
+        let state = serde_json::json!({
+            "id": crate::CryptoHash::default(),
+            "storage": { message.hash().to_string(): "hello" },
+            "sub_states": {},
+            "keys": { message.hash().to_string(): message },
+        });
+        let snapshot = format!("{} 1 json\n{}", SNAPSHOT_MAGIC, state);
+
+        let restored = import_snapshot::<String>(snapshot.as_bytes(), None).unwrap();
+        assert_eq!(restored.get(&message).unwrap(), "hello");
+        assert_eq!(restored.meta(&message).unwrap().created_at, 0);
+    }
+}
@@ -1,17 +1,213 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem::take;
+use std::sync::Arc;
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::crypto_hash::CryptoHash;
+use crate::entry::{unix_millis, Author, Entry, EntryMeta};
+use crate::state_key::StateKey;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+// `serde(default)` fields would otherwise add a `T: Default` bound
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
 pub struct State<T> {
     pub id: CryptoHash,
-    pub storage: HashMap<
-        CryptoHash, T
-        // (T, Option<CryptoHash>) (value, ref_sub_state_id)
-    >,
+    pub storage: HashMap<CryptoHash, Entry<T>>,
     pub sub_states: HashMap<CryptoHash, State<T>>,
+    /// Reverse index from storage hashes to the typed keys they were written under.
+    #[serde(default)]
+    pub keys: HashMap<CryptoHash, StateKey>,
+    /// Entries frozen by `fork` and shared with the other side of the fork.
+    /// `storage` and `keys` then only hold what was written since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<Arc<State<T>>>,
+    /// Entries of `base` deleted since the fork.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub deleted: HashSet<CryptoHash>,
+}
+
+// Not derived, which would require `T: Default`
+impl<T> Default for State<T> {
+    fn default() -> Self {
+        Self {
+            id: CryptoHash::default(),
+            storage: HashMap::new(),
+            sub_states: HashMap::new(),
+            keys: HashMap::new(),
+            base: None,
+            deleted: HashSet::new(),
+        }
+    }
+}
+
+impl<T> State<T> {
+    pub fn get(&self, key: &StateKey) -> Option<&T> {
+        self.get_hash(&key.hash())
+    }
+
+    pub fn get_hash(&self, hash: &CryptoHash) -> Option<&T> {
+        self.entry_hash(hash).map(|entry| &entry.value)
+    }
+
+    pub fn entry(&self, key: &StateKey) -> Option<&Entry<T>> {
+        self.entry_hash(&key.hash())
+    }
+
+    /// Looks through to the base for entries not written since the fork.
+    pub fn entry_hash(&self, hash: &CryptoHash) -> Option<&Entry<T>> {
+        match self.storage.get(hash) {
+            Some(entry) => Some(entry),
+            None if self.deleted.contains(hash) => None,
+            None => self.base.as_ref().and_then(|base| base.entry_hash(hash)),
+        }
+    }
+
+    pub fn meta(&self, key: &StateKey) -> Option<&EntryMeta> {
+        self.entry(key).map(|entry| &entry.meta)
+    }
+
+    pub fn contains(&self, hash: &CryptoHash) -> bool {
+        self.get_hash(hash).is_some()
+    }
+
+    /// All values, including those shared with the base.
+    pub fn iter(&self) -> impl Iterator<Item = (&CryptoHash, &T)> {
+        self.entries().map(|(hash, entry)| (hash, &entry.value))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&CryptoHash, &Entry<T>)> {
+        let mut entries: HashMap<&CryptoHash, &Entry<T>> = match &self.base {
+            Some(base) => base.entries().filter(|(hash, _)| !self.deleted.contains(*hash)).collect(),
+            None => HashMap::new(),
+        };
+        entries.extend(self.storage.iter());
+        entries.into_iter()
+    }
+
+    /// Writes `value` as the system. Diffs record who wrote their entries.
+    pub fn insert(&mut self, key: StateKey, value: T) -> Result<()> {
+        let hash = self.index_key(key)?;
+        self.insert_hash(hash, value);
+        Ok(())
+    }
+
+    pub fn insert_hash(&mut self, hash: CryptoHash, value: T) {
+        self.insert_entry(hash, Entry { value, meta: EntryMeta::new(Author::System) });
+    }
+
+    /// Keeps the creation time of the entry being replaced, if any.
+    pub fn insert_entry(&mut self, hash: CryptoHash, mut entry: Entry<T>) {
+        if let Some(existing) = self.entry_hash(&hash).filter(|existing| existing.meta.created_at > 0) {
+            entry.meta.created_at = existing.meta.created_at;
+        }
+        self.deleted.remove(&hash);
+        self.storage.insert(hash, entry);
+    }
+
+    pub fn remove_hash(&mut self, hash: &CryptoHash) {
+        self.storage.remove(hash);
+        self.keys.remove(hash);
+        if self.base.as_ref().is_some_and(|base| base.contains(hash)) {
+            self.deleted.insert(hash.clone());
+        }
+    }
+
+    /// Freezes the current entries into a base shared with the returned
+    /// state, so neither copies them. Both sides start from the same entries
+    /// and do not see each other's later writes. Sub-states are not forked.
+    pub fn fork(&mut self, id: CryptoHash) -> State<T> {
+        let unchanged = self.storage.is_empty() && self.keys.is_empty() && self.deleted.is_empty();
+        let base = match &self.base {
+            Some(base) if unchanged => base.clone(),
+            _ => {
+                let frozen = Arc::new(State {
+                    id: self.id.clone(),
+                    storage: take(&mut self.storage),
+                    sub_states: HashMap::new(),
+                    keys: take(&mut self.keys),
+                    base: self.base.take(),
+                    deleted: take(&mut self.deleted),
+                });
+                self.base = Some(frozen.clone());
+                frozen
+            }
+        };
+
+        State { id, base: Some(base), ..State::default() }
+    }
+
+    /// Records `key` in the reverse index and returns its hash. Fails if the
+    /// hash already stands for a different key.
+    pub fn index_key(&mut self, key: StateKey) -> Result<CryptoHash> {
+        let hash = key.hash();
+        if let Some(existing) = self.key_of(&hash).filter(|existing| **existing != key) {
+            return Err(anyhow!("State key {} collides with {} at {}", key, existing, hash));
+        }
+        self.keys.insert(hash.clone(), key);
+        Ok(hash)
+    }
+
+    /// The typed key `hash` was written under, if it was written under one.
+    pub fn key_of(&self, hash: &CryptoHash) -> Option<&StateKey> {
+        match self.keys.get(hash) {
+            Some(key) => Some(key),
+            None if self.deleted.contains(hash) => None,
+            None => self.base.as_ref().and_then(|base| base.key_of(hash)),
+        }
+    }
+
+    pub fn keys_in<'a>(&'a self, namespace: &'a str) -> impl Iterator<Item = &'a StateKey> + 'a {
+        self.all_keys().into_values().filter(move |key| key.namespace == namespace)
+    }
+
+    fn all_keys(&self) -> HashMap<&CryptoHash, &StateKey> {
+        let mut keys: HashMap<&CryptoHash, &StateKey> = match &self.base {
+            Some(base) => base.all_keys().into_iter().filter(|(hash, _)| !self.deleted.contains(*hash)).collect(),
+            None => HashMap::new(),
+        };
+        keys.extend(self.keys.iter());
+        keys
+    }
+}
+
+impl<T: Clone> State<T> {
+    /// A copy that holds every entry itself, without a base.
+    pub fn flatten(&self) -> State<T> {
+        State {
+            id: self.id.clone(),
+            storage: self.entries().map(|(hash, entry)| (hash.clone(), entry.clone())).collect(),
+            sub_states: self.sub_states.iter().map(|(id, state)| (id.clone(), state.flatten())).collect(),
+            keys: self.all_keys().into_iter().map(|(hash, key)| (hash.clone(), key.clone())).collect(),
+            base: None,
+            deleted: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Serialize> State<T> {
+    /// Hash of every value and sub-state, in hash order. Metadata is left
+    /// out, so states holding the same values have the same root whenever
+    /// and by whom they were written. Values are hashed as JSON with sorted
+    /// object keys, since maps such as `HashMap` serialize in any order.
+    pub fn root(&self) -> Result<CryptoHash> {
+        let mut entries: Vec<_> = self.iter().collect();
+        entries.sort_by_key(|(hash, _)| hash.hash());
+        let mut sub_states: Vec<_> = self.sub_states.iter().collect();
+        sub_states.sort_by_key(|(id, _)| id.hash());
+
+        let mut hasher = blake3::Hasher::new();
+        for (hash, value) in entries {
+            hasher.update(&hash.hash());
+            hasher.update(&serde_json::to_vec(&serde_json::to_value(value)?)?);
+        }
+        for (id, state) in sub_states {
+            hasher.update(&id.hash());
+            hasher.update(&state.root()?.hash());
+        }
+        Ok(CryptoHash::new(*hasher.finalize().as_bytes()))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -19,28 +215,142 @@ pub struct StateDiff<T: Clone> {
     pub storage_insert: HashMap<CryptoHash, T>,
     pub storage_update: HashMap<CryptoHash, T>,
     pub storage_delete: Vec<CryptoHash>,
+    /// Typed keys of the written entries, added to the state's reverse index.
+    #[serde(default)]
+    pub keys: HashMap<CryptoHash, StateKey>,
+    /// Author of the written entries without one in `authors`.
+    #[serde(default)]
+    pub author: Author,
+    #[serde(default)]
+    pub authors: HashMap<CryptoHash, Author>,
+    /// Sub-states written entries refer to.
+    #[serde(default)]
+    pub references: HashMap<CryptoHash, CryptoHash>,
+    #[serde(default)]
+    pub instruction: Option<String>,
 }
 
 impl<T: Clone> StateDiff<T> {
     pub fn new() -> Self {
+        Self::by(Author::System)
+    }
+
+    /// A diff whose entries are written by `author` unless set otherwise.
+    pub fn by(author: Author) -> Self {
         Self {
             storage_insert: HashMap::new(),
             storage_update: HashMap::new(),
             storage_delete: Vec::new(),
+            keys: HashMap::new(),
+            author,
+            authors: HashMap::new(),
+            references: HashMap::new(),
+            instruction: None,
+        }
+    }
+
+    pub fn insert(&mut self, key: StateKey, value: T) {
+        self.storage_insert.insert(key.hash(), value);
+        self.keys.insert(key.hash(), key);
+    }
+
+    pub fn insert_by(&mut self, author: Author, key: StateKey, value: T) {
+        self.authors.insert(key.hash(), author);
+        self.insert(key, value);
+    }
+
+    pub fn update(&mut self, key: StateKey, value: T) {
+        self.storage_update.insert(key.hash(), value);
+        self.keys.insert(key.hash(), key);
+    }
+
+    pub fn update_by(&mut self, author: Author, key: StateKey, value: T) {
+        self.authors.insert(key.hash(), author);
+        self.update(key, value);
+    }
+
+    pub fn delete(&mut self, key: &StateKey) {
+        self.storage_delete.push(key.hash());
+    }
+
+    /// Marks the entry written under `key` as referring to sub-state `id`.
+    pub fn refer(&mut self, key: &StateKey, id: CryptoHash) {
+        self.references.insert(key.hash(), id);
+    }
+
+    /// Records `instruction` as the writer of every entry of the diff.
+    pub fn set_instruction(&mut self, instruction: impl Into<String>) {
+        self.instruction = Some(instruction.into());
+    }
+
+    /// Folds `other` into this diff; entries from `other` win on conflict
+    /// and keep their author.
+    pub fn merge(&mut self, other: StateDiff<T>) {
+        for hash in other.storage_insert.keys().chain(other.storage_update.keys()) {
+            let author = other.authors.get(hash).copied().unwrap_or(other.author);
+            self.authors.insert(hash.clone(), author);
+        }
+        self.storage_insert.extend(other.storage_insert);
+        self.storage_update.extend(other.storage_update);
+        self.storage_delete.extend(other.storage_delete);
+        self.keys.extend(other.keys);
+        self.references.extend(other.references);
+        if self.instruction.is_none() {
+            self.instruction = other.instruction;
         }
     }
 
-    pub fn apply(&self, state: &mut State<T>) {
+    pub fn is_empty(&self) -> bool {
+        self.storage_insert.is_empty() && self.storage_update.is_empty() && self.storage_delete.is_empty()
+    }
+
+    /// What applying the diff leaves under each hash it touches; `None`
+    /// for deleted entries.
+    pub fn written(&self) -> HashMap<&CryptoHash, Option<&T>> {
+        let mut written: HashMap<_, _> = self.storage_insert.iter()
+            .chain(self.storage_update.iter())
+            .map(|(hash, value)| (hash, Some(value)))
+            .collect();
+        written.extend(self.storage_delete.iter().map(|hash| (hash, None)));
+        written
+    }
+
+    fn entry(&self, hash: &CryptoHash, value: &T, now: u64) -> Entry<T> {
+        Entry {
+            value: value.clone(),
+            meta: EntryMeta {
+                created_at: now,
+                updated_at: now,
+                author: self.authors.get(hash).copied().unwrap_or(self.author),
+                instruction: self.instruction.clone(),
+                sub_state: self.references.get(hash).cloned(),
+            },
+        }
+    }
+
+    /// Applies the diff, or nothing if one of its keys collides with a
+    /// different key already indexed in `state`.
+    pub fn apply(&self, state: &mut State<T>) -> Result<()> {
+        for (hash, key) in self.keys.iter() {
+            if let Some(existing) = state.key_of(hash).filter(|existing| *existing != key) {
+                return Err(anyhow!("State key {} collides with {} at {}", key, existing, hash));
+            }
+        }
+        state.keys.extend(self.keys.iter().map(|(hash, key)| (hash.clone(), key.clone())));
+
+        let now = unix_millis();
         for (key, value) in self.storage_insert.iter() {
-            state.storage.insert(key.clone(), value.clone());
+            state.insert_entry(key.clone(), self.entry(key, value, now));
         }
         
         for (key, value) in self.storage_update.iter() {
-            state.storage.insert(key.clone(), value.clone());
+            state.insert_entry(key.clone(), self.entry(key, value, now));
         }
 
         for key in self.storage_delete.iter() {
-            state.storage.remove(key);
+            state.remove_hash(key);
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::blake3_hash;
+use crate::crypto_hash::CryptoHash;
+
+/// What distinguishes entries of the same kind.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyId {
+    /// The only entry of its kind.
+    Single,
+    Index(usize),
+    Name(String),
+    Hash(CryptoHash),
+}
+
+/// A typed state key: the subsystem that owns the entry, what the entry is
+/// and which one. Entries are stored under the key's hash, and `State`
+/// keeps the key next to it so it can be listed and decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StateKey {
+    pub namespace: String,
+    pub kind: String,
+    pub id: KeyId,
+}
+
+impl StateKey {
+    pub fn new(namespace: &str, kind: &str, id: KeyId) -> Self {
+        Self { namespace: namespace.to_string(), kind: kind.to_string(), id }
+    }
+
+    pub fn single(namespace: &str, kind: &str) -> Self {
+        Self::new(namespace, kind, KeyId::Single)
+    }
+
+    pub fn indexed(namespace: &str, kind: &str, index: usize) -> Self {
+        Self::new(namespace, kind, KeyId::Index(index))
+    }
+
+    pub fn named(namespace: &str, kind: &str, name: &str) -> Self {
+        Self::new(namespace, kind, KeyId::Name(name.to_string()))
+    }
+
+    pub fn hashed(namespace: &str, kind: &str, hash: &CryptoHash) -> Self {
+        Self::new(namespace, kind, KeyId::Hash(hash.clone()))
+    }
+
+    /// The hash the entry is stored under. Parts are separated by NUL and
+    /// ids are tagged, so index 3 and name "3" hash differently.
+    pub fn hash(&self) -> CryptoHash {
+        let id = match &self.id {
+            KeyId::Single => "single".to_string(),
+            KeyId::Index(index) => format!("index\0{}", index),
+            KeyId::Name(name) => format!("name\0{}", name),
+            KeyId::Hash(hash) => format!("hash\0{}", hash),
+        };
+        blake3_hash(format!("{}\0{}\0{}", self.namespace, self.kind, id).as_bytes())
+    }
+
+    pub fn index(&self) -> Option<usize> {
+        match self.id {
+            KeyId::Index(index) => Some(index),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.kind)?;
+        match &self.id {
+            KeyId::Single => Ok(()),
+            KeyId::Index(index) => write!(f, "[{}]", index),
+            KeyId::Name(name) => write!(f, "({})", name),
+            KeyId::Hash(hash) => write!(f, "@{}", hash),
+        }
+    }
+}
+
+/// Key of an agent config, which is also the id instructions refer to it by.
+pub fn config_key(name: &str) -> StateKey {
+    StateKey::named("config", "agent", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{State, StateDiff};
+
+    #[test]
+    fn test_state_key_hash() {
+        let index = StateKey::indexed("llm", "user_message", 3);
+        let name = StateKey::named("llm", "user_message", "3");
+        assert_ne!(index.hash(), name.hash());
+        assert_ne!(index.hash(), StateKey::indexed("tools", "user_message", 3).hash());
+        assert_eq!(index.hash(), StateKey::indexed("llm", "user_message", 3).hash());
+
+        assert_eq!(index.to_string(), "llm/user_message[3]");
+        assert_eq!(config_key("orchestrator").to_string(), "config/agent(orchestrator)");
+    }
+
+    #[test]
+    fn test_reverse_index() {
+        let message = StateKey::indexed("llm", "user_message", 0);
+        let mut state = State::<String>::default();
+        state.insert(message.clone(), "hi".to_string()).unwrap();
+        assert_eq!(state.key_of(&message.hash()), Some(&message));
+        assert_eq!(state.keys_in("llm").count(), 1);
+        assert_eq!(state.keys_in("tools").count(), 0);
+
+        // A hash already standing for another key is refused
+        let other = StateKey::single("tools", "registry");
+        state.keys.insert(other.hash(), message.clone());
+        let mut diff = StateDiff::new();
+        diff.insert(other.clone(), "tools".to_string());
+        assert!(diff.apply(&mut state).is_err());
+        assert!(state.get(&other).is_none());
+    }
+}
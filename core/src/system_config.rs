@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use async_openai::types::FunctionObject;
 use serde::{Deserialize, Serialize};
 
@@ -11,11 +13,314 @@ pub trait RuntimeSystemConfig {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct LLMConfig {
     pub id: CryptoHash,
+    /// The `id` as written in the config, before hashing.
+    #[serde(default)]
+    pub name: String,
+    /// What the agent is for, shown where it is offered as a tool.
+    #[serde(default)]
+    pub description: Option<String>,
     pub system_prompt: String,
     pub openai_model: String,
     pub openai_temperature: f32,
     pub openai_max_tokens: u16,
     pub functions: Vec<FunctionObject>,
+    /// Runtime policy per function name; functions without an entry use the defaults.
+    #[serde(default)]
+    pub tool_settings: HashMap<String, ToolSettings>,
+
+    /// Price per model name, used to compute the cost of each turn.
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+    /// Hard limit on the usage accumulated under this config.
+    #[serde(default)]
+    pub budget: Option<Budget>,
+    /// Which past turns are sent with each request.
+    #[serde(default)]
+    pub context_window: ContextWindow,
+    /// Folds old turns into a rolling summary once the history grows too long.
+    #[serde(default)]
+    pub summarization: Option<SummarizationConfig>,
+    /// Makes the agent answer with JSON matching a schema instead of free text.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormatConfig>,
+    #[serde(default)]
+    pub tool_execution: ToolExecutionConfig,
+    /// Retries and skipping of failed turns.
+    #[serde(default)]
+    pub error_policy: ErrorPolicy,
+    /// Names of tools shipped with the runtime to enable for this agent. Their
+    /// settings go into `tool_settings` like those of the other tools.
+    #[serde(default)]
+    pub builtin_tools: Vec<String>,
+    /// Limits applied to the built-in filesystem and shell tools.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Limits applied to the built-in HTTP tools.
+    #[serde(default)]
+    pub http_tools: HttpToolConfig,
+    /// MCP servers whose tools are added to `functions` at startup.
+    #[serde(default)]
+    pub mcp_servers: Vec<McpServerConfig>,
+    /// Inference API the agent talks to.
+    #[serde(default)]
+    pub provider: ProviderConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ToolSettings {
+    /// Calls are held until the runtime's approval hook accepts them.
+    pub requires_approval: bool,
+}
+
+/// Prices are in USD per one million tokens.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ModelPricing {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl ModelPricing {
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.prompt_per_million
+            + completion_tokens as f64 * self.completion_per_million) / 1_000_000.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Budget {
+    pub max_total_tokens: Option<u64>,
+    pub max_cost: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ContextWindow {
+    pub strategy: WindowStrategy,
+    /// Always send the first turn, even when the strategy or a summary would drop it.
+    pub pin_first_turn: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WindowStrategy {
+    /// Send the whole conversation.
+    #[default]
+    Full,
+    /// Send only the most recent `turns` turns.
+    LastTurns { turns: usize },
+    /// Send as many recent turns as fit in `max_tokens`, prompt included.
+    TokenBudget { max_tokens: usize },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct SummarizationConfig {
+    /// Model used to write the summary, usually a cheaper one.
+    pub model: String,
+    /// Summarize once more than this many turns are not yet summarized.
+    pub threshold_turns: usize,
+    /// Most recent turns that are always sent verbatim.
+    pub keep_recent_turns: usize,
+    pub max_tokens: u16,
+    /// Overrides the default summarization instructions.
+    pub prompt: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ResponseFormatConfig {
+    pub name: String,
+    pub description: Option<String>,
+    /// JSON Schema the answer is validated against.
+    pub schema: serde_json::Value,
+    pub strict: bool,
+    /// Re-prompts with the validation errors this many times before failing.
+    pub max_retries: usize,
+    /// Send the schema as the provider's structured output format. When
+    /// false it is only described in the system prompt.
+    pub native: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ToolExecutionConfig {
+    /// Tool calls from one response that may run at the same time.
+    pub max_concurrency: usize,
+    /// Per-call limit; a call that times out is reported as a tool error.
+    pub timeout_secs: u64,
+    /// Consecutive tool-call responses allowed within one turn.
+    pub max_rounds: usize,
+}
+
+impl Default for ToolExecutionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 4,
+            timeout_secs: 30,
+            max_rounds: 8,
+        }
+    }
+}
+
+/// How the runtime handles a fallible instruction that fails.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ErrorPolicy {
+    /// Further attempts at a provider request that fails with a transient
+    /// error, e.g. an overloaded server. Other errors fail the turn at once.
+    pub retries: usize,
+    pub retry_delay_ms: u64,
+    /// Goes on with the rest of the queue once the turn fails, instead of
+    /// stopping it.
+    pub skip: bool,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            retry_delay_ms: 1000,
+            skip: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Directory the tools are confined to; relative paths resolve against it.
+    pub root: String,
+    pub timeout_secs: u64,
+    /// Cap on file contents and command output returned to the model.
+    pub max_output_bytes: usize,
+    /// Programs `run_command` may start. Empty means no command may run.
+    pub allowed_commands: Vec<String>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            root: ".".to_string(),
+            timeout_secs: 10,
+            max_output_bytes: 64 * 1024,
+            allowed_commands: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HttpToolConfig {
+    /// Hosts that may be fetched, subdomains included. Empty means none.
+    pub allowed_domains: Vec<String>,
+    /// Hosts that are refused even when allowed.
+    pub denied_domains: Vec<String>,
+    pub max_response_bytes: usize,
+    pub max_redirects: usize,
+    pub timeout_secs: u64,
+    /// Convert HTML responses to plain text before returning them.
+    pub extract_text: bool,
+}
+
+impl Default for HttpToolConfig {
+    fn default() -> Self {
+        Self {
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            max_response_bytes: 256 * 1024,
+            max_redirects: 5,
+            timeout_secs: 20,
+            extract_text: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct McpServerConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub transport: McpTransport,
+    /// Prepended to the server's tool names to keep them apart from other tools.
+    #[serde(default)]
+    pub tool_prefix: Option<String>,
+    /// Applies `requires_approval` to every tool of the server.
+    #[serde(default)]
+    pub requires_approval: bool,
+    /// Limit on starting the server and on each request sent to it.
+    #[serde(default = "default_mcp_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_mcp_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+pub enum McpTransport {
+    /// A subprocess speaking JSON-RPC over stdin and stdout.
+    Stdio {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+    },
+    /// A server reachable through the streamable HTTP transport.
+    Http { url: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    /// The runtime's own OpenAI-compatible client, configured through
+    /// `OPENAI_BASE_URL` and `OPENAI_API_KEY`.
+    #[default]
+    Default,
+    /// An OpenAI-compatible chat completions API.
+    #[serde(rename = "openai")]
+    OpenAi {
+        #[serde(default)]
+        base_url: Option<String>,
+        /// Environment variable holding the API key; `OPENAI_API_KEY` if unset.
+        #[serde(default)]
+        api_key_env: Option<String>,
+    },
+    /// The Anthropic Messages API.
+    Anthropic {
+        #[serde(default)]
+        base_url: Option<String>,
+        /// Environment variable holding the API key; `ANTHROPIC_API_KEY` if unset.
+        #[serde(default)]
+        api_key_env: Option<String>,
+    },
+    /// A local Ollama server, through its native chat API.
+    Ollama {
+        #[serde(default)]
+        base_url: Option<String>,
+        #[serde(default)]
+        tool_calls: ToolCallMode,
+    },
+    /// A local llama.cpp server.
+    LlamaCpp {
+        #[serde(default)]
+        base_url: Option<String>,
+        /// Environment variable holding the key set with `--api-key`, if any.
+        #[serde(default)]
+        api_key_env: Option<String>,
+        #[serde(default)]
+        tool_calls: ToolCallMode,
+    },
+}
+
+/// How tools are offered to models of local providers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolCallMode {
+    /// Native tool calling when the model supports it, the prompt otherwise.
+    #[default]
+    Auto,
+    /// Native tool calling only; models without it are rejected.
+    Native,
+    /// Tools are described in the prompt and calls parsed from the answer.
+    Prompt,
 }
 
 impl RuntimeSystemConfig for LLMConfig {
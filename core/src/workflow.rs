@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Steps run against named variables, defined under `workflows` in the config.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkflowConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Agent of the LLM and tool steps that do not name one.
+    #[serde(default)]
+    pub agent: Option<String>,
+    pub steps: Vec<WorkflowStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkflowStep {
+    /// Unique among its sibling steps; progress is recorded by it.
+    pub id: String,
+    pub action: StepAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StepAction {
+    /// Sends `prompt` as a turn of the agent's conversation. `{{name}}`
+    /// in the prompt is replaced by the variable.
+    Llm {
+        agent: Option<String>,
+        prompt: String,
+        output: Option<String>,
+    },
+    /// Calls a tool registered with the runtime. String arguments are
+    /// templated like prompts; one that is only `{{name}}` takes the value
+    /// of the variable as it is.
+    Tool {
+        agent: Option<String>,
+        name: String,
+        arguments: Value,
+        output: Option<String>,
+    },
+    Branch {
+        condition: Condition,
+        then: Vec<WorkflowStep>,
+        otherwise: Vec<WorkflowStep>,
+    },
+    /// Runs `steps` until `until` holds after an iteration.
+    Loop {
+        until: Condition,
+        max_iterations: usize,
+        steps: Vec<WorkflowStep>,
+    },
+    /// Runs `steps` for each item of the list in `over`, with the item in
+    /// `item`. The value of `collect` after each iteration is gathered
+    /// into `output`.
+    Map {
+        over: String,
+        item: String,
+        steps: Vec<WorkflowStep>,
+        collect: Option<String>,
+        output: Option<String>,
+    },
+}
+
+/// A predicate over workflow variables.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Exists(String),
+    Equals { var: String, value: Value },
+    /// The variable is a string holding `value`, or a list with it.
+    Contains { var: String, value: Value },
+    Not(Box<Condition>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    /// Evaluates the condition with `var` looking up variables.
+    pub fn holds(&self, var: &dyn Fn(&str) -> Option<Value>) -> bool {
+        match self {
+            Condition::Exists(name) => var(name).is_some_and(|value| !value.is_null()),
+            Condition::Equals { var: name, value } => var(name).as_ref() == Some(value),
+            Condition::Contains { var: name, value } => match (var(name), value) {
+                (Some(Value::String(text)), Value::String(part)) => text.contains(part.as_str()),
+                (Some(Value::Array(items)), value) => items.contains(value),
+                _ => false,
+            },
+            Condition::Not(condition) => !condition.holds(var),
+            Condition::All(conditions) => conditions.iter().all(|condition| condition.holds(var)),
+            Condition::Any(conditions) => conditions.iter().any(|condition| condition.holds(var)),
+        }
+    }
+}
@@ -1,8 +1,163 @@
-use waterfall_core::{Instruction, Runtime, ConfigReader};
-use waterfall::{LlmInstruction, LlmRuntime};
+use waterfall_core::{Instruction, Runtime, ConfigReader, CryptoHash, Journal, LLMConfig, SnapshotFormat, SnapshotOptions};
+use waterfall::{ApprovalDecision, ApprovalHook, ApprovalRequest, LlmInstruction, LlmRuntime, StateValue, TranscriptFormat, import_transcripts};
 use indicatif::{ProgressBar, ProgressStyle};
 use colored::*;
 use std::io::{self, Write};
+use tokio::sync::Mutex;
+
+/// Agents and workflows the demo loads at startup.
+const CONFIG_PATH: &str = "config.yaml";
+
+/// Asks on the terminal before a sensitive tool runs.
+struct ConsoleApproval {
+    // Concurrent tool calls must not prompt at the same time
+    prompt: Mutex<()>,
+}
+
+#[async_trait::async_trait]
+impl ApprovalHook for ConsoleApproval {
+    async fn review(&self, request: &ApprovalRequest) -> anyhow::Result<ApprovalDecision> {
+        let _guard = self.prompt.lock().await;
+
+        println!("\n{} {} {}", "⚠️  Approve call to".yellow().bold(), request.tool.bright_white().bold(), "?".yellow().bold());
+        println!("{}", serde_json::to_string_pretty(&request.arguments)?.bright_green());
+
+        let answer = tokio::task::spawn_blocking(|| -> io::Result<(String, String)> {
+            print!("{} ", "[y/N] >".cyan().bold());
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+
+            let mut reason = String::new();
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                print!("{} ", "Reason (optional) >".cyan().bold());
+                io::stdout().flush()?;
+                io::stdin().read_line(&mut reason)?;
+            }
+            Ok((answer, reason))
+        }).await??;
+
+        Ok(match answer {
+            (answer, _) if answer.trim().eq_ignore_ascii_case("y") => ApprovalDecision::Approved,
+            (_, reason) if reason.trim().is_empty() => ApprovalDecision::Rejected { reason: "rejected by user".to_string() },
+            (_, reason) => ApprovalDecision::Rejected { reason: reason.trim().to_string() },
+        })
+    }
+}
+
+/// Snapshot options for `path`: the format from its extension, `.gz` for
+/// compression and the key from `WATERFALL_SNAPSHOT_KEY` when it is set.
+fn snapshot_options(path: &str) -> anyhow::Result<SnapshotOptions> {
+    let (name, compress) = match path.strip_suffix(".gz") {
+        Some(name) => (name, true),
+        None => (path, false),
+    };
+    let format = match std::path::Path::new(name).extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => extension.parse()?,
+        None => SnapshotFormat::Json,
+    };
+
+    Ok(SnapshotOptions { format, compress, encryption_key: std::env::var("WATERFALL_SNAPSHOT_KEY").ok() })
+}
+
+fn save_snapshot(runtime: &LlmRuntime, path: &str) -> anyhow::Result<()> {
+    let snapshot = runtime.export_snapshot(&snapshot_options(path)?)?;
+    std::fs::write(path, snapshot)?;
+    Ok(())
+}
+
+fn load_snapshot(runtime: &mut LlmRuntime, path: &str) -> anyhow::Result<()> {
+    let snapshot = std::fs::read(path)?;
+    let key = std::env::var("WATERFALL_SNAPSHOT_KEY").ok();
+    runtime.restore_snapshot(&snapshot, key.as_deref())
+}
+
+/// Writes the conversation as Markdown, HTML or JSONL, by the extension of `path`.
+fn export_transcript(runtime: &LlmRuntime, config_id: &CryptoHash, path: &str) -> anyhow::Result<()> {
+    let format: TranscriptFormat = std::path::Path::new(path).extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("md")
+        .parse()?;
+    std::fs::write(path, runtime.export_transcript(config_id, format)?)?;
+    Ok(())
+}
+
+/// Loads an OpenAI chat export into the session. Of several conversations
+/// the last is taken, which for an exported JSONL is the whole session.
+fn import_transcript(runtime: &mut LlmRuntime, config_id: &CryptoHash, path: &str) -> anyhow::Result<()> {
+    let mut conversations = import_transcripts(&std::fs::read_to_string(path)?)?;
+    let conversation = conversations.pop().ok_or_else(|| anyhow::anyhow!("{} holds no conversation", path))?;
+    runtime.import_conversation(&conversation, config_id)?;
+
+    println!("{} {} {}", "Imported".green(), conversation.turns, "turns".green());
+    if !conversations.is_empty() {
+        println!("{} {} earlier conversation(s) of {}", "Left out".yellow(), conversations.len(), path);
+    }
+    for skipped in &conversation.skipped {
+        println!("{} message {}: {}", "Skipped".yellow(), skipped.position, skipped.reason);
+    }
+    if let Some((message, _)) = &conversation.pending_message {
+        println!("{} {}", "Unanswered last message:".yellow(), message);
+    }
+    Ok(())
+}
+
+/// A runtime with only the config injected, where a journal recorded by
+/// the demo starts.
+async fn fresh_runtime(system_config: &LLMConfig) -> anyhow::Result<LlmRuntime> {
+    let mut runtime = LlmRuntime::new();
+    runtime.set_approval_hook(ConsoleApproval { prompt: Mutex::new(()) });
+    runtime.inject_system_config(system_config).await?;
+    Ok(runtime)
+}
+
+/// Rebuilds the session from a journal without calling the model.
+async fn replay_journal(runtime: &mut LlmRuntime, system_config: &LLMConfig, path: &str) -> anyhow::Result<()> {
+    let journal = Journal::from_jsonl(&std::fs::read_to_string(path)?)?;
+    let mut replayed = fresh_runtime(system_config).await?;
+    replayed.replay_journal(&journal)?;
+    *runtime = replayed;
+    Ok(())
+}
+
+/// Sends the messages of a journal to the model again and lists what came
+/// out differently. The session itself is left as it is.
+async fn rerun_journal(system_config: &LLMConfig, path: &str) -> anyhow::Result<()> {
+    let journal = Journal::from_jsonl(&std::fs::read_to_string(path)?)?;
+    let mut rerun = fresh_runtime(system_config).await?;
+    rerun.set_quiet(true);
+    for turn in rerun.rerun_journal(&journal, &system_config.id).await? {
+        if turn.matches() {
+            println!("{} {}", format!("#{}", turn.sequence).green(), turn.message);
+            continue;
+        }
+        println!("{} {}", format!("#{}", turn.sequence).yellow(), turn.message);
+        if let Some(error) = &turn.error {
+            println!("   {}: {}", "Failed".red(), error);
+        }
+        for divergence in &turn.divergences {
+            let key = divergence.key.as_ref().map(ToString::to_string).unwrap_or_else(|| divergence.hash.to_string());
+            let preview = |value: &Option<StateValue>| value.as_ref().map(StateValue::preview).unwrap_or_else(|| "-".to_string());
+            println!("   {} {} {} {}", key.bright_black(), preview(&divergence.recorded), "→".bright_black(), preview(&divergence.replayed));
+        }
+    }
+    Ok(())
+}
+
+/// Runs a workflow of the config file, or resumes it if its last run failed.
+/// Inputs are a JSON object after the name: `/workflow open_tabs {"urls": [...]}`.
+async fn run_workflow(runtime: &mut LlmRuntime, command: &str) -> anyhow::Result<()> {
+    let (name, inputs) = command.split_once(' ').unwrap_or((command, "{}"));
+    let workflow = ConfigReader::workflows(CONFIG_PATH)?
+        .into_iter()
+        .find(|workflow| workflow.name == name)
+        .ok_or_else(|| anyhow::anyhow!("No workflow {} in {}", name, CONFIG_PATH))?;
+    let inputs = serde_json::from_str(inputs)?;
+
+    let run = runtime.run_workflow(&workflow, inputs).await?;
+    println!("{} {} ({} steps)", "Finished workflow".green(), name.bright_white(), run.completed.len());
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
@@ -16,7 +171,7 @@ async fn main() {
     spinner.set_message("Loading configuration...");
     
     // Load configuration from yaml file
-    let system_config = match ConfigReader::new("config.yaml") {
+    let system_config = match ConfigReader::new(CONFIG_PATH) {
         Ok(config) => {
             spinner.finish_with_message("Configuration loaded successfully!".green().to_string());
             config
@@ -28,6 +183,20 @@ async fn main() {
         }
     };
     
+    // Create the runtime, restoring a saved session if one was given
+    let mut runtime = LlmRuntime::new();
+    runtime.set_approval_hook(ConsoleApproval { prompt: Mutex::new(()) });
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.iter().position(|arg| arg == "--restore").and_then(|i| args.get(i + 1)) {
+        match load_snapshot(&mut runtime, path) {
+            Ok(_) => println!("{} {}", "Restored session from".green(), path.bright_white()),
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+                return;
+            }
+        }
+    }
+
     // Get user input
     println!("\n{}", "What would you like me to help you with?".yellow());
     print!("{} ", ">".cyan().bold());
@@ -36,12 +205,11 @@ async fn main() {
     let mut user_input = String::new();
     io::stdin().read_line(&mut user_input).unwrap();
     
-    // Create the runtime with fancy loading
-    let mut runtime = LlmRuntime::new();
+    // Initialize the runtime with fancy loading
     spinner.set_message("Initializing runtime...");
     spinner.enable_steady_tick(std::time::Duration::from_millis(100));
     
-    match runtime.inject_system_config(&system_config) {
+    match runtime.inject_system_config(&system_config).await {
         Ok(_) => spinner.finish_with_message("Runtime initialized successfully!".green().to_string()),
         Err(e) => {
             spinner.finish_with_message("Failed to initialize runtime".red().to_string());
@@ -55,11 +223,15 @@ async fn main() {
     spinner.reset();
     
     let ix = LlmInstruction::parse_from(
-        user_input.trim().to_string(),
+        user_input.trim().into(),
         system_config.id.clone(),
     );
 
-    runtime.push_instruction(ix);
+    if let Err(e) = runtime.push_instruction(ix) {
+        spinner.finish_with_message("Failed to prepare request".red().to_string());
+        eprintln!("{}: {}", "Error".red().bold(), e);
+        return;
+    }
     
     // Execute with spinner
     spinner.set_message("Executing instruction...");
@@ -76,7 +248,7 @@ async fn main() {
     
     // Loop to allow for more interactions
     loop {
-        println!("\n{}", "What else would you like to do? (Type 'exit' to quit)".yellow());
+        println!("\n{}", "What else would you like to do? (Type 'exit' to quit, '/save <path>' or '/load <path>' for snapshots, '/export <path>' or '/import <path>' for transcripts, '/journal <path>', '/replay <path>' or '/rerun <path>' for journals, '/workflow <name> [inputs]')".yellow());
         print!("{} ", ">".cyan().bold());
         io::stdout().flush().unwrap();
         
@@ -88,13 +260,79 @@ async fn main() {
             println!("{}", "Goodbye! 👋".bright_blue());
             break;
         }
+
+        if let Some(path) = input.strip_prefix("/save ") {
+            match save_snapshot(&runtime, path.trim()) {
+                Ok(_) => println!("{} {}", "Saved session to".green(), path.trim().bright_white()),
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/export ") {
+            match export_transcript(&runtime, &system_config.id, path.trim()) {
+                Ok(_) => println!("{} {}", "Exported transcript to".green(), path.trim().bright_white()),
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/import ") {
+            if let Err(e) = import_transcript(&mut runtime, &system_config.id, path.trim()) {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/journal ") {
+            let written = runtime.journal().to_jsonl().and_then(|jsonl| Ok(std::fs::write(path.trim(), jsonl)?));
+            match written {
+                Ok(_) => println!("{} {}", "Saved journal to".green(), path.trim().bright_white()),
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/replay ") {
+            match replay_journal(&mut runtime, &system_config, path.trim()).await {
+                Ok(_) => {
+                    println!("{} {}", "Replayed journal".green(), path.trim().bright_white());
+                    runtime.print_state_pretty().unwrap();
+                }
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/rerun ") {
+            if let Err(e) = rerun_journal(&system_config, path.trim()).await {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+            }
+            continue;
+        }
+        if let Some(command) = input.strip_prefix("/workflow ") {
+            if let Err(e) = run_workflow(&mut runtime, command.trim()).await {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+            }
+            continue;
+        }
+        if let Some(path) = input.strip_prefix("/load ") {
+            // The config is injected again to register its tools
+            let restored = match load_snapshot(&mut runtime, path.trim()) {
+                Ok(_) => runtime.inject_system_config(&system_config).await,
+                Err(e) => Err(e),
+            };
+            match restored {
+                Ok(_) => println!("{} {}", "Restored session from".green(), path.trim().bright_white()),
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
         
         let ix = LlmInstruction::parse_from(
-            input.to_string(),
+            input.into(),
             system_config.id.clone(),
         );
         
-        runtime.push_instruction(ix);
+        if let Err(e) = runtime.push_instruction(ix) {
+            eprintln!("{}: {}", "Error".red().bold(), e);
+            continue;
+        }
         
         // Execute with spinner
         spinner.set_message("Executing instruction...");
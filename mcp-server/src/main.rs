@@ -0,0 +1,74 @@
+mod server;
+
+use std::sync::Arc;
+
+use waterfall_core::ConfigReader;
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+use server::Server;
+
+/// Serves the agents of the given config files as MCP tools over stdio.
+///
+/// Stdout carries the protocol, so diagnostics go to stderr.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let paths = std::env::args().skip(1).collect::<Vec<_>>();
+    if paths.is_empty() {
+        eprintln!("usage: waterfall-mcp <config.yaml>...");
+        std::process::exit(2);
+    }
+
+    let configs = paths.iter()
+        .map(|path| ConfigReader::new(path).map_err(|e| anyhow!("Cannot load {}: {}", path, e)))
+        .collect::<Result<Vec<_>>>()?;
+    let server = Arc::new(Server::load(configs).await?);
+    eprintln!("waterfall-mcp: serving {} agent(s)", paths.len());
+
+    // Calls run concurrently, so responses are written by a single task
+    let (responses, mut outgoing) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(response) = outgoing.recv().await {
+            let mut line = serde_json::to_vec(&response)?;
+            line.push(b'\n');
+            stdout.write_all(&line).await?;
+            stdout.flush().await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match serde_json::from_str::<Value>(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                let _ = responses.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": -32700, "message": format!("Parse error: {}", e) },
+                }));
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            if let Some(response) = server.handle(message).await {
+                let _ = responses.send(response);
+            }
+        });
+    }
+
+    // Let calls still running answer before exiting
+    drop(responses);
+    writer.await?
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use waterfall::{config_usage_key, read_totals, LlmRuntime, StateValue, UsageTotals, MCP_PROTOCOL_VERSION};
+use waterfall_core::{LLMConfig, State};
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// An agent offered as a tool, with a runtime that has its config injected.
+/// Every call runs on a clone of that runtime.
+struct Agent {
+    config: LLMConfig,
+    runtime: LlmRuntime,
+    /// What every call of the agent spent, with or without a session, so
+    /// its budget holds across them all.
+    usage: Mutex<UsageTotals>,
+}
+
+/// A session's conversation, locked while one of its calls runs.
+type Session = Arc<Mutex<State<StateValue>>>;
+
+/// Answers MCP requests, exposing each loaded agent as one tool.
+pub struct Server {
+    agents: BTreeMap<String, Agent>,
+    /// Conversation state by agent and session id.
+    sessions: Mutex<HashMap<(String, String), Session>>,
+}
+
+impl Server {
+    pub async fn load(configs: Vec<LLMConfig>) -> Result<Self> {
+        let mut agents = BTreeMap::new();
+
+        for config in configs {
+            if agents.contains_key(&config.name) {
+                return Err(anyhow!("Agent {} is configured more than once", config.name));
+            }
+
+            let mut runtime = LlmRuntime::new();
+            runtime.set_quiet(true);
+            runtime.inject_system_config(&config).await?;
+
+            agents.insert(config.name.clone(), Agent { config, runtime, usage: Mutex::default() });
+        }
+
+        Ok(Self { agents, sessions: Mutex::new(HashMap::new()) })
+    }
+
+    /// Handles one JSON-RPC message; notifications get no response.
+    pub async fn handle(&self, message: Value) -> Option<Value> {
+        let id = message.get("id")?.clone();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => Ok(initialize_result()),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({
+                "tools": self.agents.values().map(|agent| tool_definition(&agent.config)).collect::<Vec<_>>(),
+            })),
+            "tools/call" => self.call_tool(&params).await,
+            method => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        };
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+        })
+    }
+
+    /// Runs the agent. Its failures are tool errors the caller can read,
+    /// while malformed calls are protocol errors.
+    async fn call_tool(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let agent = self.agents.get(name)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Unknown tool: {}", name)))?;
+
+        let prompt = params["arguments"]["prompt"].as_str()
+            .ok_or_else(|| (INVALID_PARAMS, "Missing prompt argument".to_string()))?;
+        let session_id = params["arguments"]["session_id"].as_str();
+
+        Ok(match self.run_agent(agent, prompt, session_id).await {
+            Ok(answer) => json!({ "content": [{ "type": "text", "text": answer }] }),
+            Err(e) => json!({ "content": [{ "type": "text", "text": e.to_string() }], "isError": true }),
+        })
+    }
+
+    async fn run_agent(&self, agent: &Agent, prompt: &str, session_id: Option<&str>) -> Result<String> {
+        let mut runtime = agent.runtime.clone();
+
+        let Some(session_id) = session_id else {
+            // A fresh conversation
+            return run_metered(agent, &mut runtime, prompt).await;
+        };
+
+        let session = self.sessions.lock().await
+            .entry((agent.config.name.clone(), session_id.to_string()))
+            .or_insert_with(|| Arc::new(Mutex::new(agent.runtime.state.clone())))
+            .clone();
+        let mut state = session.lock().await;
+
+        runtime.state = state.clone();
+        let answer = run_metered(agent, &mut runtime, prompt).await;
+        *state = std::mem::take(&mut runtime.state);
+
+        answer
+    }
+}
+
+/// Runs a call on totals that start with what every call of the agent
+/// spent, and adds what this one spent. Calls of an agent with a budget run
+/// one at a time, so each is checked against all the calls before it.
+async fn run_metered(agent: &Agent, runtime: &mut LlmRuntime, prompt: &str) -> Result<String> {
+    let key = config_usage_key(&agent.config.id);
+    let usage = agent.usage.lock().await;
+    let spent = usage.clone();
+    runtime.state.insert(key.clone(), spent.clone().into())?;
+    let held = agent.config.budget.is_some().then_some(usage);
+
+    let answer = runtime.execute_prompt(prompt, agent.config.id.clone()).await;
+
+    let call_usage = read_totals(&runtime.state, &key)?.since(&spent);
+    match held {
+        Some(mut usage) => usage.merge(&call_usage),
+        None => agent.usage.lock().await.merge(&call_usage),
+    }
+
+    answer
+}
+
+fn initialize_result() -> Value {
+    json!({
+        "protocolVersion": MCP_PROTOCOL_VERSION,
+        "capabilities": { "tools": { "listChanged": false } },
+        "serverInfo": { "name": "waterfall", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+fn tool_definition(config: &LLMConfig) -> Value {
+    let description = config.description.clone()
+        .unwrap_or_else(|| format!("Ask the {} agent and get its final answer", config.name));
+
+    json!({
+        "name": config.name,
+        "description": description,
+        "inputSchema": {
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string", "description": "The message for the agent" },
+                "session_id": {
+                    "type": "string",
+                    "description": "Continues the conversation of earlier calls with the same id"
+                },
+            },
+            "required": ["prompt"],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_protocol_methods() {
+        let server = Server::load(Vec::new()).await.unwrap();
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} })).await.unwrap();
+        assert_eq!(response["result"]["protocolVersion"], MCP_PROTOCOL_VERSION);
+
+        assert!(server.handle(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" })).await.is_none());
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "id": 2, "method": "tools/call", "params": { "name": "nope" } })).await.unwrap();
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "id": 3, "method": "resources/list" })).await.unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_tool_definition() {
+        let config = LLMConfig { name: "researcher".to_string(), ..Default::default() };
+        let tool = tool_definition(&config);
+
+        assert_eq!(tool["name"], "researcher");
+        assert_eq!(tool["inputSchema"]["required"], json!(["prompt"]));
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+use crate::ToolHandler;
+use super::Sandbox;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ReadFileArgs {
+    /// Path of the file, relative to the sandbox root.
+    pub path: String,
+    /// Byte offset to start reading from.
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct WriteFileArgs {
+    /// Path of the file, relative to the sandbox root. Missing directories are created.
+    pub path: String,
+    pub content: String,
+    /// Append to the file instead of replacing it.
+    #[serde(default)]
+    pub append: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ListDirArgs {
+    /// Directory to list, relative to the sandbox root.
+    #[serde(default = "current_dir")]
+    pub path: String,
+}
+
+fn current_dir() -> String {
+    ".".to_string()
+}
+
+pub struct ReadFile(pub Arc<Sandbox>);
+
+#[async_trait::async_trait]
+impl ToolHandler for ReadFile {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: ReadFileArgs = serde_json::from_value(arguments)?;
+        let path = self.0.resolve(&args.path)?;
+
+        let mut file = tokio::fs::File::open(&path).await
+            .map_err(|e| anyhow!("Cannot open {}: {}", args.path, e))?;
+        if args.offset > 0 {
+            use tokio::io::AsyncSeekExt;
+            file.seek(std::io::SeekFrom::Start(args.offset)).await?;
+        }
+
+        let limit = self.0.config.max_output_bytes as u64;
+        let mut content = Vec::new();
+        file.take(limit + 1).read_to_end(&mut content).await?;
+
+        Ok(self.0.render(&content, false))
+    }
+}
+
+pub struct WriteFile(pub Arc<Sandbox>);
+
+#[async_trait::async_trait]
+impl ToolHandler for WriteFile {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: WriteFileArgs = serde_json::from_value(arguments)?;
+        let path = self.0.resolve(&args.path)?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if args.append {
+            use tokio::io::AsyncWriteExt;
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+            file.write_all(args.content.as_bytes()).await?;
+        } else {
+            tokio::fs::write(&path, args.content.as_bytes()).await?;
+        }
+
+        Ok(json!({ "path": args.path, "bytes_written": args.content.len() }).to_string())
+    }
+}
+
+pub struct ListDir(pub Arc<Sandbox>);
+
+#[async_trait::async_trait]
+impl ToolHandler for ListDir {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: ListDirArgs = serde_json::from_value(arguments)?;
+        let path = self.0.resolve(&args.path)?;
+
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&path).await
+            .map_err(|e| anyhow!("Cannot list {}: {}", args.path, e))?;
+        while let Some(entry) = dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let kind = if file_type.is_dir() {
+                "dir"
+            } else if file_type.is_symlink() {
+                "symlink"
+            } else {
+                "file"
+            };
+
+            entries.push(json!({
+                "name": entry.file_name().to_string_lossy(),
+                "kind": kind,
+                "size": entry.metadata().await.map(|m| m.len()).unwrap_or_default(),
+            }));
+        }
+        entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+        // Drop whole entries rather than cutting the JSON apart
+        let mut size = 0;
+        let total = entries.len();
+        entries.retain(|entry| {
+            size += entry.to_string().len() + 1;
+            size <= self.0.config.max_output_bytes
+        });
+
+        Ok(json!({ "entries": entries, "truncated": entries.len() < total }).to_string())
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use waterfall_core::HttpToolConfig;
+
+use anyhow::{anyhow, Result};
+use reqwest::{Method, StatusCode, Url};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::ToolHandler;
+use super::html_to_text;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HttpGetArgs {
+    /// Absolute http or https URL.
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HttpRequestArgs {
+    /// Absolute http or https URL.
+    pub url: String,
+    /// GET, POST, PUT, PATCH, DELETE or HEAD.
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Performs requests on behalf of the HTTP tools, enforcing the domain lists
+/// on every redirect hop and capping what is read back.
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    pub config: HttpToolConfig,
+}
+
+impl HttpFetcher {
+    /// `client` must not follow redirects on its own, or the domain lists
+    /// would only be checked for the first hop.
+    pub fn new(client: reqwest::Client, config: &HttpToolConfig) -> Self {
+        Self { client, config: config.clone() }
+    }
+
+    /// Fails unless `url` is http(s) and its host is allowed and not denied.
+    pub fn check_url(&self, url: &Url) -> Result<()> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Scheme {} is not allowed", url.scheme()));
+        }
+
+        let host = url.host_str()
+            .ok_or_else(|| anyhow!("URL {} has no host", url))?
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+
+        if self.config.denied_domains.iter().any(|domain| domain_matches(&host, domain)) {
+            return Err(anyhow!("Domain {} is denied", host));
+        }
+        if !self.config.allowed_domains.iter().any(|domain| domain_matches(&host, domain)) {
+            return Err(anyhow!("Domain {} is not on the allowlist", host));
+        }
+
+        Ok(())
+    }
+
+    pub async fn fetch(&self, args: HttpRequestArgs) -> Result<Value> {
+        let mut url = Url::parse(&args.url).map_err(|e| anyhow!("Invalid URL {}: {}", args.url, e))?;
+        let mut method = Method::from_bytes(args.method.to_ascii_uppercase().as_bytes())
+            .ok()
+            .filter(|m| [Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::HEAD].contains(m))
+            .ok_or_else(|| anyhow!("Method {} is not supported", args.method))?;
+        let mut body = args.body;
+        let mut headers = args.headers;
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let mut redirects = 0;
+
+        let response = loop {
+            self.check_url(&url)?;
+
+            let mut request = self.client.request(method.clone(), url.clone()).timeout(timeout);
+            for (name, value) in &headers {
+                request = request.header(name, value);
+            }
+            if let Some(body) = &body {
+                request = request.body(body.clone());
+            }
+
+            let response = request.send().await.map_err(|e| anyhow!("Request to {} failed: {}", url, e))?;
+
+            let location = response.headers().get(reqwest::header::LOCATION);
+            let (true, Some(location)) = (response.status().is_redirection(), location) else {
+                break response;
+            };
+
+            redirects += 1;
+            if redirects > self.config.max_redirects {
+                return Err(anyhow!("Too many redirects (limit {})", self.config.max_redirects));
+            }
+
+            let location = location.to_str().map_err(|_| anyhow!("Invalid redirect location"))?;
+            let next = url.join(location).map_err(|e| anyhow!("Invalid redirect location {}: {}", location, e))?;
+            // Credentials the model sent are meant for the origin it asked for
+            if next.origin() != url.origin() {
+                headers.retain(|name, _| !is_sensitive_header(name));
+            }
+            url = next;
+
+            // Like browsers, only 307 and 308 repeat the original method and body
+            let status = response.status();
+            if status != StatusCode::TEMPORARY_REDIRECT && status != StatusCode::PERMANENT_REDIRECT && method != Method::HEAD {
+                method = Method::GET;
+                body = None;
+            }
+        };
+
+        let status = response.status();
+        let final_url = response.url().to_string();
+        let content_type = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+
+        let (bytes, truncated) = self.read_capped(response, timeout).await?;
+        let mut text = String::from_utf8_lossy(&bytes).into_owned();
+        if self.config.extract_text && content_type.starts_with("text/html") {
+            text = html_to_text(&text);
+        }
+
+        Ok(json!({
+            "status": status.as_u16(),
+            "url": final_url,
+            "content_type": content_type,
+            "body": text,
+            "truncated": truncated,
+        }))
+    }
+
+    /// Reads at most `max_response_bytes` of the body, so oversized
+    /// responses are cut off instead of buffered.
+    async fn read_capped(&self, mut response: reqwest::Response, timeout: Duration) -> Result<(Vec<u8>, bool)> {
+        let limit = self.config.max_response_bytes;
+        let mut bytes = Vec::new();
+
+        let read = async {
+            while let Some(chunk) = response.chunk().await? {
+                bytes.extend_from_slice(&chunk);
+                if bytes.len() > limit {
+                    bytes.truncate(limit);
+                    return Ok::<_, anyhow::Error>(true);
+                }
+            }
+            Ok(false)
+        };
+
+        let truncated = tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| anyhow!("Reading the response timed out after {}s", self.config.timeout_secs))??;
+
+        Ok((bytes, truncated))
+    }
+}
+
+/// Headers that carry credentials, dropped on redirects to another origin.
+fn is_sensitive_header(name: &str) -> bool {
+    ["authorization", "cookie", "proxy-authorization"].iter().any(|sensitive| name.eq_ignore_ascii_case(sensitive))
+}
+
+/// True when `host` is `domain` or one of its subdomains.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches("*.").trim_end_matches('.').to_ascii_lowercase();
+    host == domain || host.strip_suffix(&domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+pub struct HttpGet(pub Arc<HttpFetcher>);
+
+#[async_trait::async_trait]
+impl ToolHandler for HttpGet {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: HttpGetArgs = serde_json::from_value(arguments)?;
+        let request = HttpRequestArgs { url: args.url, method: default_method(), headers: HashMap::new(), body: None };
+        Ok(self.0.fetch(request).await?.to_string())
+    }
+}
+
+pub struct HttpRequest(pub Arc<HttpFetcher>);
+
+#[async_trait::async_trait]
+impl ToolHandler for HttpRequest {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: HttpRequestArgs = serde_json::from_value(arguments)?;
+        Ok(self.0.fetch(args).await?.to_string())
+    }
+}
@@ -0,0 +1,328 @@
+mod fs;
+mod html;
+mod http;
+mod sandbox;
+mod shell;
+
+use std::sync::Arc;
+
+use waterfall_core::LLMConfig;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::FunctionObject;
+use schemars::JsonSchema;
+
+use crate::ToolHandler;
+
+pub use fs::*;
+pub use html::*;
+pub use http::*;
+pub use sandbox::*;
+pub use shell::*;
+
+/// Every tool a config can enable through `builtin_tools`.
+pub const BUILTIN_TOOLS: &[&str] = &["read_file", "write_file", "list_dir", "run_command", "http_get", "http_request"];
+
+/// Builds the function definition and handler of the built-in tool `name`,
+/// confined to the sandbox and HTTP limits of `config`. `http` must not
+/// follow redirects by itself.
+pub fn builtin_tool(name: &str, config: &LLMConfig, http: &reqwest::Client) -> Result<(FunctionObject, Arc<dyn ToolHandler>)> {
+    let sandbox = || Sandbox::new(&config.sandbox).map(Arc::new);
+    let fetcher = || Arc::new(HttpFetcher::new(http.clone(), &config.http_tools));
+
+    let tool: (FunctionObject, Arc<dyn ToolHandler>) = match name {
+        "read_file" => (
+            function_object::<ReadFileArgs>(name, "Read a text file from the workspace"),
+            Arc::new(ReadFile(sandbox()?)),
+        ),
+        "write_file" => (
+            function_object::<WriteFileArgs>(name, "Create, overwrite or append to a file in the workspace"),
+            Arc::new(WriteFile(sandbox()?)),
+        ),
+        "list_dir" => (
+            function_object::<ListDirArgs>(name, "List the entries of a directory in the workspace"),
+            Arc::new(ListDir(sandbox()?)),
+        ),
+        "run_command" => (
+            function_object::<RunCommandArgs>(name, "Run an allowed program in the workspace and return its output"),
+            Arc::new(RunCommand(sandbox()?)),
+        ),
+        "http_get" => (
+            function_object::<HttpGetArgs>(name, "Fetch a URL and return its status and body, with HTML reduced to text"),
+            Arc::new(HttpGet(fetcher())),
+        ),
+        "http_request" => (
+            function_object::<HttpRequestArgs>(name, "Send an HTTP request with a method, headers and body and return the response"),
+            Arc::new(HttpRequest(fetcher())),
+        ),
+        other => return Err(anyhow!("Unknown builtin tool {}", other)),
+    };
+
+    Ok(tool)
+}
+
+/// Derives the parameters of a function from its argument type.
+pub fn function_object<Args: JsonSchema>(name: &str, description: &str) -> FunctionObject {
+    let mut parameters = serde_json::to_value(schemars::schema_for!(Args))
+        .expect("JSON schemas always serialize");
+
+    if let Some(schema) = parameters.as_object_mut() {
+        schema.remove("$schema");
+        schema.remove("title");
+    }
+
+    FunctionObject {
+        name: name.to_string(),
+        description: Some(description.to_string()),
+        parameters: Some(parameters),
+        strict: Some(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+    use serde_json::{json, Value};
+    use waterfall_core::{HttpToolConfig, SandboxConfig};
+
+    fn config(root: &std::path::Path) -> LLMConfig {
+        LLMConfig {
+            sandbox: SandboxConfig {
+                root: root.to_string_lossy().into_owned(),
+                max_output_bytes: 64,
+                allowed_commands: vec!["echo".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn http_client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    async fn call(name: &str, config: &LLMConfig, arguments: Value) -> Result<String> {
+        let (_, handler) = builtin_tool(name, config, &http_client())?;
+        handler.call(arguments).await
+    }
+
+    #[test]
+    fn test_generated_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let (function, _) = builtin_tool("write_file", &config(dir.path()), &http_client()).unwrap();
+
+        let parameters = function.parameters.unwrap();
+        assert_eq!(parameters["type"], "object");
+        assert_eq!(parameters["required"], json!(["content", "path"]));
+        assert!(parameters.get("$schema").is_none());
+    }
+
+    #[test]
+    fn test_sandbox_confinement() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::new(&config(dir.path()).sandbox).unwrap();
+
+        assert!(sandbox.resolve("notes/new.txt").unwrap().starts_with(sandbox.root()));
+        assert!(sandbox.resolve("../outside.txt").is_err());
+        assert!(sandbox.resolve("a/../../outside.txt").is_err());
+        assert!(sandbox.resolve("/etc/passwd").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", dir.path().join("escape")).unwrap();
+            assert!(sandbox.resolve("escape/passwd").is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        call("write_file", &config, json!({ "path": "notes/a.txt", "content": "hello" })).await.unwrap();
+        call("write_file", &config, json!({ "path": "notes/a.txt", "content": " world", "append": true })).await.unwrap();
+        assert_eq!(call("read_file", &config, json!({ "path": "notes/a.txt" })).await.unwrap(), "hello world");
+
+        call("write_file", &config, json!({ "path": "long.txt", "content": "x".repeat(100) })).await.unwrap();
+        let long = call("read_file", &config, json!({ "path": "long.txt" })).await.unwrap();
+        assert_eq!(long, format!("{}\n[output truncated]", "x".repeat(64)));
+
+        let listing = call("list_dir", &config, json!({ "path": "notes" })).await.unwrap();
+        let listing = serde_json::from_str::<Value>(&listing).unwrap();
+        assert_eq!(listing["entries"][0]["name"], "a.txt");
+        assert_eq!(listing["truncated"], false);
+
+        assert!(call("read_file", &config, json!({ "path": "../secret" })).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        let output = call("run_command", &config, json!({ "command": "echo", "args": ["hi"] })).await.unwrap();
+        let output = serde_json::from_str::<Value>(&output).unwrap();
+        assert_eq!(output["exit_code"], 0);
+        assert_eq!(output["stdout"], "hi\n");
+
+        assert!(call("run_command", &config, json!({ "command": "rm", "args": ["-rf", "."] })).await.is_err());
+        assert!(call("run_command", &config, json!({ "command": "/bin/echo" })).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builtin_tool_approval() {
+        use crate::{LlmRuntime, ToolResult};
+        use waterfall_core::{ProviderConfig, ToolSettings};
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let mut config = LLMConfig {
+            name: "agent".to_string(),
+            builtin_tools: vec!["read_file".to_string()],
+            provider: ProviderConfig::Ollama { base_url: None, tool_calls: Default::default() },
+            ..config(dir.path())
+        };
+        config.tool_settings.insert("read_file".to_string(), ToolSettings { requires_approval: true });
+
+        let mut runtime = LlmRuntime::new();
+        runtime.inject_system_config(&config).await.unwrap();
+        let result = runtime.call_tool(&config.id, "read_file", json!({ "path": "a.txt" })).await.unwrap();
+        assert_eq!(result, ToolResult::Rejected("denied by policy".to_string()));
+
+        runtime.set_approval_hook(crate::AutoApprove);
+        let result = runtime.call_tool(&config.id, "read_file", json!({ "path": "a.txt" })).await.unwrap();
+        assert_eq!(result, ToolResult::Ok("hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_configs_keep_their_own_tools() {
+        use crate::{LlmRuntime, ToolResult};
+        use waterfall_core::{config_key, ProviderConfig};
+
+        let mut runtime = LlmRuntime::new();
+        let mut dirs = Vec::new();
+        for name in ["a", "b"] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("name.txt"), name).unwrap();
+            let config = LLMConfig {
+                id: config_key(name).hash(),
+                name: name.to_string(),
+                builtin_tools: vec!["read_file".to_string()],
+                provider: ProviderConfig::Ollama { base_url: None, tool_calls: Default::default() },
+                ..config(dir.path())
+            };
+            runtime.inject_system_config(&config).await.unwrap();
+            dirs.push(dir);
+        }
+
+        for name in ["a", "b"] {
+            let result = runtime.call_tool(&config_key(name).hash(), "read_file", json!({ "path": "name.txt" })).await.unwrap();
+            assert_eq!(result, ToolResult::Ok(name.to_string()));
+        }
+    }
+
+    /// Serves a few fixed routes on a random local port.
+    async fn stand_in() -> String {
+        use axum::{http::HeaderMap, response::{Html, Redirect}, routing::{get, post}, Router};
+
+        let app = Router::new()
+            .route("/page", get(|| async {
+                Html("<html><head><title>t</title><style>p{}</style></head><body><h1>Hello</h1><p>fish &amp; chips</p><script>x()</script></body></html>")
+            }))
+            .route("/redirect", get(|| async { Redirect::temporary("/page") }))
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+            .route("/away", get(|| async { Redirect::temporary("http://example.com/") }))
+            .route("/same", get(|| async { Redirect::temporary("/headers") }))
+            // The same server under another host name, so another origin
+            .route("/other", get(|headers: HeaderMap| async move {
+                let host = headers["host"].to_str().unwrap().replace("127.0.0.1", "localhost");
+                Redirect::temporary(&format!("http://{}/headers", host))
+            }))
+            .route("/headers", get(|headers: HeaderMap| async move {
+                ["authorization", "cookie", "x-trace"]
+                    .map(|name| headers.get(name).map_or("-", |value| value.to_str().unwrap()).to_string())
+                    .join(" ")
+            }))
+            .route("/big", get(|| async { "y".repeat(1000) }))
+            .route("/echo", post(|body: String| async move { body }));
+
+        stand_in::serve(app).await
+    }
+
+    fn http_config(allowed: &[&str]) -> LLMConfig {
+        LLMConfig {
+            http_tools: HttpToolConfig {
+                allowed_domains: allowed.iter().map(|d| d.to_string()).collect(),
+                max_response_bytes: 100,
+                max_redirects: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_tools() {
+        let base = stand_in().await;
+        let config = http_config(&["127.0.0.1"]);
+
+        let page = call("http_get", &config, json!({ "url": format!("{}/redirect", base) })).await.unwrap();
+        let page = serde_json::from_str::<Value>(&page).unwrap();
+        assert_eq!(page["status"], 200);
+        assert_eq!(page["url"], format!("{}/page", base));
+        assert_eq!(page["body"], "Hello\nfish & chips");
+
+        let big = call("http_get", &config, json!({ "url": format!("{}/big", base) })).await.unwrap();
+        let big = serde_json::from_str::<Value>(&big).unwrap();
+        assert_eq!(big["body"].as_str().unwrap().len(), 100);
+        assert_eq!(big["truncated"], true);
+
+        let echo = call("http_request", &config, json!({ "url": format!("{}/echo", base), "method": "post", "body": "ping" })).await.unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&echo).unwrap()["body"], "ping");
+
+        let looping = call("http_get", &config, json!({ "url": format!("{}/loop", base) })).await;
+        assert!(looping.unwrap_err().to_string().contains("Too many redirects"));
+
+        // Redirects are checked against the allowlist too
+        let away = call("http_get", &config, json!({ "url": format!("{}/away", base) })).await;
+        assert!(away.unwrap_err().to_string().contains("example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_redirects_keep_credentials_on_their_origin() {
+        let base = stand_in().await;
+        let config = http_config(&["127.0.0.1", "localhost"]);
+        let headers = json!({ "Authorization": "Bearer secret", "Cookie": "session=1", "X-Trace": "abc" });
+
+        let same = call("http_request", &config, json!({ "url": format!("{}/same", base), "headers": headers })).await.unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&same).unwrap()["body"], "Bearer secret session=1 abc");
+
+        let other = call("http_request", &config, json!({ "url": format!("{}/other", base), "headers": headers })).await.unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&other).unwrap()["body"], "- - abc");
+    }
+
+    #[test]
+    fn test_domain_lists() {
+        let mut config = http_config(&["example.com"]);
+        config.http_tools.denied_domains = vec!["private.example.com".to_string()];
+        let fetcher = HttpFetcher::new(http_client(), &config.http_tools);
+        let check = |url: &str| fetcher.check_url(&reqwest::Url::parse(url).unwrap());
+
+        assert!(check("https://example.com/a").is_ok());
+        assert!(check("https://docs.EXAMPLE.com/a").is_ok());
+        assert!(check("https://notexample.com/").is_err());
+        assert!(check("https://private.example.com/").is_err());
+        assert!(check("https://a.private.example.com/").is_err());
+        assert!(check("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let text = html_to_text("<div>A&nbsp;<b>bold</b> &#x41;<!-- hidden --></div><ul><li>one<li>two</ul>");
+        assert_eq!(text, "A bold A\none\ntwo");
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::{Component, Path, PathBuf};
+
+use waterfall_core::SandboxConfig;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Confines the built-in tools to one directory and caps what they return.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    pub config: SandboxConfig,
+}
+
+impl Sandbox {
+    pub fn new(config: &SandboxConfig) -> Result<Self> {
+        let root = std::fs::canonicalize(&config.root)
+            .map_err(|e| anyhow!("Sandbox root {} is not accessible: {}", config.root, e))?;
+
+        if !root.is_dir() {
+            return Err(anyhow!("Sandbox root {} is not a directory", config.root));
+        }
+
+        Ok(Self { root, config: config.clone() })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `path` against the root, following symlinks of the parts
+    /// that exist, and fails if the result lies outside the root.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut normalized = PathBuf::new();
+        for component in self.root.join(path).components() {
+            match component {
+                Component::ParentDir => { normalized.pop(); }
+                Component::CurDir => {}
+                other => normalized.push(other),
+            }
+        }
+
+        // Paths that do not exist yet (write_file) are checked through their
+        // deepest existing ancestor
+        let mut existing = normalized.as_path();
+        let mut missing = Vec::new();
+        while !existing.exists() {
+            missing.push(existing.file_name().ok_or_else(|| anyhow!("Invalid path {}", path))?);
+            existing = existing.parent().ok_or_else(|| anyhow!("Invalid path {}", path))?;
+        }
+
+        let mut resolved = std::fs::canonicalize(existing)?;
+        resolved.extend(missing.into_iter().rev());
+
+        if !resolved.starts_with(&self.root) {
+            return Err(anyhow!("Path {} is outside the sandbox", path));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Lossily decodes `bytes`, cut at `max_output_bytes`.
+    pub fn render(&self, bytes: &[u8], truncated: bool) -> String {
+        let limit = bytes.len().min(self.config.max_output_bytes);
+        let mut text = String::from_utf8_lossy(&bytes[..limit]).into_owned();
+
+        if truncated || bytes.len() > limit {
+            text.push_str("\n[output truncated]");
+        }
+
+        text
+    }
+
+    /// Reads `reader` to the end but keeps at most `max_output_bytes`, so a
+    /// chatty process never blocks on a full pipe.
+    pub async fn read_capped(&self, mut reader: impl AsyncRead + Unpin) -> Result<(Vec<u8>, bool)> {
+        let mut kept = Vec::new();
+        let mut truncated = false;
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            let room = self.config.max_output_bytes.saturating_sub(kept.len());
+            kept.extend_from_slice(&buffer[..read.min(room)]);
+            truncated |= read > room;
+        }
+
+        Ok((kept, truncated))
+    }
+}
@@ -0,0 +1,79 @@
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::ToolHandler;
+use super::Sandbox;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RunCommandArgs {
+    /// Program to run; must be on the sandbox allowlist. No shell is involved.
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Working directory, relative to the sandbox root.
+    #[serde(default)]
+    pub cwd: Option<String>,
+}
+
+pub struct RunCommand(pub Arc<Sandbox>);
+
+#[async_trait::async_trait]
+impl ToolHandler for RunCommand {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        let args: RunCommandArgs = serde_json::from_value(arguments)?;
+        let sandbox = &self.0;
+
+        // Only bare program names, so the allowlist cannot be sidestepped with paths
+        if args.command.contains(std::path::MAIN_SEPARATOR) || args.command.contains('/')
+            || !sandbox.config.allowed_commands.contains(&args.command)
+        {
+            return Err(anyhow!("Command {} is not allowed", args.command));
+        }
+
+        let cwd = match &args.cwd {
+            Some(cwd) => sandbox.resolve(cwd)?,
+            None => sandbox.root().to_path_buf(),
+        };
+
+        let mut child = tokio::process::Command::new(&args.command)
+            .args(&args.args)
+            .current_dir(cwd)
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Cannot start {}: {}", args.command, e))?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let run = async {
+            let (stdout, stderr, status) = tokio::join!(
+                sandbox.read_capped(stdout),
+                sandbox.read_capped(stderr),
+                child.wait(),
+            );
+            Ok::<_, anyhow::Error>((stdout?, stderr?, status?))
+        };
+
+        let timeout = Duration::from_secs(sandbox.config.timeout_secs);
+        let ((stdout, stdout_truncated), (stderr, stderr_truncated), status) = tokio::time::timeout(timeout, run)
+            .await
+            .map_err(|_| anyhow!("Command {} timed out after {}s", args.command, sandbox.config.timeout_secs))??;
+
+        Ok(json!({
+            "exit_code": status.code(),
+            "stdout": sandbox.render(&stdout, stdout_truncated),
+            "stderr": sandbox.render(&stderr, stderr_truncated),
+        }).to_string())
+    }
+}
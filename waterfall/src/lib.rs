@@ -1,3 +1,11 @@
 mod llm;
+mod provider;
+#[cfg(feature = "builtin-tools")]
+mod builtin;
+#[cfg(test)]
+mod stand_in;
 
-pub use llm::*;
\ No newline at end of file
+pub use llm::*;
+pub use provider::*;
+#[cfg(feature = "builtin-tools")]
+pub use builtin::*;
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+
+use waterfall_core::{CryptoHash, State, StateKey};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{read_value, StateValue, LLM_NAMESPACE};
+
+/// A tool call waiting for a reviewer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApprovalRequest {
+    pub tool_call_id: String,
+    pub tool: String,
+    pub arguments: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum ApprovalDecision {
+    Approved,
+    Rejected { reason: String },
+    /// The reviewer answers later through `LlmRuntime::resolve_approval`.
+    Pending,
+}
+
+/// Decides whether a call to a tool marked `requires_approval` may run.
+#[async_trait::async_trait]
+pub trait ApprovalHook: Send + Sync {
+    async fn review(&self, request: &ApprovalRequest) -> Result<ApprovalDecision>;
+}
+
+/// Rejects every call. This is the runtime default, so sensitive tools never
+/// run unless a hook is installed.
+pub struct AutoDeny;
+
+#[async_trait::async_trait]
+impl ApprovalHook for AutoDeny {
+    async fn review(&self, _request: &ApprovalRequest) -> Result<ApprovalDecision> {
+        Ok(ApprovalDecision::Rejected { reason: "denied by policy".to_string() })
+    }
+}
+
+/// Accepts every call, for trusted environments and tests.
+pub struct AutoApprove;
+
+#[async_trait::async_trait]
+impl ApprovalHook for AutoApprove {
+    async fn review(&self, _request: &ApprovalRequest) -> Result<ApprovalDecision> {
+        Ok(ApprovalDecision::Approved)
+    }
+}
+
+/// POSTs the request as JSON to `url` and expects an `ApprovalDecision` back.
+pub struct HttpApprovalHook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpApprovalHook {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { client: reqwest::Client::new(), url: url.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl ApprovalHook for HttpApprovalHook {
+    async fn review(&self, request: &ApprovalRequest) -> Result<ApprovalDecision> {
+        let response = self.client
+            .post(&self.url)
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| anyhow!("Approval callback failed: {}", e))?;
+
+        Ok(response.json().await?)
+    }
+}
+
+/// A call parked in State until its reviewer decides.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PendingApproval {
+    pub request: ApprovalRequest,
+    /// Turn whose `tool_call` record holds the call.
+    pub turn: usize,
+    pub system_config_hash: CryptoHash,
+}
+
+pub fn pending_approvals_key() -> StateKey {
+    StateKey::single(LLM_NAMESPACE, "pending_approvals")
+}
+
+/// Parked calls by tool call id.
+pub fn read_pending_approvals(state: &State<StateValue>) -> Result<BTreeMap<String, PendingApproval>> {
+    Ok(read_value::<BTreeMap<String, PendingApproval>>(state, &pending_approvals_key())?.cloned().unwrap_or_default())
+}
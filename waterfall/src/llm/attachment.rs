@@ -0,0 +1,246 @@
+use std::path::Path;
+
+use waterfall_core::{blake3_hash, Author, CryptoHash, State, StateDiff, StateKey};
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart, ImageDetail, ImageUrl,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use super::{read_value, StateValue, TokenEstimator, LLM_NAMESPACE};
+
+/// Image types vision models accept.
+const IMAGE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Flat estimate for an image, which providers bill by resolution.
+const IMAGE_TOKENS: usize = 765;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Image,
+    /// A text document, sent inline after the message.
+    Document,
+}
+
+/// A file sent with a user turn.
+///
+/// The content is stored in state under its blake3 hash, so a file attached
+/// to several turns is kept once. Turns only record the reference.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub hash: CryptoHash,
+    pub kind: AttachmentKind,
+    pub media_type: String,
+    pub name: Option<String>,
+    /// The content as base64; not part of the stored reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+impl Attachment {
+    /// Reads a local file, taking the media type from its extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow!("Cannot read attachment {}: {}", path.display(), e))?;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+
+        Self::from_bytes(&bytes, media_type_for(path), name)
+    }
+
+    pub fn from_base64(data: &str, media_type: &str, name: Option<String>) -> Result<Self> {
+        let bytes = STANDARD.decode(data.trim())
+            .map_err(|e| anyhow!("Attachment is not valid base64: {}", e))?;
+        Self::from_bytes(&bytes, media_type, name)
+    }
+
+    /// Images must be of a type vision models accept; anything else must be
+    /// UTF-8 text.
+    pub fn from_bytes(bytes: &[u8], media_type: &str, name: Option<String>) -> Result<Self> {
+        let label = name.as_deref().unwrap_or("attachment");
+        let kind = if media_type.starts_with("image/") {
+            if !IMAGE_TYPES.contains(&media_type) {
+                return Err(anyhow!("Image {} has unsupported type {}", label, media_type));
+            }
+            AttachmentKind::Image
+        } else {
+            if std::str::from_utf8(bytes).is_err() {
+                return Err(anyhow!("Document {} ({}) is not text; only images and text documents can be attached", label, media_type));
+            }
+            AttachmentKind::Document
+        };
+
+        Ok(Self {
+            hash: blake3_hash(bytes),
+            kind,
+            media_type: media_type.to_string(),
+            name,
+            data: Some(STANDARD.encode(bytes)),
+        })
+    }
+
+    fn data(&self) -> Result<&str> {
+        self.data.as_deref().ok_or_else(|| anyhow!("Content of attachment {} is not loaded", self.hash))
+    }
+
+    fn text(&self) -> Result<String> {
+        Ok(String::from_utf8(STANDARD.decode(self.data()?)?)?)
+    }
+
+    fn content_part(&self) -> Result<ChatCompletionRequestUserMessageContentPart> {
+        Ok(match self.kind {
+            AttachmentKind::Image => ChatCompletionRequestUserMessageContentPart::ImageUrl(
+                ChatCompletionRequestMessageContentPartImage {
+                    image_url: ImageUrl {
+                        url: format!("data:{};base64,{}", self.media_type, self.data()?),
+                        detail: Some(ImageDetail::Auto),
+                    },
+                },
+            ),
+            AttachmentKind::Document => ChatCompletionRequestUserMessageContentPart::Text(
+                ChatCompletionRequestMessageContentPartText {
+                    text: format!(
+                        "Attached document {} ({}):\n{}",
+                        self.name.as_deref().unwrap_or("untitled"),
+                        self.media_type,
+                        self.text()?,
+                    ),
+                },
+            ),
+        })
+    }
+}
+
+pub fn attachments_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "user_attachments", index)
+}
+
+/// Attachment content is keyed by its own hash, so it is stored once.
+pub fn attachment_content_key(hash: &CryptoHash) -> StateKey {
+    StateKey::hashed(LLM_NAMESPACE, "attachment", hash)
+}
+
+/// Stores the attachments of turn `index`, skipping content already in state.
+pub fn attachment_diff(state: &State<StateValue>, index: usize, attachments: &[Attachment]) -> Result<StateDiff<StateValue>> {
+    let mut diff = StateDiff::by(Author::User);
+    if attachments.is_empty() {
+        return Ok(diff);
+    }
+
+    let mut references = Vec::new();
+    for attachment in attachments {
+        let key = attachment_content_key(&attachment.hash);
+        if state.get(&key).is_none() {
+            diff.insert(key, STANDARD.decode(attachment.data()?)?.into());
+        }
+        references.push(Attachment { data: None, ..attachment.clone() });
+    }
+    diff.insert(attachments_key(index), references.into());
+
+    Ok(diff)
+}
+
+/// The attachments of turn `index` with their content loaded.
+pub fn read_attachments(state: &State<StateValue>, index: usize) -> Result<Vec<Attachment>> {
+    let Some(references) = read_value::<Vec<Attachment>>(state, &attachments_key(index))? else {
+        return Ok(Vec::new());
+    };
+
+    references.iter()
+        .map(|attachment| {
+            let data = read_value::<Vec<u8>>(state, &attachment_content_key(&attachment.hash))?
+                .ok_or_else(|| anyhow!("Content of attachment {} is missing from state", attachment.hash))?;
+            Ok(Attachment { data: Some(STANDARD.encode(data)), ..attachment.clone() })
+        })
+        .collect()
+}
+
+/// The message text followed by one part per attachment. Plain text when
+/// there is nothing attached.
+pub fn user_message_content(text: &str, attachments: &[Attachment]) -> Result<ChatCompletionRequestUserMessageContent> {
+    if attachments.is_empty() {
+        return Ok(text.into());
+    }
+
+    let mut parts = vec![ChatCompletionRequestUserMessageContentPart::Text(
+        ChatCompletionRequestMessageContentPartText { text: text.to_string() },
+    )];
+    for attachment in attachments {
+        parts.push(attachment.content_part()?);
+    }
+    Ok(ChatCompletionRequestUserMessageContent::Array(parts))
+}
+
+pub fn attachment_tokens(estimator: &TokenEstimator, attachments: &[Attachment]) -> usize {
+    attachments.iter()
+        .map(|attachment| match attachment.kind {
+            AttachmentKind::Image => IMAGE_TOKENS,
+            AttachmentKind::Document => attachment.text().map(|text| estimator.estimate(&text)).unwrap_or_default(),
+        })
+        .sum()
+}
+
+fn media_type_for(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        _ => "text/plain",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachments_are_deduplicated() {
+        let image = Attachment::from_bytes(&[0x89, b'P', b'N', b'G'], "image/png", Some("a.png".to_string())).unwrap();
+        let notes = Attachment::from_base64(&STANDARD.encode("hello"), "text/plain", None).unwrap();
+        assert_eq!(notes.kind, AttachmentKind::Document);
+
+        let mut state = State::<StateValue>::default();
+        attachment_diff(&state, 0, &[image.clone(), notes.clone()]).unwrap().apply(&mut state).unwrap();
+
+        // The same image on a later turn only adds the reference
+        let diff = attachment_diff(&state, 1, std::slice::from_ref(&image)).unwrap();
+        assert_eq!(diff.storage_insert.len(), 1);
+        diff.apply(&mut state).unwrap();
+
+        assert_eq!(read_attachments(&state, 1).unwrap(), vec![image.clone()]);
+        assert_eq!(read_attachments(&state, 0).unwrap(), vec![image, notes]);
+        assert!(read_attachments(&state, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_user_message_content() {
+        let image = Attachment::from_base64("AAAA", "image/png", None).unwrap();
+        let notes = Attachment::from_bytes(b"line one", "text/markdown", Some("notes.md".to_string())).unwrap();
+
+        let content = serde_json::to_value(user_message_content("Describe these", &[image, notes]).unwrap()).unwrap();
+        assert_eq!(content, serde_json::json!([
+            { "type": "text", "text": "Describe these" },
+            { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA", "detail": "auto" } },
+            { "type": "text", "text": "Attached document notes.md (text/markdown):\nline one" },
+        ]));
+
+        // Binary documents cannot be sent as chat content parts
+        assert!(Attachment::from_bytes(&[0xff, 0xfe], "application/octet-stream", None).is_err());
+        assert!(Attachment::from_bytes(b"", "image/tiff", None).is_err());
+    }
+}
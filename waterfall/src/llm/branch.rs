@@ -0,0 +1,174 @@
+use waterfall_core::{CryptoHash, State, StateDiff, StateKey};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    assistant_message_key, pending_approvals_key, read_pending_approvals, read_summary, read_value, summary_key,
+    StateValue, LLM_NAMESPACE,
+};
+
+/// Name of the branch a conversation starts on.
+pub const MAIN_BRANCH: &str = "main";
+
+/// Where a branch of the conversation came from, stored in its own state.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BranchInfo {
+    pub name: String,
+    /// `None` for the main branch.
+    pub parent: Option<String>,
+    /// Number of turns of the parent the branch started with.
+    pub forked_at: Option<usize>,
+}
+
+impl BranchInfo {
+    fn main() -> Self {
+        Self { name: MAIN_BRANCH.to_string(), parent: None, forked_at: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch {
+    pub info: BranchInfo,
+    pub turns: usize,
+    pub active: bool,
+}
+
+pub fn branch_info_key() -> StateKey {
+    StateKey::single(LLM_NAMESPACE, "branch")
+}
+
+/// Inactive branches are kept in the active state's `sub_states` under this id.
+pub fn branch_id(name: &str) -> CryptoHash {
+    StateKey::named(LLM_NAMESPACE, "branch", name).hash()
+}
+
+pub fn read_branch_info(state: &State<StateValue>) -> Result<BranchInfo> {
+    Ok(read_value::<BranchInfo>(state, &branch_info_key())?.cloned().unwrap_or_else(BranchInfo::main))
+}
+
+/// Completed turns, which are numbered from 0 without gaps.
+pub fn turn_count(state: &State<StateValue>) -> usize {
+    (0..).take_while(|index| state.get(&assistant_message_key(*index)).is_some()).count()
+}
+
+/// The active branch first, then the others by name.
+pub fn list_branches(state: &State<StateValue>) -> Result<Vec<Branch>> {
+    let mut others = state.sub_states.values()
+        .filter(|sub_state| sub_state.get(&branch_info_key()).is_some())
+        .map(|sub_state| Ok(Branch { info: read_branch_info(sub_state)?, turns: turn_count(sub_state), active: false }))
+        .collect::<Result<Vec<_>>>()?;
+    others.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+
+    let active = Branch { info: read_branch_info(state)?, turns: turn_count(state), active: true };
+    Ok(std::iter::once(active).chain(others).collect())
+}
+
+/// Adds a branch `name` holding the first `turn` turns of the active one,
+/// which stays active. The two share their entries until either changes.
+pub fn fork_branch(state: &mut State<StateValue>, name: &str, turn: usize) -> Result<()> {
+    let active = read_branch_info(state)?;
+    if name == active.name || state.sub_states.contains_key(&branch_id(name)) {
+        return Err(anyhow!("Branch {} already exists", name));
+    }
+    let turns = turn_count(state);
+    if turn > turns {
+        return Err(anyhow!("Cannot fork at turn {}; branch {} has {} turns", turn, active.name, turns));
+    }
+
+    if state.get(&branch_info_key()).is_none() {
+        state.insert(branch_info_key(), active.clone().into())?;
+    }
+
+    let mut fork = state.fork(branch_id(name));
+    let mut diff = StateDiff::new();
+
+    // Every indexed entry of the runtime belongs to a turn
+    for key in fork.keys_in(LLM_NAMESPACE).filter(|key| key.index().is_some_and(|index| index >= turn)) {
+        diff.delete(key);
+    }
+    if read_summary(&fork)?.is_some_and(|summary| summary.covered_turns > turn) {
+        diff.delete(&summary_key());
+    }
+    let mut pending_approvals = read_pending_approvals(&fork)?;
+    let pending_count = pending_approvals.len();
+    pending_approvals.retain(|_, pending| pending.turn < turn);
+    if pending_approvals.len() != pending_count {
+        diff.update(pending_approvals_key(), pending_approvals.into());
+    }
+    diff.update(branch_info_key(), BranchInfo {
+        name: name.to_string(),
+        parent: Some(active.name.clone()),
+        forked_at: Some(turn),
+    }.into());
+    diff.refer(&branch_info_key(), branch_id(&active.name));
+    diff.apply(&mut fork)?;
+
+    state.sub_states.insert(branch_id(name), fork);
+    Ok(())
+}
+
+/// Makes branch `name` the active state. The previous one moves to its
+/// `sub_states` along with the other branches.
+pub fn switch_branch(state: &mut State<StateValue>, name: &str) -> Result<()> {
+    if read_branch_info(state)?.name == name {
+        return Ok(());
+    }
+    let target = state.sub_states.remove(&branch_id(name))
+        .ok_or_else(|| anyhow!("No branch named {}", name))?;
+
+    let mut previous = std::mem::replace(state, target);
+    state.sub_states = std::mem::take(&mut previous.sub_states);
+    let previous_name = read_branch_info(&previous)?.name;
+    state.sub_states.insert(branch_id(&previous_name), previous);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::user_message_key;
+
+    fn conversation(turns: usize) -> State<StateValue> {
+        let mut state = State::default();
+        let mut diff = StateDiff::new();
+        for index in 0..turns {
+            diff.insert(user_message_key(index), format!("question {}", index).into());
+            diff.insert(assistant_message_key(index), format!("answer {}", index).into());
+        }
+        diff.apply(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_fork_and_switch() {
+        let mut state = conversation(3);
+        fork_branch(&mut state, "retry", 1).unwrap();
+        assert!(fork_branch(&mut state, "retry", 0).is_err());
+        assert!(fork_branch(&mut state, "late", 4).is_err());
+
+        // Both sides read the frozen entries instead of holding copies
+        let fork = &state.sub_states[&branch_id("retry")];
+        assert!(Arc::ptr_eq(state.base.as_ref().unwrap(), fork.base.as_ref().unwrap()));
+        assert!(state.storage.is_empty());
+
+        switch_branch(&mut state, "retry").unwrap();
+        assert_eq!(turn_count(&state), 1);
+        let mut diff = StateDiff::new();
+        diff.insert(user_message_key(1), "another question".into());
+        diff.insert(assistant_message_key(1), "another answer".into());
+        diff.apply(&mut state).unwrap();
+
+        let branches = list_branches(&state).unwrap();
+        assert_eq!(branches.len(), 2);
+        assert_eq!((branches[0].info.name.as_str(), branches[0].turns, branches[0].active), ("retry", 2, true));
+        assert_eq!(branches[0].info.parent.as_deref(), Some(MAIN_BRANCH));
+        assert_eq!((branches[1].info.name.as_str(), branches[1].turns), (MAIN_BRANCH, 3));
+
+        switch_branch(&mut state, MAIN_BRANCH).unwrap();
+        assert_eq!(read_value::<String>(&state, &assistant_message_key(1)).unwrap().unwrap(), "answer 1");
+        assert!(switch_branch(&mut state, "missing").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use waterfall_core::{ContextWindow, WindowStrategy};
+
+/// Cheap token estimate for a model family, good enough to keep requests
+/// under the context limit without shipping a tokenizer per provider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenEstimator {
+    /// Average number of UTF-8 bytes per token.
+    pub bytes_per_token: f32,
+    /// Fixed overhead the chat format adds around every message.
+    pub tokens_per_message: usize,
+}
+
+impl TokenEstimator {
+    pub fn for_model(model: &str) -> Self {
+        // Strip router prefixes such as `google/` or `anthropic/`
+        let model = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+
+        let (bytes_per_token, tokens_per_message) = if model.starts_with("claude") {
+            (3.5, 5)
+        } else if model.starts_with("gemini") || model.starts_with("gemma") {
+            (4.0, 3)
+        } else if ["llama", "mistral", "mixtral", "qwen", "deepseek", "phi"]
+            .iter()
+            .any(|family| model.starts_with(family))
+        {
+            (3.6, 4)
+        } else {
+            // gpt-*, o-series and anything unknown
+            (4.0, 4)
+        };
+
+        Self { bytes_per_token, tokens_per_message }
+    }
+
+    pub fn estimate(&self, text: &str) -> usize {
+        (text.len() as f32 / self.bytes_per_token).ceil() as usize
+    }
+
+    pub fn estimate_message(&self, text: &str) -> usize {
+        self.estimate(text) + self.tokens_per_message
+    }
+}
+
+/// Picks which past turns to send, given the estimated size of each turn and
+/// the tokens already reserved for the system prompt and the new message.
+/// The first `summarized_turns` turns are covered by the summary and left
+/// out, except for a pinned first turn.
+///
+/// Returns turn indices in conversation order.
+pub fn select_turns(turn_tokens: &[usize], summarized_turns: usize, window: &ContextWindow, reserved_tokens: usize) -> Vec<usize> {
+    let turn_count = turn_tokens.len();
+    let pinned = window.pin_first_turn && turn_count > 0;
+    let first_unpinned = summarized_turns.min(turn_count).max(if pinned { 1 } else { 0 });
+
+    let mut selected = match window.strategy {
+        WindowStrategy::Full => (first_unpinned..turn_count).collect(),
+        WindowStrategy::LastTurns { turns } => {
+            (turn_count.saturating_sub(turns).max(first_unpinned)..turn_count).collect::<Vec<_>>()
+        }
+        WindowStrategy::TokenBudget { max_tokens } => {
+            let mut remaining = max_tokens.saturating_sub(reserved_tokens);
+            if pinned {
+                remaining = remaining.saturating_sub(turn_tokens[0]);
+            }
+
+            // Walk back from the most recent turn and stop at the first one
+            // that no longer fits, so the window never has gaps
+            let mut selected = Vec::new();
+            for index in (first_unpinned..turn_count).rev() {
+                if turn_tokens[index] > remaining {
+                    break;
+                }
+                remaining -= turn_tokens[index];
+                selected.push(index);
+            }
+            selected.reverse();
+            selected
+        }
+    };
+
+    if pinned {
+        selected.insert(0, 0);
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(strategy: WindowStrategy, pin_first_turn: bool) -> ContextWindow {
+        ContextWindow { strategy, pin_first_turn }
+    }
+
+    #[test]
+    fn test_estimator_families() {
+        let claude = TokenEstimator::for_model("anthropic/claude-sonnet-4");
+        let gpt = TokenEstimator::for_model("gpt-4o");
+
+        assert_eq!(gpt.estimate("abcdefgh"), 2);
+        assert!(claude.estimate_message(&"a".repeat(100)) > gpt.estimate_message(&"a".repeat(100)));
+    }
+
+    #[test]
+    fn test_last_turns() {
+        let turns = [10; 5];
+
+        assert_eq!(select_turns(&turns, 0, &window(WindowStrategy::Full, false), 0), vec![0, 1, 2, 3, 4]);
+        assert_eq!(select_turns(&turns, 0, &window(WindowStrategy::LastTurns { turns: 2 }, false), 0), vec![3, 4]);
+        assert_eq!(select_turns(&turns, 0, &window(WindowStrategy::LastTurns { turns: 2 }, true), 0), vec![0, 3, 4]);
+        assert_eq!(select_turns(&turns, 0, &window(WindowStrategy::LastTurns { turns: 9 }, true), 0), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_token_budget() {
+        let turns = [50, 10, 30, 20];
+        let budget = |max_tokens| WindowStrategy::TokenBudget { max_tokens };
+
+        assert_eq!(select_turns(&turns, 0, &window(budget(60), false), 0), vec![1, 2, 3]);
+        assert_eq!(select_turns(&turns, 0, &window(budget(59), false), 0), vec![2, 3]);
+        assert_eq!(select_turns(&turns, 0, &window(budget(60), false), 15), vec![3]);
+        assert_eq!(select_turns(&turns, 0, &window(budget(80), true), 0), vec![0, 3]);
+        // The pinned turn is kept even when it alone blows the budget
+        assert_eq!(select_turns(&turns, 0, &window(budget(10), true), 0), vec![0]);
+        assert!(select_turns(&[], 0, &window(budget(10), true), 0).is_empty());
+    }
+
+    #[test]
+    fn test_summarized_turns() {
+        let turns = [10; 6];
+        let budget = WindowStrategy::TokenBudget { max_tokens: 100 };
+
+        assert_eq!(select_turns(&turns, 4, &window(WindowStrategy::Full, false), 0), vec![4, 5]);
+        // The pinned turn is the first of the conversation, even when summarized
+        assert_eq!(select_turns(&turns, 4, &window(WindowStrategy::Full, true), 0), vec![0, 4, 5]);
+        assert_eq!(select_turns(&turns, 3, &window(WindowStrategy::LastTurns { turns: 2 }, true), 0), vec![0, 4, 5]);
+        assert_eq!(select_turns(&turns, 3, &window(budget, true), 0), vec![0, 3, 4, 5]);
+    }
+}
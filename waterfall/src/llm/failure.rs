@@ -0,0 +1,164 @@
+use waterfall_core::{BoxError, State, StateKey};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{read_value, StateValue, LLM_NAMESPACE};
+
+/// A turn that failed, recorded at its index until a later turn there
+/// succeeds.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InstructionFailure {
+    /// The instruction and turn, e.g. `llm_instruction[3]`.
+    pub instruction: String,
+    pub attempts: usize,
+    pub error: String,
+    /// Whether the queue went on without the turn.
+    pub skipped: bool,
+}
+
+/// A provider request that failed on its last allowed attempt.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct ExhaustedRetries {
+    pub attempts: usize,
+    pub error: BoxError,
+}
+
+pub fn failure_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "failure", index)
+}
+
+pub fn read_failure(state: &State<StateValue>, index: usize) -> Result<Option<InstructionFailure>> {
+    Ok(read_value::<InstructionFailure>(state, &failure_key(index))?.cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assistant_message_key, stand_in, LlmInstruction, LlmRuntime, LlmTurn, StateValue};
+    use axum::http::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use waterfall_core::{CryptoHash, ErrorPolicy, Instruction, LLMConfig, ProviderConfig, Runtime, RuntimeError, State};
+
+    /// Serves an Ollama model whose first `failures` chat requests fail with `status`.
+    async fn failing_model(failures: usize, status: StatusCode) -> String {
+        let remaining = AtomicUsize::new(failures);
+        stand_in::ollama(&["completion", "tools"], move |_| {
+            match remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)) {
+                Ok(_) => Err(status),
+                Err(_) => Ok("Hello".to_string()),
+            }
+        }).await
+    }
+
+    async fn failing_runtime(failures: usize, status: StatusCode, error_policy: ErrorPolicy) -> (LlmRuntime, LLMConfig) {
+        let config = LLMConfig {
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            provider: ProviderConfig::Ollama { base_url: Some(failing_model(failures, status).await), tool_calls: Default::default() },
+            error_policy,
+            ..Default::default()
+        };
+        let mut runtime = LlmRuntime::new();
+        runtime.set_quiet(true);
+        runtime.inject_system_config(&config).await.unwrap();
+        (runtime, config)
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() {
+        let policy = ErrorPolicy { retries: 2, retry_delay_ms: 0, skip: false };
+
+        // Retries absorb failures that go away
+        let (mut runtime, config) = failing_runtime(2, StatusCode::SERVICE_UNAVAILABLE, policy.clone()).await;
+        runtime.push_instruction(LlmInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        runtime.execute().await.unwrap();
+        assert!(read_value::<String>(&runtime.state, &assistant_message_key(0)).unwrap().is_some());
+        assert!(read_failure(&runtime.state, 0).unwrap().is_none());
+
+        // Without skipping, the last failure stops the queue
+        let (mut runtime, config) = failing_runtime(3, StatusCode::SERVICE_UNAVAILABLE, policy.clone()).await;
+        runtime.push_instruction(LlmInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        match runtime.execute().await {
+            Err(RuntimeError::Failed { attempts, .. }) => assert_eq!(attempts, 3),
+            other => panic!("expected a failed turn, got {:?}", other),
+        }
+        let failure = read_failure(&runtime.state, 0).unwrap().unwrap();
+        assert_eq!((failure.instruction.as_str(), failure.attempts, failure.skipped), ("llm_instruction[0]", 3, false));
+        assert_eq!(runtime.state.meta(&failure_key(0)).unwrap().instruction.as_deref(), Some("llm_instruction[0]"));
+
+        // Permanent errors are not retried
+        let (mut runtime, config) = failing_runtime(1, StatusCode::BAD_REQUEST, policy.clone()).await;
+        runtime.push_instruction(LlmInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        match runtime.execute().await {
+            Err(RuntimeError::Failed { attempts, .. }) => assert_eq!(attempts, 1),
+            other => panic!("expected a failed turn, got {:?}", other),
+        }
+
+        // Skipping records the failure and goes on
+        let (mut runtime, config) = failing_runtime(1, StatusCode::SERVICE_UNAVAILABLE, ErrorPolicy { retries: 0, skip: true, ..policy }).await;
+        runtime.push_instruction(LlmInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        runtime.execute().await.unwrap();
+        assert!(read_failure(&runtime.state, 0).unwrap().unwrap().skipped);
+        assert!(read_value::<String>(&runtime.state, &assistant_message_key(0)).unwrap().is_none());
+
+        // The next turn takes the index over and clears the failure
+        runtime.push_instruction(LlmInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        runtime.execute().await.unwrap();
+        assert!(read_value::<String>(&runtime.state, &assistant_message_key(0)).unwrap().is_some());
+        assert!(read_failure(&runtime.state, 0).unwrap().is_none());
+    }
+
+    /// A turn that must not fail.
+    #[derive(Clone)]
+    struct StrictInstruction(LlmInstruction);
+
+    impl Instruction<StateValue> for StrictInstruction {
+        const INSTRUCTION_NAME: &'static str = "strict_instruction";
+        const FALLIBLE: bool = false;
+
+        type Error = anyhow::Error;
+
+        fn parse_from(value: StateValue, system_config_hash: CryptoHash) -> Self {
+            Self(LlmInstruction::parse_from(value, system_config_hash))
+        }
+
+        fn parse_into(&self) -> StateValue {
+            self.0.parse_into()
+        }
+
+        fn system_config_hash(&self) -> CryptoHash {
+            self.0.system_config_hash()
+        }
+
+        fn prepare(&mut self, state: &State<StateValue>) -> Result<(), Self::Error> {
+            self.0.prepare(state)
+        }
+    }
+
+    impl LlmTurn for StrictInstruction {
+        fn turn(&self) -> &LlmInstruction {
+            &self.0
+        }
+
+        fn turn_mut(&mut self) -> &mut LlmInstruction {
+            &mut self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_infallible_instruction_halts() {
+        // Neither retried nor skipped, whatever the policy says
+        let policy = ErrorPolicy { retries: 2, retry_delay_ms: 0, skip: true };
+        let (mut runtime, config) = failing_runtime(1, StatusCode::SERVICE_UNAVAILABLE, policy).await;
+        runtime.push_instruction(StrictInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        runtime.push_instruction(StrictInstruction::parse_from("hi".into(), config.id.clone())).unwrap();
+        match runtime.execute().await {
+            Err(RuntimeError::Halted { instruction, .. }) => assert_eq!(instruction, "strict_instruction[0]"),
+            other => panic!("expected a halted queue, got {:?}", other),
+        }
+        assert!(read_failure(&runtime.state, 0).unwrap().is_none());
+        assert!(read_value::<String>(&runtime.state, &assistant_message_key(0)).unwrap().is_none());
+    }
+}
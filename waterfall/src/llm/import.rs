@@ -0,0 +1,339 @@
+use waterfall_core::{Author, State, StateDiff};
+
+use serde_json::Value;
+
+use super::{
+    assistant_message_key, attachment_diff, tool_call_key, user_message_key, Attachment, StateValue, ToolCallRecord,
+    ToolResult,
+};
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ImportError {
+    #[error("invalid JSON{}: {reason}", .line.map(|line| format!(" on line {}", line)).unwrap_or_default())]
+    InvalidJson { line: Option<usize>, reason: String },
+    #[error("expected an array of chat messages or objects with a messages field")]
+    NotAConversation,
+    #[error("message {position}: expected {expected}, found {found}")]
+    RoleOrder { position: usize, expected: &'static str, found: String },
+    #[error("message {position}: tool result for unknown call {id}")]
+    UnknownToolCall { position: usize, id: String },
+    #[error("message {position}: no tool result for call {id}")]
+    MissingToolResult { position: usize, id: String },
+    #[error("message {position}: turn ends after tool calls without an answer")]
+    IncompleteTurn { position: usize },
+    #[error("message {position}: {reason}")]
+    InvalidMessage { position: usize, reason: String },
+}
+
+/// A message, or part of one, the importer left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedMessage {
+    /// Position of the message in its conversation.
+    pub position: usize,
+    pub reason: String,
+}
+
+/// A conversation converted to the runtime's turn layout.
+#[derive(Debug, Clone)]
+pub struct ImportedConversation {
+    /// Writes turns `0..turns` into an empty session.
+    pub diff: StateDiff<StateValue>,
+    pub turns: usize,
+    /// The leading system message, which belongs in the agent config.
+    pub system_prompt: Option<String>,
+    /// A last user message that was never answered. It is not written, so
+    /// it can be sent as the next instruction.
+    pub pending_message: Option<(String, Vec<Attachment>)>,
+    pub skipped: Vec<SkippedMessage>,
+}
+
+/// Reads OpenAI chat exports: a JSON array of messages, an object with a
+/// `messages` field or an array of those, or JSONL with a message or such
+/// an object on every line. Returns one conversation per `messages` field.
+pub fn import_transcripts(input: &str) -> Result<Vec<ImportedConversation>, ImportError> {
+    parse_conversations(input)?.iter().map(|messages| import_messages(messages)).collect()
+}
+
+fn parse_conversations(input: &str) -> Result<Vec<Vec<Value>>, ImportError> {
+    let values = match serde_json::from_str::<Value>(input) {
+        Ok(Value::Array(values)) => values,
+        Ok(value) => vec![value],
+        // Not a single document, so one per line
+        Err(_) => input.lines().enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| serde_json::from_str(line).map_err(|e| ImportError::InvalidJson {
+                line: Some(number + 1),
+                reason: e.to_string(),
+            }))
+            .collect::<Result<Vec<Value>, _>>()?,
+    };
+
+    if values.is_empty() {
+        return Err(ImportError::NotAConversation);
+    }
+    if values.iter().all(|value| value.get("messages").is_some()) {
+        return values.into_iter()
+            .map(|mut value| match value["messages"].take() {
+                Value::Array(messages) => Ok(messages),
+                _ => Err(ImportError::NotAConversation),
+            })
+            .collect();
+    }
+    // Messages without a role are reported as skipped by `import_messages`
+    if values.iter().any(|value| value.get("role").is_some()) {
+        return Ok(vec![values]);
+    }
+    Err(ImportError::NotAConversation)
+}
+
+struct OpenTurn {
+    /// Position of the user message.
+    position: usize,
+    user: String,
+    attachments: Vec<Attachment>,
+    tool_calls: Vec<ToolCallRecord>,
+    /// Ids of calls of the last round still waiting for their result.
+    unanswered: Vec<String>,
+    rounds: usize,
+}
+
+/// Converts one conversation, checking that users and assistants take
+/// turns and that every tool call gets its result.
+pub fn import_messages(messages: &[Value]) -> Result<ImportedConversation, ImportError> {
+    let mut system_prompt = None;
+    let mut turns: Vec<(OpenTurn, String)> = Vec::new();
+    let mut open: Option<OpenTurn> = None;
+    let mut skipped = Vec::new();
+
+    for (position, message) in messages.iter().enumerate() {
+        let Some(role) = message["role"].as_str() else {
+            skipped.push(SkippedMessage { position, reason: "message has no role".to_string() });
+            continue;
+        };
+
+        match role {
+            "system" | "developer" if system_prompt.is_none() && turns.is_empty() && open.is_none() => {
+                system_prompt = Some(content_text(message, position, false, &mut skipped)?.0);
+            }
+            "system" | "developer" => {
+                skipped.push(SkippedMessage { position, reason: format!("{} message after the conversation started", role) });
+            }
+            "user" => {
+                if let Some(turn) = &open {
+                    return Err(match turn.tool_calls.is_empty() {
+                        true => ImportError::RoleOrder { position, expected: "assistant", found: role.to_string() },
+                        false => ImportError::IncompleteTurn { position },
+                    });
+                }
+                let (user, attachments) = content_text(message, position, true, &mut skipped)?;
+                open = Some(OpenTurn { position, user, attachments, tool_calls: Vec::new(), unanswered: Vec::new(), rounds: 0 });
+            }
+            "assistant" => {
+                let Some(turn) = open.as_mut() else {
+                    return Err(ImportError::RoleOrder { position, expected: "user", found: role.to_string() });
+                };
+                if let Some(id) = turn.unanswered.first() {
+                    return Err(ImportError::MissingToolResult { position, id: id.clone() });
+                }
+
+                let (text, _) = content_text(message, position, false, &mut skipped)?;
+                match message["tool_calls"].as_array().filter(|calls| !calls.is_empty()) {
+                    Some(calls) => {
+                        for call in calls {
+                            let record = tool_call_record(call, turn.rounds, position)?;
+                            turn.unanswered.push(record.id.clone());
+                            turn.tool_calls.push(record);
+                        }
+                        turn.rounds += 1;
+                        if !text.is_empty() {
+                            skipped.push(SkippedMessage { position, reason: "text sent along with tool calls is not kept".to_string() });
+                        }
+                    }
+                    None => turns.push((open.take().expect("checked above"), text)),
+                }
+            }
+            "tool" => {
+                let id = message["tool_call_id"].as_str().unwrap_or_default().to_string();
+                let Some(turn) = open.as_mut().filter(|turn| turn.unanswered.contains(&id)) else {
+                    return Err(ImportError::UnknownToolCall { position, id });
+                };
+                turn.unanswered.retain(|unanswered| *unanswered != id);
+                let (content, _) = content_text(message, position, false, &mut skipped)?;
+                if let Some(record) = turn.tool_calls.iter_mut().find(|record| record.id == id) {
+                    record.result = tool_result(content);
+                }
+            }
+            _ => skipped.push(SkippedMessage { position, reason: format!("unsupported role {}", role) }),
+        }
+    }
+
+    let pending_message = match open {
+        Some(turn) if !turn.tool_calls.is_empty() => {
+            return Err(ImportError::IncompleteTurn { position: messages.len().saturating_sub(1) });
+        }
+        Some(turn) => Some((turn.user, turn.attachments)),
+        None => None,
+    };
+
+    // Content is checked against an empty session, which is what the diff is for
+    let empty = State::default();
+    let mut diff = StateDiff::new();
+    for (index, (turn, assistant)) in turns.iter().enumerate() {
+        diff.insert_by(Author::User, user_message_key(index), turn.user.clone().into());
+        diff.insert_by(Author::Model, assistant_message_key(index), assistant.clone().into());
+        if !turn.tool_calls.is_empty() {
+            diff.insert_by(Author::Tool, tool_call_key(index), turn.tool_calls.clone().into());
+        }
+        diff.merge(attachment_diff(&empty, index, &turn.attachments).map_err(|e| ImportError::InvalidMessage {
+            position: turn.position,
+            reason: e.to_string(),
+        })?);
+    }
+
+    Ok(ImportedConversation { diff, turns: turns.len(), system_prompt, pending_message, skipped })
+}
+
+/// The text of a message, with inline images of user messages as attachments.
+/// Parts that cannot be kept are reported and left out.
+fn content_text(message: &Value, position: usize, images: bool, skipped: &mut Vec<SkippedMessage>) -> Result<(String, Vec<Attachment>), ImportError> {
+    let parts = match &message["content"] {
+        Value::Null => return Ok((String::new(), Vec::new())),
+        Value::String(text) => return Ok((text.clone(), Vec::new())),
+        Value::Array(parts) => parts,
+        _ => return Err(ImportError::InvalidMessage { position, reason: "content is neither text nor a list of parts".to_string() }),
+    };
+
+    let mut texts = Vec::new();
+    let mut attachments = Vec::new();
+    for part in parts {
+        match part["type"].as_str().unwrap_or_default() {
+            "text" => texts.push(part["text"].as_str().unwrap_or_default()),
+            "image_url" if images => {
+                let url = part["image_url"]["url"].as_str().unwrap_or_default();
+                let inline = url.strip_prefix("data:").and_then(|data| data.split_once(";base64,"));
+                match inline {
+                    Some((media_type, data)) => attachments.push(Attachment::from_base64(data, media_type, None)
+                        .map_err(|e| ImportError::InvalidMessage { position, reason: e.to_string() })?),
+                    None => skipped.push(SkippedMessage { position, reason: format!("image {} is not inline", url) }),
+                }
+            }
+            kind => skipped.push(SkippedMessage { position, reason: format!("unsupported content part {}", kind) }),
+        }
+    }
+
+    Ok((texts.join("\n"), attachments))
+}
+
+fn tool_call_record(call: &Value, round: usize, position: usize) -> Result<ToolCallRecord, ImportError> {
+    let invalid = |reason: &str| ImportError::InvalidMessage { position, reason: reason.to_string() };
+    Ok(ToolCallRecord {
+        id: call["id"].as_str().ok_or_else(|| invalid("tool call has no id"))?.to_string(),
+        round,
+        name: call["function"]["name"].as_str().ok_or_else(|| invalid("tool call has no function name"))?.to_string(),
+        arguments: call["function"]["arguments"].as_str().unwrap_or("{}").to_string(),
+        // Replaced once the tool message arrives
+        result: ToolResult::PendingApproval,
+    })
+}
+
+/// Reverses `ToolResult::content`, so exported sessions come back as they were.
+fn tool_result(content: String) -> ToolResult {
+    let value = serde_json::from_str::<Value>(&content).unwrap_or_default();
+    let only = |field: &str| value.as_object()
+        .filter(|object| object.len() == 1)
+        .and_then(|object| object.get(field))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    if let Some(error) = only("error") {
+        ToolResult::Error(error)
+    } else if let Some(reason) = only("rejected") {
+        ToolResult::Rejected(reason)
+    } else if value["status"] == "pending_approval" {
+        ToolResult::PendingApproval
+    } else {
+        ToolResult::Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use waterfall_core::Instruction;
+
+    use super::*;
+    use crate::{read_attachments, LlmInstruction};
+
+    #[test]
+    fn test_import_conversation() {
+        let messages = json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "Weather in Oslo?" },
+            { "role": "assistant", "content": null, "tool_calls": [
+                { "id": "call_1", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" } },
+            ] },
+            { "role": "tool", "tool_call_id": "call_1", "content": "{\"error\":\"offline\"}" },
+            { "role": "assistant", "content": "I could not check." },
+            { "role": "function", "name": "legacy", "content": "ignored" },
+            { "role": "user", "content": [
+                { "type": "text", "text": "What is this?" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+                { "type": "input_audio", "input_audio": {} },
+            ] },
+            { "role": "assistant", "content": "A picture." },
+            { "role": "user", "content": "Still there?" },
+        ]);
+
+        let conversations = import_transcripts(&messages.to_string()).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.turns, 2);
+        assert_eq!(conversation.system_prompt.as_deref(), Some("Be brief."));
+        assert_eq!(conversation.pending_message.as_ref().unwrap().0, "Still there?");
+        assert_eq!(conversation.skipped.iter().map(|skipped| skipped.position).collect::<Vec<_>>(), [5, 6]);
+
+        // The runtime reads the turns back as its own
+        let mut state = State::default();
+        conversation.diff.apply(&mut state).unwrap();
+        let mut ix = LlmInstruction::default();
+        ix.prepare(&state).unwrap();
+        assert_eq!(ix.new_message_index, 2);
+        let calls = ix.memory[0].2.as_ref().unwrap();
+        assert_eq!((calls[0].name.as_str(), &calls[0].result), ("weather", &ToolResult::Error("offline".to_string())));
+        assert_eq!(read_attachments(&state, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_import_rejects_bad_order() {
+        let jsonl = [
+            json!({ "role": "user", "content": "Hi" }).to_string(),
+            json!({ "role": "user", "content": "Hello?" }).to_string(),
+        ].join("\n");
+        assert_eq!(
+            import_transcripts(&jsonl).unwrap_err(),
+            ImportError::RoleOrder { position: 1, expected: "assistant", found: "user".to_string() },
+        );
+
+        let unanswered = json!({ "messages": [
+            { "role": "user", "content": "Weather?" },
+            { "role": "assistant", "tool_calls": [{ "id": "call_1", "function": { "name": "weather", "arguments": "{}" } }] },
+            { "role": "assistant", "content": "Rain" },
+        ] });
+        assert!(matches!(import_transcripts(&unanswered.to_string()), Err(ImportError::MissingToolResult { position: 2, .. })));
+        assert!(matches!(import_transcripts("{\"role\": \"user\"}\nnot json"), Err(ImportError::InvalidJson { line: Some(2), .. })));
+        assert_eq!(import_transcripts("{\"foo\": 1}").unwrap_err(), ImportError::NotAConversation);
+    }
+
+    #[test]
+    fn test_import_skips_messages_without_role() {
+        let jsonl = [
+            json!({ "role": "user", "content": "Hi" }),
+            json!({ "content": "stray" }),
+            json!({ "role": "assistant", "content": "Hello" }),
+        ].map(|message| message.to_string()).join("\n");
+
+        let conversations = import_transcripts(&jsonl).unwrap();
+        assert_eq!(conversations[0].turns, 1);
+        assert_eq!(conversations[0].skipped, vec![SkippedMessage { position: 1, reason: "message has no role".to_string() }]);
+    }
+}
@@ -1,50 +1,92 @@
-use waterfall_core::{state_key, CryptoHash, Instruction, State};
+use waterfall_core::{CryptoHash, Instruction, State, StateKey};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use super::{read_tool_calls, read_value, Attachment, StateValue, ToolCallRecord, LLM_NAMESPACE};
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct LlmInstruction {
     pub system_config_hash: CryptoHash,
-    pub memory: Vec<(String, String, Option<String>)>, // user | assistant | function call
+    pub memory: Vec<(String, String, Option<Vec<ToolCallRecord>>)>, // user | assistant | function call
     pub new_message: String,
     pub new_message_index: usize,
+    /// Files sent along with `new_message`.
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+pub fn user_message_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "user_message", index)
+}
+
+pub fn assistant_message_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "assistant_message", index)
 }
 
-impl Instruction<String> for LlmInstruction {
+impl LlmInstruction {
+    pub fn with_attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+}
+
+/// An instruction the LLM runtime runs as a conversation turn. Instructions
+/// of their own name or fallibility wrap an [`LlmInstruction`].
+pub trait LlmTurn: Instruction<StateValue, Error = anyhow::Error> {
+    fn turn(&self) -> &LlmInstruction;
+    fn turn_mut(&mut self) -> &mut LlmInstruction;
+}
+
+impl LlmTurn for LlmInstruction {
+    fn turn(&self) -> &LlmInstruction {
+        self
+    }
+
+    fn turn_mut(&mut self) -> &mut LlmInstruction {
+        self
+    }
+}
+
+impl Instruction<StateValue> for LlmInstruction {
     const INSTRUCTION_NAME: &'static str = "llm_instruction";
-    const FALLIBLE: bool = false;
+    // Requests fail for reasons that go away, like rate limits and timeouts
+    const FALLIBLE: bool = true;
 
     type Error = anyhow::Error;
 
-    fn parse_from(value: String, system_config_hash: CryptoHash) -> Self {
+    fn parse_from(value: StateValue, system_config_hash: CryptoHash) -> Self {
         Self {
             system_config_hash,
             memory: Vec::new(),
-            new_message: value,
+            new_message: value.into_text(),
             new_message_index: 0,
+            attachments: Vec::new(),
         }
     }
 
-    fn parse_into(&self) -> String {
-        self.new_message.clone()
+    fn parse_into(&self) -> StateValue {
+        self.new_message.clone().into()
+    }
+
+    fn system_config_hash(&self) -> CryptoHash {
+        self.system_config_hash.clone()
     }
 
-    fn prepare(&mut self, state: &State<String>) -> Result<(), Self::Error> {
+    fn prepare(&mut self, state: &State<StateValue>) -> Result<(), Self::Error> {
         loop {
-            let user_message_key = state_key!("user_message", self.new_message_index);
-            let assistant_message_key = state_key!("assistant_message", self.new_message_index);
-            let toolcall_message_key = state_key!("tool_call", self.new_message_index);
+            let user_message_key = user_message_key(self.new_message_index);
+            let assistant_message_key = assistant_message_key(self.new_message_index);
 
-            let maybe_user_message = state.storage.get(&user_message_key);
-            let maybe_assistant_message = state.storage.get(&assistant_message_key);
+            let maybe_user_message = read_value::<String>(state, &user_message_key)?;
+            let maybe_assistant_message = read_value::<String>(state, &assistant_message_key)?;
             if maybe_user_message.is_none() || maybe_assistant_message.is_none() { break; }
             
             let user_message = maybe_user_message.unwrap().clone();
             let assistant_message = maybe_assistant_message.unwrap().clone();
-            let tool_call = state.storage.get(&toolcall_message_key);
+            let tool_calls = read_tool_calls(state, self.new_message_index)?;
             
-            self.memory.push((user_message, assistant_message, tool_call.cloned()));
+            self.memory.push((user_message, assistant_message, (!tool_calls.is_empty()).then_some(tool_calls)));
             
             self.new_message_index += 1;
         }
@@ -0,0 +1,437 @@
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use waterfall_core::{McpServerConfig, McpTransport};
+
+use anyhow::{anyhow, Result};
+use async_openai::types::FunctionObject;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::Mutex;
+
+use super::ToolHandler;
+
+pub const MCP_PROTOCOL_VERSION: &str = "2025-03-26";
+
+/// A tool as listed by an MCP server.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(rename = "inputSchema")]
+    pub input_schema: Value,
+}
+
+struct StdioPipes {
+    // Held so the server is killed when the client goes away
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+enum Transport {
+    /// Requests are serialized, since responses arrive on a single pipe.
+    Stdio(Box<Mutex<StdioPipes>>),
+    Http {
+        client: reqwest::Client,
+        url: String,
+        session_id: StdMutex<Option<String>>,
+    },
+}
+
+/// A JSON-RPC connection to one MCP server.
+pub struct McpClient {
+    pub name: String,
+    transport: Transport,
+    next_id: AtomicU64,
+    /// Limit on each request, so a hung server does not block its caller.
+    timeout: Duration,
+}
+
+impl McpClient {
+    /// Starts or reaches the server and performs the initialization
+    /// handshake, within the server's `timeout_secs`.
+    pub async fn connect(config: &McpServerConfig, http: &reqwest::Client) -> Result<Self> {
+        tokio::time::timeout(Duration::from_secs(config.timeout_secs), Self::start(config, http))
+            .await
+            .map_err(|_| anyhow!("MCP server {} did not initialize within {}s", config.name, config.timeout_secs))?
+    }
+
+    async fn start(config: &McpServerConfig, http: &reqwest::Client) -> Result<Self> {
+        let transport = match &config.transport {
+            McpTransport::Stdio { command, args, env } => {
+                let mut child = tokio::process::Command::new(command)
+                    .args(args)
+                    .envs(env)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| anyhow!("Cannot start MCP server {}: {}", config.name, e))?;
+
+                let stdin = child.stdin.take().expect("stdin is piped");
+                let stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+                Transport::Stdio(Box::new(Mutex::new(StdioPipes { _child: child, stdin, stdout })))
+            }
+            McpTransport::Http { url } => Transport::Http {
+                client: http.clone(),
+                url: url.clone(),
+                session_id: StdMutex::new(None),
+            },
+        };
+
+        let client = Self {
+            name: config.name.clone(),
+            transport,
+            next_id: AtomicU64::new(1),
+            timeout: Duration::from_secs(config.timeout_secs),
+        };
+
+        client.request("initialize", json!({
+            "protocolVersion": MCP_PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": "waterfall", "version": env!("CARGO_PKG_VERSION") },
+        })).await?;
+        client.notify("notifications/initialized").await?;
+
+        Ok(client)
+    }
+
+    /// All tools of the server, following pagination cursors.
+    pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let page = self.request("tools/list", params).await?;
+
+            tools.extend(serde_json::from_value::<Vec<McpTool>>(page["tools"].clone())?);
+            cursor = page["nextCursor"].as_str().map(|c| c.to_string());
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// Calls `name` and renders its result for a tool message. Results the
+    /// server flags with `isError` become errors, so the model sees them as such.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<String> {
+        let result = self.request("tools/call", json!({ "name": name, "arguments": arguments })).await?;
+        mcp_tool_output(&result)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+
+        let response = tokio::time::timeout(self.timeout, self.exchange(id, &message, method))
+            .await
+            .map_err(|_| anyhow!("MCP server {} did not answer {} within {}s", self.name, method, self.timeout.as_secs()))??;
+
+        if let Some(error) = response.get("error") {
+            return Err(anyhow!(
+                "MCP server {} failed {}: {} ({})",
+                self.name, method, error["message"].as_str().unwrap_or("unknown error"), error["code"]
+            ));
+        }
+
+        Ok(response["result"].clone())
+    }
+
+    /// Sends request `id` and waits for its response.
+    async fn exchange(&self, id: u64, message: &Value, method: &str) -> Result<Value> {
+        let response = match &self.transport {
+            Transport::Stdio(pipes) => {
+                let mut pipes = pipes.lock().await;
+                write_line(&mut pipes.stdin, message).await?;
+
+                loop {
+                    let line = pipes.stdout.next_line().await?
+                        .ok_or_else(|| anyhow!("MCP server {} closed its output", self.name))?;
+                    let Ok(incoming) = serde_json::from_str::<Value>(&line) else {
+                        continue;
+                    };
+
+                    if incoming.get("method").is_some() {
+                        // Requests from the server; notifications need no answer
+                        if let Some(request_id) = incoming.get("id") {
+                            let reply = server_request_reply(request_id, &incoming["method"]);
+                            write_line(&mut pipes.stdin, &reply).await?;
+                        }
+                    } else if incoming["id"] == json!(id) {
+                        break incoming;
+                    }
+                }
+            }
+            Transport::Http { .. } => {
+                self.post(message).await?
+                    .ok_or_else(|| anyhow!("MCP server {} sent no response to {}", self.name, method))?
+            }
+        };
+        Ok(response)
+    }
+
+    async fn notify(&self, method: &str) -> Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+
+        let sent = async {
+            match &self.transport {
+                Transport::Stdio(pipes) => write_line(&mut pipes.lock().await.stdin, &message).await,
+                Transport::Http { .. } => self.post(&message).await.map(|_| ()),
+            }
+        };
+        tokio::time::timeout(self.timeout, sent)
+            .await
+            .map_err(|_| anyhow!("MCP server {} did not accept {} within {}s", self.name, method, self.timeout.as_secs()))?
+    }
+
+    /// Sends one message over HTTP and returns the response carrying the
+    /// same id, whether it comes back as JSON or as an event stream.
+    async fn post(&self, message: &Value) -> Result<Option<Value>> {
+        let Transport::Http { client, url, session_id } = &self.transport else {
+            unreachable!("post is only used by the HTTP transport");
+        };
+
+        let mut request = client.post(url)
+            .header(reqwest::header::ACCEPT, "application/json, text/event-stream")
+            .header("MCP-Protocol-Version", MCP_PROTOCOL_VERSION)
+            .json(message);
+        if let Some(session_id) = session_id.lock().unwrap().as_ref() {
+            request = request.header("Mcp-Session-Id", session_id);
+        }
+
+        let response = request.send().await?
+            .error_for_status()
+            .map_err(|e| anyhow!("MCP server {} rejected the request: {}", self.name, e))?;
+
+        if let Some(id) = response.headers().get("Mcp-Session-Id").and_then(|v| v.to_str().ok()) {
+            *session_id.lock().unwrap() = Some(id.to_string());
+        }
+
+        let is_stream = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        let body = response.text().await?;
+
+        if message.get("id").is_none() || body.trim().is_empty() {
+            return Ok(None);
+        }
+
+        if !is_stream {
+            return Ok(Some(serde_json::from_str(&body)?));
+        }
+
+        let response = sse_events(&body)
+            .filter_map(|data| serde_json::from_str::<Value>(&data).ok())
+            .find(|event| event.get("method").is_none() && event["id"] == message["id"]);
+        Ok(response)
+    }
+}
+
+async fn write_line(stdin: &mut ChildStdin, message: &Value) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stdin.write_all(&line).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+/// Answers pings and refuses everything else the client does not offer.
+fn server_request_reply(id: &Value, method: &Value) -> Value {
+    if method == "ping" {
+        json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+    } else {
+        json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32601, "message": "Method not found" } })
+    }
+}
+
+/// The `data` payloads of a server-sent event stream.
+fn sse_events(body: &str) -> impl Iterator<Item = String> + '_ {
+    body.split("\n\n")
+        .map(|event| event.lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+            .collect::<Vec<_>>()
+            .join("\n"))
+        .filter(|data| !data.is_empty())
+}
+
+/// Renders a `tools/call` result as tool message content.
+///
+/// Plain text results are passed through. Structured content and non-text
+/// blocks are returned as JSON next to the text; binary payloads are left
+/// out, since the model cannot read them from a tool message.
+pub fn mcp_tool_output(result: &Value) -> Result<String> {
+    let blocks = result["content"].as_array().cloned().unwrap_or_default();
+
+    let text = blocks.iter()
+        .filter(|block| block["type"] == "text")
+        .filter_map(|block| block["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if result["isError"].as_bool().unwrap_or(false) {
+        return Err(anyhow!(if text.is_empty() { "tool failed".to_string() } else { text }));
+    }
+
+    let attachments = blocks.iter()
+        .filter(|block| block["type"] != "text")
+        .map(|block| match block["type"].as_str() {
+            Some("resource") => json!({
+                "type": "resource",
+                "uri": block["resource"]["uri"],
+                "text": block["resource"]["text"],
+            }),
+            _ => json!({ "type": block["type"], "mimeType": block["mimeType"] }),
+        })
+        .collect::<Vec<_>>();
+
+    let structured = result.get("structuredContent").filter(|v| !v.is_null());
+    if structured.is_none() && attachments.is_empty() {
+        return Ok(text);
+    }
+
+    let mut output = json!({ "text": text });
+    if let Some(structured) = structured {
+        output["structured_content"] = structured.clone();
+    }
+    if !attachments.is_empty() {
+        output["attachments"] = Value::Array(attachments);
+    }
+
+    Ok(output.to_string())
+}
+
+/// The function definition the model sees for an MCP tool.
+pub fn mcp_function_object(server: &McpServerConfig, tool: &McpTool) -> FunctionObject {
+    FunctionObject {
+        name: format!("{}{}", server.tool_prefix.as_deref().unwrap_or(""), tool.name),
+        description: tool.description.clone(),
+        parameters: Some(tool.input_schema.clone()),
+        strict: Some(false),
+    }
+}
+
+/// Routes calls of one function to the server that provides it.
+pub struct McpToolHandler {
+    pub client: Arc<McpClient>,
+    /// Name of the tool on the server, without the prefix.
+    pub tool: String,
+}
+
+#[async_trait::async_trait]
+impl ToolHandler for McpToolHandler {
+    async fn call(&self, arguments: Value) -> Result<String> {
+        self.client.call_tool(&self.tool, arguments).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+
+    /// Serves a streamable HTTP MCP server with an `add` tool on a random port.
+    async fn stand_in() -> String {
+        use axum::{http::HeaderMap, response::IntoResponse, routing::post, Json, Router};
+
+        async fn handle(headers: HeaderMap, Json(message): Json<Value>) -> axum::response::Response {
+            let Some(id) = message.get("id").cloned() else {
+                return axum::http::StatusCode::ACCEPTED.into_response();
+            };
+            let session = headers.get("Mcp-Session-Id").and_then(|v| v.to_str().ok());
+
+            let result = match (message["method"].as_str(), session) {
+                (Some("initialize"), _) => json!({ "protocolVersion": MCP_PROTOCOL_VERSION, "capabilities": { "tools": {} } }),
+                (_, None) => return axum::http::StatusCode::BAD_REQUEST.into_response(),
+                (Some("tools/list"), _) if message["params"]["cursor"].is_null() => json!({
+                    "tools": [{ "name": "add", "description": "Add numbers", "inputSchema": { "type": "object" } }],
+                    "nextCursor": "2",
+                }),
+                (Some("tools/list"), _) => json!({
+                    "tools": [{ "name": "fail", "inputSchema": { "type": "object" } }],
+                }),
+                (Some("tools/call"), _) if message["params"]["name"] == "add" => {
+                    let sum = message["params"]["arguments"]["a"].as_i64().unwrap() + message["params"]["arguments"]["b"].as_i64().unwrap();
+                    json!({ "content": [{ "type": "text", "text": sum.to_string() }], "structuredContent": { "sum": sum } })
+                }
+                _ => json!({ "content": [{ "type": "text", "text": "boom" }], "isError": true }),
+            };
+
+            let response = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+            if message["method"] == "tools/call" {
+                // Answer calls as an event stream, preceded by a notification
+                let body = format!(
+                    "event: message\ndata: {}\n\nevent: message\ndata: {}\n\n",
+                    json!({ "jsonrpc": "2.0", "method": "notifications/progress" }), response
+                );
+                ([("Content-Type", "text/event-stream")], body).into_response()
+            } else {
+                ([("Mcp-Session-Id", "s1")], Json(response)).into_response()
+            }
+        }
+
+        format!("{}/mcp", stand_in::serve(Router::new().route("/mcp", post(handle))).await)
+    }
+
+    #[tokio::test]
+    async fn test_http_server() {
+        let config = McpServerConfig {
+            name: "math".to_string(),
+            transport: McpTransport::Http { url: stand_in().await },
+            tool_prefix: Some("math_".to_string()),
+            requires_approval: false,
+            timeout_secs: 5,
+        };
+        let client = McpClient::connect(&config, &reqwest::Client::new()).await.unwrap();
+
+        let tools = client.list_tools().await.unwrap();
+        assert_eq!(tools.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["add", "fail"]);
+        assert_eq!(mcp_function_object(&config, &tools[0]).name, "math_add");
+
+        let output = client.call_tool("add", json!({ "a": 2, "b": 3 })).await.unwrap();
+        let output = serde_json::from_str::<Value>(&output).unwrap();
+        assert_eq!(output, json!({ "text": "5", "structured_content": { "sum": 5 } }));
+
+        let error = client.call_tool("fail", json!({})).await.unwrap_err();
+        assert_eq!(error.to_string(), "boom");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hung_server_times_out() {
+        let config = McpServerConfig {
+            name: "hung".to_string(),
+            transport: McpTransport::Stdio { command: "sleep".to_string(), args: vec!["10".to_string()], env: Default::default() },
+            tool_prefix: None,
+            requires_approval: false,
+            timeout_secs: 1,
+        };
+
+        let started = std::time::Instant::now();
+        let error = McpClient::connect(&config, &reqwest::Client::new()).await.err().unwrap();
+        assert!(error.to_string().contains("within 1s"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_tool_output() {
+        let text = json!({ "content": [{ "type": "text", "text": "a" }, { "type": "text", "text": "b" }] });
+        assert_eq!(mcp_tool_output(&text).unwrap(), "a\nb");
+
+        let image = json!({ "content": [{ "type": "image", "data": "AAAA", "mimeType": "image/png" }] });
+        let output = serde_json::from_str::<Value>(&mcp_tool_output(&image).unwrap()).unwrap();
+        assert_eq!(output["attachments"][0], json!({ "type": "image", "mimeType": "image/png" }));
+    }
+}
@@ -1,5 +1,35 @@
+mod approval;
+mod attachment;
+mod branch;
+mod context;
+mod failure;
+mod import;
 mod ix;
+mod mcp;
+mod replay;
 mod runtime;
+mod structured;
+mod summary;
+mod tools;
+mod transcript;
+mod usage;
+mod value;
+mod workflow;
 
+pub use approval::*;
+pub use attachment::*;
+pub use branch::*;
+pub use context::*;
+pub use failure::*;
+pub use import::*;
 pub use ix::*;
-pub use runtime::*;
\ No newline at end of file
+pub use mcp::*;
+pub use replay::*;
+pub use runtime::*;
+pub use structured::*;
+pub use summary::*;
+pub use tools::*;
+pub use transcript::*;
+pub use usage::*;
+pub use value::*;
+pub use workflow::*;
@@ -0,0 +1,150 @@
+use waterfall_core::Divergence;
+
+use serde::Serialize;
+
+use super::StateValue;
+
+/// How a journaled turn came out when executed again.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayedTurn {
+    /// Sequence number of the journal entry.
+    pub sequence: usize,
+    pub message: String,
+    /// Why the turn failed this time, if it did.
+    pub error: Option<String>,
+    /// Entries written differently than recorded, usage included.
+    /// Timestamps and generated tool call ids are not compared.
+    pub divergences: Vec<Divergence<StateValue>>,
+}
+
+impl ReplayedTurn {
+    pub fn matches(&self) -> bool {
+        self.error.is_none() && self.divergences.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assistant_message_key, read_totals, read_value, session_usage_key, stand_in, turn_count, user_message_key, LlmInstruction, LlmRuntime, ReplayedTurn};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use waterfall_core::{ConflictPolicy, Dependency, Instruction, Journal, LLMConfig, ProviderConfig, Runtime, Schedule, ScheduleError, SummarizationConfig};
+
+    /// Serves an Ollama model that numbers its answers.
+    async fn numbering_model() -> String {
+        let answers = AtomicUsize::new(0);
+        stand_in::ollama(&["completion", "tools"], move |_| Ok(format!("Answer {}", answers.fetch_add(1, Ordering::SeqCst)))).await
+    }
+
+    async fn session(config: &LLMConfig) -> LlmRuntime {
+        let mut runtime = LlmRuntime::new();
+        runtime.set_quiet(true);
+        runtime.inject_system_config(config).await.unwrap();
+        runtime
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay_and_rerun() {
+        let config = LLMConfig {
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            provider: ProviderConfig::Ollama { base_url: Some(numbering_model().await), tool_calls: Default::default() },
+            ..Default::default()
+        };
+        let mut runtime = session(&config).await;
+        for message in ["hi", "and again"] {
+            runtime.push_instruction(LlmInstruction::parse_from(message.into(), config.id.clone())).unwrap();
+            runtime.execute().await.unwrap();
+        }
+        let journal = Journal::from_jsonl(&runtime.journal().to_jsonl().unwrap()).unwrap();
+        assert_eq!(journal.entries.len(), 2);
+
+        // Replaying reaches the same state without a request
+        let mut replayed = session(&config).await;
+        replayed.replay_journal(&journal).unwrap();
+        assert_eq!(replayed.state.root().unwrap(), runtime.state.root().unwrap());
+        assert_eq!(replayed.journal().entries.len(), 2);
+
+        // Rerunning asks the model again; the replay did not, so its answers go on from 2
+        let mut rerun = session(&config).await;
+        let turns = rerun.rerun_journal(&journal, &config.id).await.unwrap();
+        assert_eq!(turns.iter().map(|turn| turn.message.as_str()).collect::<Vec<_>>(), ["hi", "and again"]);
+        let answer = assistant_message_key(0);
+        let divergence = turns[0].divergences.iter().find(|divergence| divergence.key.as_ref() == Some(&answer)).unwrap();
+        assert_eq!(divergence.recorded.clone().unwrap().into_text(), "Answer 0");
+        assert_eq!(divergence.replayed.clone().unwrap().into_text(), "Answer 2");
+        assert!(turns[1].divergences.iter().any(|divergence| divergence.replayed.clone().map(|value| value.into_text()) == Some("Answer 3".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_identical_rerun_matches() {
+        let config = LLMConfig {
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            provider: ProviderConfig::Ollama {
+                base_url: Some(stand_in::ollama(&["completion", "tools"], |_| Ok("Hello".to_string())).await),
+                tool_calls: Default::default(),
+            },
+            ..Default::default()
+        };
+        let mut runtime = session(&config).await;
+        for message in ["hi", "and again"] {
+            runtime.push_instruction(LlmInstruction::parse_from(message.into(), config.id.clone())).unwrap();
+            runtime.execute().await.unwrap();
+        }
+
+        // The usage of the rerun is recorded later
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let mut rerun = session(&config).await;
+        let turns = rerun.rerun_journal(runtime.journal(), &config.id).await.unwrap();
+        assert_eq!(turns.len(), 2);
+        assert!(turns.iter().all(ReplayedTurn::matches), "{:?}", turns);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_turns_are_journaled() {
+        let config = LLMConfig {
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            provider: ProviderConfig::Ollama { base_url: Some(numbering_model().await), tool_calls: Default::default() },
+            ..Default::default()
+        };
+        let mut runtime = session(&config).await;
+        let mut schedule = Schedule::new();
+        let first = schedule.add(LlmInstruction::parse_from("hi".into(), config.id.clone()));
+        schedule.add_after(LlmInstruction::parse_from("and again".into(), config.id.clone()), vec![Dependency::Step(first)]);
+        assert_eq!(runtime.execute_schedule(&schedule).await.unwrap(), [0, 1]);
+        assert_eq!(read_value::<String>(&runtime.state, &assistant_message_key(1)).unwrap().unwrap(), "Answer 1");
+
+        let mut replayed = session(&config).await;
+        replayed.replay_journal(runtime.journal()).unwrap();
+        assert_eq!(replayed.state.root().unwrap(), runtime.state.root().unwrap());
+
+        // Unordered turns of one conversation take a turn each and add up their usage
+        let mut unordered = Schedule::new();
+        unordered.add(LlmInstruction::parse_from("one".into(), config.id.clone()));
+        unordered.add(LlmInstruction::parse_from("two".into(), config.id.clone()));
+        assert_eq!(runtime.execute_schedule(&unordered).await.unwrap().len(), 2);
+        let mut turns = [2, 3].map(|index| read_value::<String>(&runtime.state, &user_message_key(index)).unwrap().unwrap().clone());
+        turns.sort();
+        assert_eq!(turns, ["one", "two"]);
+        assert_eq!(read_totals(&runtime.state, &session_usage_key()).unwrap().requests, 4);
+
+        // Both turns write the summary of the conversation
+        let summarizing = LLMConfig {
+            summarization: Some(SummarizationConfig { model: "llama3".to_string(), threshold_turns: 1, keep_recent_turns: 0, ..Default::default() }),
+            ..config.clone()
+        };
+        runtime.inject_system_config(&summarizing).await.unwrap();
+        assert!(matches!(runtime.execute_schedule(&unordered).await, Err(ScheduleError::Conflict { .. })));
+
+        // The rerun step takes the turn its dropped diff had, leaving no gap
+        let mut runtime = session(&summarizing).await;
+        for message in ["hi", "and again"] {
+            runtime.push_instruction(LlmInstruction::parse_from(message.into(), summarizing.id.clone())).unwrap();
+            runtime.execute().await.unwrap();
+        }
+        unordered.conflicts = ConflictPolicy::Rerun;
+        assert_eq!(runtime.execute_schedule(&unordered).await.unwrap().len(), 2);
+        assert_eq!(turn_count(&runtime.state), 4);
+    }
+}
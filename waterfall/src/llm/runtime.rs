@@ -1,106 +1,686 @@
-use waterfall_core::{state_key, CryptoHash, Instruction, LLMConfig, Runtime, State, StateDiff};
-use std::env;
+use waterfall_core::{
+    config_key, diverging_writes_by, Author, ErrorPolicy, Journal, RuntimeError, Schedule, ScheduleError, StepId, StepRunner, export_snapshot, import_snapshot, CryptoHash, Instruction, LLMConfig, Runtime, SnapshotOptions, State,
+    StateDiff, StateKey, ToolSettings,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, Result};
 use async_openai::types::{
-    ChatCompletionRequestMessage, ChatCompletionToolArgs, ChatCompletionToolChoiceOption, CompletionUsage, FunctionCall
-};
-use async_openai::{
-    config::OpenAIConfig, types::CreateChatCompletionRequestArgs, Client
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, ChatCompletionRequestToolMessageArgs,
+    ChatCompletionToolArgs, ChatCompletionToolChoiceOption, ChatCompletionToolType, FunctionCall,
 };
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse};
 use colored::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use indicatif::{ProgressBar, ProgressStyle};
 
-use super::LlmInstruction;
+use super::{
+    assistant_message_key, attachment_diff, attachment_tokens, check_budget, failure_key, fork_branch, format_timestamp, list_branches, mcp_function_object,
+    parse_structured_output, pending_approvals_key, read_attachments, read_failure, read_pending_approvals, read_summary,
+    read_tool_calls, read_value, read_value_at, response_format, retry_message, schema_instructions,
+    select_turns, structured_output_key, summary_key, summary_request, summary_system_prompt,
+    switch_branch, tool_call_key, tool_history_messages, totals_diff, turn_count, turns_to_summarize, usage_diff, user_message_content,
+    user_message_key,
+    ApprovalDecision, ApprovalHook, ApprovalRequest, AutoDeny, Branch, BudgetError, ConversationSummary, ExhaustedRetries, ImportedConversation, InstructionFailure,
+    LlmInstruction, LlmTurn,
+    McpClient, McpToolHandler,
+    PendingApproval, ReplayedTurn, StateValue, StructuredOutputError, TokenEstimator, ToolCallRecord, ToolExecutor,
+    ToolHandler, ToolRegistry, ToolResult, Transcript, TranscriptFormat, TurnUsage, UsageTotals, LLM_NAMESPACE,
+};
+use crate::{build_provider, is_transient, LlmProvider};
 
 #[derive(Clone)]
 pub struct LlmRuntime {
-    client: Client<OpenAIConfig>,
-    instructions: Vec<LlmInstruction>,
+    /// Providers by the id of the config that selects them.
+    providers: HashMap<CryptoHash, Arc<dyn LlmProvider>>,
+    /// Shared by the providers and the MCP servers reached over HTTP.
+    http: reqwest::Client,
+    /// Used by the built-in HTTP tools. It does not follow redirects; the
+    /// tools follow them by hand so every hop is checked.
+    #[cfg(feature = "builtin-tools")]
+    tool_http: reqwest::Client,
+    instructions: Vec<Box<dyn QueuedInstruction>>,
+    /// Handlers registered with `register_tool`, for every config.
+    tools: ToolRegistry,
+    /// Handlers of the built-in and MCP tools by the config that enabled
+    /// them, so configs with tools of the same name keep their own.
+    config_tools: HashMap<CryptoHash, ToolRegistry>,
+    approval_hook: Arc<dyn ApprovalHook>,
+    /// Suppresses progress and tool-call output on the terminal.
+    quiet: bool,
+    journal: Journal<StateValue>,
+    /// Turns taken by the steps of the running schedule, so steps running
+    /// at the same time write turns of their own.
+    scheduled_turns: Arc<Mutex<HashSet<usize>>>,
+
+    pub state: State<StateValue>,
+}
+
+/// A queued instruction, with what the runtime needs of its type.
+trait QueuedInstruction: Send + Sync {
+    fn turn(&self) -> &LlmInstruction;
+    fn turn_mut(&mut self) -> &mut LlmInstruction;
+    fn name(&self) -> &'static str;
+    fn fallible(&self) -> bool;
+    fn record(&self, journal: &mut Journal<StateValue>, state_root: CryptoHash, diff: StateDiff<StateValue>);
+    fn boxed(&self) -> Box<dyn QueuedInstruction>;
+}
+
+impl<IX: LlmTurn> QueuedInstruction for IX {
+    fn turn(&self) -> &LlmInstruction {
+        LlmTurn::turn(self)
+    }
+
+    fn turn_mut(&mut self) -> &mut LlmInstruction {
+        LlmTurn::turn_mut(self)
+    }
+
+    fn name(&self) -> &'static str {
+        IX::INSTRUCTION_NAME
+    }
+
+    fn fallible(&self) -> bool {
+        IX::FALLIBLE
+    }
 
-    pub state: State<String>,
+    fn record(&self, journal: &mut Journal<StateValue>, state_root: CryptoHash, diff: StateDiff<StateValue>) {
+        journal.record(self, state_root, diff);
+    }
+
+    fn boxed(&self) -> Box<dyn QueuedInstruction> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn QueuedInstruction> {
+    fn clone(&self) -> Self {
+        self.boxed()
+    }
 }
 
 #[async_trait::async_trait]
-impl Runtime<LlmInstruction, String> for LlmRuntime {
-    fn push_instruction(&mut self, instruction: LlmInstruction) {
+impl<IX: LlmTurn> Runtime<IX, StateValue> for LlmRuntime {
+    fn push_instruction(&mut self, instruction: IX) -> Result<(), RuntimeError> {
         let mut ix = instruction;
-        ix.prepare(&self.state).unwrap();
-        self.instructions.push(ix);
+        ix.prepare(&self.state).map_err(|source| RuntimeError::Prepare {
+            instruction: IX::INSTRUCTION_NAME.to_string(),
+            source: source.into(),
+        })?;
+        self.instructions.push(Box::new(ix));
+        Ok(())
+    }
+
+    async fn execute_one(&mut self, instruction: &IX) -> Result<(), RuntimeError> {
+        self.execute_queued(instruction).await
+    }
+
+    async fn execute(&mut self) -> Result<(), RuntimeError> {
+        LlmRuntime::execute(self).await
+    }
+}
+
+#[async_trait::async_trait]
+impl StepRunner<LlmInstruction, StateValue> for LlmRuntime {
+    /// Runs the step on a copy of the runtime holding `state`, and returns
+    /// what the copy journaled for it. A turn another step took while
+    /// running goes to the next free index instead; a failed step frees
+    /// its turn again.
+    async fn run_step(&self, instruction: LlmInstruction, state: State<StateValue>) -> Result<StateDiff<StateValue>, RuntimeError> {
+        let mut runtime = self.clone();
+        runtime.state = state;
+        runtime.journal = Journal::default();
+        runtime.instructions.clear();
+        runtime.push_instruction(instruction)?;
+        let index = {
+            let mut taken = self.scheduled_turns.lock().unwrap();
+            let turn = runtime.instructions.last_mut().expect("the instruction was just pushed").turn_mut();
+            while !taken.insert(turn.new_message_index) {
+                turn.new_message_index += 1;
+            }
+            turn.new_message_index
+        };
+        if let Err(error) = LlmRuntime::execute(&mut runtime).await {
+            self.scheduled_turns.lock().unwrap().remove(&index);
+            return Err(error);
+        }
+        Ok(runtime.journal.entries.pop().map(|entry| entry.diff).unwrap_or_else(StateDiff::new))
+    }
+
+    /// Frees the turn of the dropped diff, so the step's next run takes it
+    /// again and turns stay gapless.
+    fn discard(&self, _instruction: &LlmInstruction, diff: &StateDiff<StateValue>) {
+        let mut taken = self.scheduled_turns.lock().unwrap();
+        for key in diff.keys.values() {
+            // A turn writes its user message, or its failure when skipped
+            if let Some(index) = key.index().filter(|index| *key == user_message_key(*index) || *key == failure_key(*index)) {
+                taken.remove(&index);
+            }
+        }
+    }
+
+    /// Usage totals add up; any other value written by two steps conflicts.
+    fn merge(&self, base: Option<&StateValue>, ours: &StateValue, theirs: &StateValue) -> Option<StateValue> {
+        let (StateValue::UsageTotals(ours), StateValue::UsageTotals(theirs)) = (ours, theirs) else {
+            return None;
+        };
+        let base = match base {
+            Some(StateValue::UsageTotals(base)) => base.clone(),
+            _ => UsageTotals::default(),
+        };
+        let mut totals = theirs.clone();
+        totals.merge(&ours.since(&base));
+        Some(totals.into())
+    }
+}
+
+impl Default for LlmRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LlmRuntime {
+    /// Providers are set up per config by `inject_system_config`, so API
+    /// keys are only needed for the providers in use.
+    pub fn new() -> Self {
+        Self {
+            providers: HashMap::new(),
+            http: reqwest::Client::new(),
+            #[cfg(feature = "builtin-tools")]
+            tool_http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("HTTP client settings are valid"),
+            instructions: Vec::new(),
+            tools: ToolRegistry::new(),
+            config_tools: HashMap::new(),
+            approval_hook: Arc::new(AutoDeny),
+            quiet: false,
+            journal: Journal::default(),
+            scheduled_turns: Arc::default(),
+            state: State::default(),
+        }
+    }
+
+    /// Lets the runtime execute calls to `name` instead of only displaying them.
+    pub fn register_tool(&mut self, name: impl Into<String>, handler: impl ToolHandler + 'static) {
+        self.tools.insert(name.into(), Arc::new(handler));
+    }
+
+    /// The handlers for calls under config `config_id`: those registered with
+    /// `register_tool`, and those of the tools the config enabled.
+    fn tool_handlers(&self, config_id: &CryptoHash) -> ToolRegistry {
+        let mut handlers = self.tools.clone();
+        if let Some(config_tools) = self.config_tools.get(config_id) {
+            handlers.extend(config_tools.iter().map(|(name, handler)| (name.clone(), handler.clone())));
+        }
+        handlers
     }
 
-    async fn execute_one(&mut self, instruction: &LlmInstruction) -> Result<()> {
-        let spinner = ProgressBar::new_spinner();
+    /// Keeps the runtime off stdout, for hosts that use it as a channel.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Reviews calls to tools marked `requires_approval`. Defaults to `AutoDeny`.
+    pub fn set_approval_hook(&mut self, hook: impl ApprovalHook + 'static) {
+        self.approval_hook = Arc::new(hook);
+    }
+
+    /// Tool calls parked until a reviewer approves or rejects them.
+    pub fn pending_approvals(&self) -> Result<Vec<PendingApproval>> {
+        Ok(read_pending_approvals(&self.state)?.into_values().collect())
+    }
+
+    /// Settles a parked tool call. Approved calls run now; either way the
+    /// outcome replaces the pending result in the turn's tool-call record,
+    /// so the model sees it with the next request.
+    pub async fn resolve_approval(&mut self, tool_call_id: &str, decision: ApprovalDecision) -> Result<ToolResult> {
+        let mut pending_approvals = read_pending_approvals(&self.state)?;
+        let pending = pending_approvals.remove(tool_call_id)
+            .ok_or_else(|| anyhow!("No pending approval for tool call {}", tool_call_id))?;
+
+        let llm_config = self.llm_config(&pending.system_config_hash)?;
+        let result = match decision {
+            ApprovalDecision::Approved => {
+                let handlers = self.tool_handlers(&llm_config.id);
+                let executor = ToolExecutor {
+                    handlers: &handlers,
+                    config: &llm_config,
+                    approval: self.approval_hook.as_ref(),
+                };
+                executor.run(&pending.request.tool, pending.request.arguments.clone()).await
+            }
+            ApprovalDecision::Rejected { reason } => ToolResult::Rejected(reason),
+            ApprovalDecision::Pending => return Err(anyhow!("A resolution must approve or reject the call")),
+        };
+
+        let record_key = tool_call_key(pending.turn);
+        let mut records = read_tool_calls(&self.state, pending.turn)?;
+        for record in records.iter_mut().filter(|record| record.id == tool_call_id) {
+            record.result = result.clone();
+        }
+
+        let mut state_diff = StateDiff::new();
+        state_diff.update_by(Author::Tool, record_key, records.into());
+        state_diff.update(pending_approvals_key(), pending_approvals.into());
+        state_diff.apply(&mut self.state)?;
+
+        Ok(result)
+    }
+
+    /// Runs the queue until it is empty or an instruction stops it.
+    pub async fn execute(&mut self) -> Result<(), RuntimeError> {
+        if self.instructions.is_empty() {
+            return Ok(());
+        }
+
+        if !self.quiet {
+            println!("{}", "Processing requests...".bright_black().italic());
+        }
+        while let Some(instruction) = self.instructions.pop() {
+            match self.execute_queued(instruction.as_ref()).await {
+                // The failure is recorded; the policy lets the queue go on
+                Err(RuntimeError::Failed { .. }) if self.error_policy(instruction.turn()).skip => {}
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn execute_queued(&mut self, instruction: &dyn QueuedInstruction) -> Result<(), RuntimeError> {
+        let state_root = self.state.root().map_err(|e| RuntimeError::State(e.into()))?;
+        let mut applied = StateDiff::new();
+        let result = self.attempt_instruction(instruction, &mut applied).await;
+        // Failed attempts may have written too, e.g. a summary
+        if !applied.is_empty() {
+            instruction.record(&mut self.journal, state_root, applied);
+        }
+        result
+    }
+
+    /// Runs `instruction`, collecting every diff it applies into `applied`.
+    /// Fallible instructions fail as their error policy says; infallible
+    /// ones are not retried and halt the queue on their first failure.
+    async fn attempt_instruction(&mut self, instruction: &dyn QueuedInstruction, applied: &mut StateDiff<StateValue>) -> Result<(), RuntimeError> {
+        let turn = instruction.turn();
+        let llm_config = self.llm_config(&turn.system_config_hash)
+            .map_err(|source| RuntimeError::Prepare {
+                instruction: instruction.name().to_string(),
+                source: source.into(),
+            })?;
+        // Entries record the instruction and turn they were written for
+        let writer = format!("{}[{}]", instruction.name(), turn.new_message_index);
+        let retries = if instruction.fallible() { llm_config.error_policy.retries } else { 0 };
+
+        check_budget(&self.state, &llm_config, None).map_err(|e| RuntimeError::Stopped(e.into()))?;
+        let Err(error) = self.run_turn(turn, retries, &writer, applied).await else {
+            return Ok(());
+        };
+        // The budget ran out between the requests of the turn
+        if error.is::<BudgetError>() {
+            return Err(RuntimeError::Stopped(error.into()));
+        }
+        if !instruction.fallible() {
+            return Err(RuntimeError::Halted { instruction: writer, source: error.into() });
+        }
+
+        let attempts = error.downcast_ref::<ExhaustedRetries>().map_or(1, |e| e.attempts);
+        let mut state_diff = StateDiff::new();
+        state_diff.insert(failure_key(turn.new_message_index), InstructionFailure {
+            instruction: writer.clone(),
+            attempts,
+            error: error.to_string(),
+            skipped: llm_config.error_policy.skip,
+        }.into());
+        state_diff.set_instruction(&writer);
+        state_diff.apply(&mut self.state).map_err(|e| RuntimeError::State(e.into()))?;
+        applied.merge(state_diff);
+        Err(RuntimeError::Failed { instruction: writer, attempts, source: error.into() })
+    }
+
+    /// Runs the turn: summarizes if due, sends the request and applies what
+    /// it wrote.
+    async fn run_turn(&mut self, instruction: &LlmInstruction, retries: usize, writer: &str, applied: &mut StateDiff<StateValue>) -> Result<()> {
+        let spinner = if self.quiet { ProgressBar::hidden() } else { ProgressBar::new_spinner() };
         spinner.set_style(ProgressStyle::default_spinner()
             .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
             .template("{spinner} {msg}").unwrap());
         spinner.set_message("Processing instruction...");
         spinner.enable_steady_tick(std::time::Duration::from_millis(80));
-        
-        let (state_diff, usage) = self.send_request(&instruction).await?;
-        state_diff.apply(&mut self.state);
-        
+
+        let result = async {
+            if let Some(mut summary_diff) = self.summarize(instruction, retries).await? {
+                summary_diff.set_instruction(writer);
+                summary_diff.apply(&mut self.state)?;
+                applied.merge(summary_diff);
+            }
+
+            let mut turn_usage = None;
+            let result = self.send_request(instruction, retries, &mut turn_usage).await;
+            let mut state_diff = match result {
+                Ok(state_diff) => state_diff,
+                Err(e) => {
+                    // What the failed turn spent still counts towards the totals and the budget
+                    if let Some(usage) = turn_usage {
+                        let mut totals_diff = totals_diff(&self.state, &self.llm_config(&instruction.system_config_hash)?, &usage)?;
+                        totals_diff.set_instruction(writer);
+                        totals_diff.apply(&mut self.state)?;
+                        applied.merge(totals_diff);
+                    }
+                    return Err(e);
+                }
+            };
+            // A turn that fails and is skipped leaves its index to the next one
+            if read_failure(&self.state, instruction.new_message_index)?.is_some() {
+                state_diff.delete(&failure_key(instruction.new_message_index));
+            }
+            state_diff.set_instruction(writer);
+            state_diff.apply(&mut self.state)?;
+            applied.merge(state_diff);
+            Ok::<_, anyhow::Error>(turn_usage.expect("at least one request was sent"))
+        }.await;
+
+        let usage = match result {
+            Ok(usage) => usage,
+            Err(e) => {
+                spinner.finish_with_message("❌ Failed".red().to_string());
+                return Err(e);
+            }
+        };
         spinner.finish_with_message("✅ Done".green().to_string());
         
-        // Print usage with fixed formatting
-        println!("   Tokens: prompt={}, completion={}, total={}", 
-            usage.prompt_tokens, 
-            usage.completion_tokens,
-            usage.total_tokens
-        );
+        if !self.quiet {
+            // Print usage with fixed formatting
+            println!("   Tokens: prompt={}, completion={}, total={}", 
+                usage.prompt_tokens, 
+                usage.completion_tokens,
+                usage.total_tokens
+            );
+            if let Some(cost) = usage.cost {
+                println!("   Cost: ${:.4}", cost);
+            }
+        }
 
         Ok(())
     }
 
-    async fn execute(&mut self) -> Result<()> {
-        if self.instructions.is_empty() {
-            return Ok(());
+    fn error_policy(&self, instruction: &LlmInstruction) -> ErrorPolicy {
+        self.llm_config(&instruction.system_config_hash)
+            .map(|config| config.error_policy)
+            .unwrap_or_default()
+    }
+
+    /// Forks the conversation of the active branch at `turn` into a new
+    /// branch that starts with its first `turn` turns.
+    pub fn fork_branch(&mut self, name: &str, turn: usize) -> Result<()> {
+        fork_branch(&mut self.state, name, turn)
+    }
+
+    /// Continues the conversation on branch `name` from now on.
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        switch_branch(&mut self.state, name)
+    }
+
+    pub fn branches(&self) -> Result<Vec<Branch>> {
+        list_branches(&self.state)
+    }
+
+    /// The active branch as a readable transcript, held with the config
+    /// stored under `config_id`.
+    pub fn export_transcript(&self, config_id: &CryptoHash, format: TranscriptFormat) -> Result<String> {
+        Transcript::from_state(&self.state, config_id)?.render(format)
+    }
+
+    /// Writes an imported conversation into a session that has no turns yet.
+    /// Its system prompt, if any, replaces that of the config `config_id`
+    /// stored in the session, so the conversation goes on as it was held.
+    pub fn import_conversation(&mut self, conversation: &ImportedConversation, config_id: &CryptoHash) -> Result<()> {
+        let turns = turn_count(&self.state);
+        if turns > 0 {
+            return Err(anyhow!("Cannot import into a session that already has {} turns", turns));
         }
-        
-        println!("{}", "Processing requests...".bright_black().italic());
-        while let Some(instruction) = self.instructions.pop() {
-            self.execute_one(&instruction).await?;
+
+        let mut llm_config = self.llm_config(config_id)?;
+        conversation.diff.apply(&mut self.state)?;
+        if let Some(system_prompt) = conversation.system_prompt.as_ref().filter(|prompt| **prompt != llm_config.system_prompt) {
+            llm_config.system_prompt = system_prompt.clone();
+            self.state.insert_hash(config_id.clone(), llm_config.into());
         }
+        Ok(())
+    }
+
+    /// Runs the steps of `schedule`, independent ones concurrently, and
+    /// journals them in the order their diffs were applied. Turns running at
+    /// the same time take consecutive indices and do not see each other;
+    /// their usage adds up. Other entries both write, such as the summary,
+    /// conflict. A step rerun by the conflict policy calls its tools again.
+    pub async fn execute_schedule(&mut self, schedule: &Schedule<LlmInstruction>) -> Result<Vec<StepId>, ScheduleError> {
+        let mut runner = self.clone();
+        runner.scheduled_turns = Arc::default();
+        let runner = Arc::new(runner);
+        schedule.run(runner, &mut self.state, &mut self.journal).await
+    }
+
+    /// Runs tool `name` as a call of the model would run it: checked against
+    /// the functions of the config `config_id` and approved if required.
+    pub async fn call_tool(&self, config_id: &CryptoHash, name: &str, arguments: Value) -> Result<ToolResult> {
+        let llm_config = self.llm_config(config_id)?;
+        let handlers = self.tool_handlers(&llm_config.id);
+        let executor = ToolExecutor {
+            handlers: &handlers,
+            config: &llm_config,
+            approval: self.approval_hook.as_ref(),
+        };
+        let call = ChatCompletionMessageToolCall {
+            id: format!("call_{}", name),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall { name: name.to_string(), arguments: arguments.to_string() },
+        };
+        Ok(executor.execute(0, &call).await.result)
+    }
+
+    /// The instructions executed so far, with what they wrote.
+    pub fn journal(&self) -> &Journal<StateValue> {
+        &self.journal
+    }
 
+    /// Rebuilds the session from `journal` without calling a model. The
+    /// session must be where the journal started, e.g. a new runtime with
+    /// the same config injected.
+    pub fn replay_journal(&mut self, journal: &Journal<StateValue>) -> Result<()> {
+        journal.replay(&mut self.state)?;
+        self.journal.extend(journal.clone());
         Ok(())
     }
-}
 
-impl LlmRuntime {
-    pub fn new() -> Self {
-        let config = OpenAIConfig::new()
-            .with_api_base(env::var("OPENAI_BASE_URL").unwrap())
-            .with_api_key(env::var("OPENAI_API_KEY").unwrap());
+    /// Sends the messages of `journal` again under the config `config_id`,
+    /// e.g. one with another model, and compares what each turn wrote with
+    /// what it wrote when it was recorded.
+    pub async fn rerun_journal(&mut self, journal: &Journal<StateValue>, config_id: &CryptoHash) -> Result<Vec<ReplayedTurn>> {
+        let mut turns = Vec::new();
+        for entry in journal.entries.iter().filter(|entry| entry.instruction == LlmInstruction::INSTRUCTION_NAME) {
+            let mut instruction: LlmInstruction = entry.instruction()?;
+            instruction.system_config_hash = config_id.clone();
+            instruction.prepare(&self.state)?;
+            // Attachments are not part of the input, only of what the turn wrote
+            let mut recorded = self.state.clone();
+            entry.diff.apply(&mut recorded)?;
+            instruction.attachments = read_attachments(&recorded, instruction.new_message_index)?;
+
+            let sequence = self.journal.entries.len();
+            let error = match self.execute_one(&instruction).await {
+                Ok(()) => None,
+                Err(error @ RuntimeError::Failed { .. }) => Some(error.to_string()),
+                Err(error) => return Err(error.into()),
+            };
+            let replayed = self.journal.entries.get(sequence).map(|replayed| replayed.diff.clone()).unwrap_or_else(StateDiff::new);
+            turns.push(ReplayedTurn {
+                sequence: entry.sequence,
+                message: instruction.new_message,
+                error,
+                divergences: diverging_writes_by(&entry.diff, &replayed, StateValue::without_volatile_fields)?,
+            });
+        }
+        Ok(turns)
+    }
+
+    /// The whole session, with every branch, as a snapshot.
+    pub fn export_snapshot(&self, options: &SnapshotOptions) -> Result<Vec<u8>> {
+        export_snapshot(&self.state, options)
+    }
+
+    /// Replaces the session with one exported by `export_snapshot`. Tools
+    /// are not part of a snapshot; inject the config again to register them.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8], key: Option<&str>) -> Result<()> {
+        self.state = import_snapshot(snapshot, key)?;
+        Ok(())
+    }
 
-        let client = Client::build(
-            reqwest::Client::new(),
-            config,
-            Default::default()
-        );
+    /// Stores the config after registering its built-in tools and the tools
+    /// of its MCP servers, which are started or reached here.
+    pub async fn inject_system_config(&mut self, system_config: &LLMConfig) -> Result<()> {
+        let mut system_config = system_config.clone();
+        let provider = build_provider(&system_config.provider, &self.http)?;
+        self.providers.insert(system_config.id.clone(), provider);
+        // Injecting a config again replaces the tools it enabled before
+        self.config_tools.remove(&system_config.id);
+        self.register_builtin_tools(&mut system_config)?;
+        self.register_mcp_tools(&mut system_config).await?;
 
-        Self { client, instructions: Vec::new(), state: State::default() }
+        // Configs read from YAML are keyed by name; others keep the id they were given
+        let key = config_key(&system_config.name);
+        if key.hash() == system_config.id {
+            self.state.insert(key, system_config.into())?;
+        } else {
+            self.state.insert_hash(system_config.id.clone(), system_config.into());
+        }
+        Ok(())
     }
 
-    pub fn inject_system_config(&mut self, system_config: &LLMConfig) -> Result<()> {
-        self.state.storage.insert(system_config.id.clone(), serde_json::to_string(system_config)?);
+    /// Lists the tools of every MCP server of the config, adds them to its
+    /// functions and routes their calls to the server.
+    async fn register_mcp_tools(&mut self, system_config: &mut LLMConfig) -> Result<()> {
+        for server in system_config.mcp_servers.clone() {
+            let client = Arc::new(McpClient::connect(&server, &self.http).await?);
+
+            for tool in client.list_tools().await? {
+                let function = mcp_function_object(&server, &tool);
+                if system_config.functions.iter().any(|f| f.name == function.name) || self.tools.contains_key(&function.name) {
+                    return Err(anyhow!("Tool {} of MCP server {} conflicts with another tool", function.name, server.name));
+                }
+
+                if server.requires_approval {
+                    system_config.tool_settings.insert(function.name.clone(), ToolSettings { requires_approval: true });
+                }
+                self.config_tools.entry(system_config.id.clone()).or_default()
+                    .insert(function.name.clone(), Arc::new(McpToolHandler { client: client.clone(), tool: tool.name }));
+                system_config.functions.push(function);
+            }
+        }
+
         Ok(())
     }
 
+    /// Registers the handlers of the built-in tools named by the config and
+    /// adds their function definitions to it.
+    #[cfg(feature = "builtin-tools")]
+    fn register_builtin_tools(&mut self, system_config: &mut LLMConfig) -> Result<()> {
+        for name in system_config.builtin_tools.clone() {
+            let (function, handler) = crate::builtin_tool(&name, system_config, &self.tool_http)?;
+            if !system_config.functions.iter().any(|f| f.name == function.name) {
+                system_config.functions.push(function);
+            }
+            self.config_tools.entry(system_config.id.clone()).or_default().insert(name, handler);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "builtin-tools"))]
+    fn register_builtin_tools(&mut self, system_config: &mut LLMConfig) -> Result<()> {
+        if system_config.builtin_tools.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Built-in tools require the waterfall builtin-tools feature"))
+        }
+    }
+
+    /// The provider selected by `config`, built on first use for configs
+    /// that reached the state without `inject_system_config`.
+    pub fn provider(&self, config: &LLMConfig) -> Result<Arc<dyn LlmProvider>> {
+        match self.providers.get(&config.id) {
+            Some(provider) => Ok(provider.clone()),
+            None => build_provider(&config.provider, &self.http),
+        }
+    }
+
+    fn llm_config(&self, system_config_hash: &CryptoHash) -> Result<LLMConfig> {
+        read_value_at::<LLMConfig>(&self.state, system_config_hash)?
+            .cloned()
+            .ok_or(anyhow!("LLM config not found"))
+    }
+
     fn prepare_messages(&self, ix: &LlmInstruction) -> Result<Vec<ChatCompletionRequestMessage>> {
         let mut messages = Vec::new();
 
-        let system_config = self.state.storage.get(&ix.system_config_hash)
-            .ok_or(anyhow!("LLM config not found"))?;
-        let system_config = serde_json::from_str::<LLMConfig>(&system_config)?;
+        let system_config = self.llm_config(&ix.system_config_hash)?;
 
-        messages.push(ChatCompletionRequestMessage::System(system_config.system_prompt.clone().into()));
+        let system_prompt = match system_config.response_format.as_ref().filter(|f| !f.native) {
+            Some(format) => format!("{}\n\n{}", system_config.system_prompt, schema_instructions(format)),
+            None => system_config.system_prompt.clone(),
+        };
+        messages.push(ChatCompletionRequestMessage::System(system_prompt.clone().into()));
+
+        // Summarized turns stay in storage but are replaced by their summary
+        let summary = read_summary(&self.state)?;
+        let covered_turns = summary.as_ref()
+            .map(|summary| summary.covered_turns.min(ix.memory.len()))
+            .unwrap_or_default();
+        let summary_message = summary.map(|summary| format!(
+            "Summary of the earlier conversation:\n{}", summary.text
+        ));
+        if let Some(summary_message) = &summary_message {
+            messages.push(ChatCompletionRequestMessage::System(summary_message.clone().into()));
+        }
 
-        for (user, assistant, _tool_call) in ix.memory.iter() {
-            messages.push(ChatCompletionRequestMessage::User(user.clone().into()));
+        // Trim the history to the configured window before building the request
+        let estimator = TokenEstimator::for_model(&system_config.openai_model);
+        let reserved_tokens = estimator.estimate_message(&system_prompt)
+            + summary_message.as_deref().map(|m| estimator.estimate_message(m)).unwrap_or_default()
+            + estimator.estimate_message(&ix.new_message)
+            + attachment_tokens(&estimator, &ix.attachments);
+        let turn_attachments = (0..ix.memory.len())
+            .map(|index| read_attachments(&self.state, index))
+            .collect::<Result<Vec<_>>>()?;
+        let turn_tokens = ix.memory.iter().zip(&turn_attachments)
+            .map(|((user, assistant, tool_call), attachments)| {
+                estimator.estimate_message(user)
+                    + attachment_tokens(&estimator, attachments)
+                    + estimator.estimate_message(assistant)
+                    + tool_call.as_ref()
+                        .map(|calls| estimator.estimate(&serde_json::to_string(calls).unwrap_or_default()))
+                        .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        for index in select_turns(&turn_tokens, covered_turns, &system_config.context_window, reserved_tokens) {
+            let (user, assistant, tool_call) = &ix.memory[index];
+            messages.push(ChatCompletionRequestMessage::User(
+                user_message_content(user, &turn_attachments[index])?.into()
+            ));
+            if let Some(tool_call) = tool_call {
+                messages.extend(tool_history_messages(tool_call)?);
+            }
             messages.push(ChatCompletionRequestMessage::Assistant(assistant.clone().into()));
         }
 
-        messages.push(ChatCompletionRequestMessage::User(ix.new_message.clone().into()));
+        messages.push(ChatCompletionRequestMessage::User(
+            user_message_content(&ix.new_message, &ix.attachments)?.into()
+        ));
 
         Ok(messages)
     }
@@ -114,14 +694,21 @@ impl LlmRuntime {
         println!("╠{}╣", border_h.bright_cyan());
         
         // Display conversation history
-        let mut index = 0;
-        let mut has_messages = false;
+        let mut turns = self.state.keys_in(LLM_NAMESPACE)
+            .filter(|key| key.kind == "user_message")
+            .filter_map(StateKey::index)
+            .collect::<Vec<_>>();
+        turns.sort();
+        let mut message_keys = Vec::new();
         
-        while let (Some(user_msg), Some(assistant_msg)) = (
-            self.state.storage.get(&state_key!("user_message", index)),
-            self.state.storage.get(&state_key!("assistant_message", index))
-        ) {
-            has_messages = true;
+        for index in turns {
+            let (Some(user_msg), Some(assistant_msg)) = (
+                read_value::<String>(&self.state, &user_message_key(index))?,
+                read_value::<String>(&self.state, &assistant_message_key(index))?
+            ) else {
+                continue;
+            };
+            message_keys.extend([user_message_key(index).hash(), assistant_message_key(index).hash()]);
             
             // User message heading
             println!("║{:^width$}║", format!("👤 User ({})", index).bright_blue(), width = width - 2);
@@ -149,20 +736,14 @@ impl LlmRuntime {
             
             println!("║ ╰{}╯ ║", "─".repeat(width - 8));
             println!("║{:^width$}║", " ".repeat(width - 2)); // Empty line for spacing
-            
-            index += 1;
         }
         
-        if !has_messages {
+        if message_keys.is_empty() {
             println!("║{:^width$}║", "📝 No conversation history yet".yellow().italic(), width = width - 2);
         }
         
         // Other state entries
-        let message_keys: Vec<CryptoHash> = (0..index).flat_map(|i| {
-            vec![state_key!("user_message", i), state_key!("assistant_message", i)]
-        }).collect();
-        
-        let other_entries: Vec<_> = self.state.storage.iter()
+        let other_entries: Vec<_> = self.state.entries()
             .filter(|(k, _)| !message_keys.contains(k))
             .collect();
         
@@ -170,21 +751,32 @@ impl LlmRuntime {
             println!("╠{}╣", border_h.bright_cyan());
             println!("║{:^width$}║", "📊 System State".bright_yellow(), width = width - 2);
             
-            for (key, value) in other_entries {
+            for (key, entry) in other_entries {
+                let value = entry.value.preview();
                 println!("║ ┌{}┐ ║", "─".repeat(width - 8));
                 
-                let key_str = key.to_string();
-                let display_key = if key_str.len() > 20 {
-                    format!("{}...{}", &key_str[..4], &key_str[key_str.len() - 4..])
+                // Entries written under a typed key are shown by what they are
+                let key_str = self.state.key_of(key).map(ToString::to_string).unwrap_or_else(|| key.to_string());
+                // Agent and workflow names in keys may be any text, so cut on chars
+                let key_chars = key_str.chars().count();
+                let display_key = if key_chars > 54 {
+                    let head = key_str.chars().take(40).collect::<String>();
+                    let tail = key_str.chars().skip(key_chars - 8).collect::<String>();
+                    format!("{}...{}", head, tail)
                 } else {
                     key_str
                 };
                 println!("║ │ {:<54} │ ║", display_key.bright_yellow().bold());
+                if entry.meta.updated_at > 0 {
+                    let written = format!("{:?} · {}", entry.meta.author, format_timestamp(entry.meta.updated_at));
+                    println!("║ │ {:<54} │ ║", written.dimmed());
+                }
                 
                 let display_value = if value.len() > 100 {
-                    format!("{}... [+{} bytes]", &value[..97], value.len() - 100)
+                    let head = value.chars().take(97).collect::<String>();
+                    format!("{}... [+{} bytes]", head, value.len() - head.len())
                 } else {
-                    value.clone()
+                    value
                 };
                 
                 for line in display_value.lines() {
@@ -200,12 +792,75 @@ impl LlmRuntime {
         Ok(())
     }
 
-    pub async fn send_request(&self, ix: &LlmInstruction) -> Result<(
-        StateDiff<String>, CompletionUsage
-    )> {
-        let llm_config = self.state.storage.get(&ix.system_config_hash)
-            .ok_or(anyhow!("LLM config not found"))?;
-        let llm_config = serde_json::from_str::<LLMConfig>(&llm_config)?;
+    /// Folds the oldest turns into the rolling summary once the unsummarized
+    /// history exceeds the configured threshold.
+    pub async fn summarize(&self, ix: &LlmInstruction, retries: usize) -> Result<Option<StateDiff<StateValue>>> {
+        let llm_config = self.llm_config(&ix.system_config_hash)?;
+        let Some(summarization) = &llm_config.summarization else {
+            return Ok(None);
+        };
+
+        let previous = read_summary(&self.state)?;
+        let covered_turns = previous.as_ref().map(|s| s.covered_turns).unwrap_or_default();
+        let Some(turns) = turns_to_summarize(summarization, covered_turns, ix.memory.len()) else {
+            return Ok(None);
+        };
+
+        let messages = vec![
+            ChatCompletionRequestMessage::System(summary_system_prompt(summarization).into()),
+            ChatCompletionRequestMessage::User(
+                summary_request(previous.as_ref(), &ix.memory[turns.clone()]).into()
+            ),
+        ];
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&summarization.model)
+            .messages(messages)
+            .max_tokens(summarization.max_tokens)
+            .build()?;
+
+        check_budget(&self.state, &llm_config, None)?;
+        let response = self.complete(self.provider(&llm_config)?.as_ref(), &llm_config, retries, request).await?;
+
+        let text = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .ok_or_else(|| anyhow!("Summarizer {} returned no summary", summarization.model))?;
+
+        let summary = ConversationSummary { text, covered_turns: turns.end };
+        let mut state_diff = StateDiff::new();
+        state_diff.update_by(Author::Model, summary_key(), summary.into());
+
+        if let Some(usage) = response.usage {
+            let usage = TurnUsage::from_completion(&llm_config, &summarization.model, &usage);
+            state_diff.merge(totals_diff(&self.state, &llm_config, &usage)?);
+        }
+
+        Ok(Some(state_diff))
+    }
+
+    /// Sends `request`, retrying transient provider errors up to `retries`
+    /// times with the delay of the error policy of `llm_config`.
+    async fn complete(&self, provider: &dyn LlmProvider, llm_config: &LLMConfig, retries: usize, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        let policy = &llm_config.error_policy;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match provider.complete(request.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(error) if attempts <= retries && is_transient(&error) => {
+                    tracing::warn!("Request to {} failed, retrying ({} of {}): {}", request.model, attempts, retries, error);
+                    tokio::time::sleep(std::time::Duration::from_millis(policy.retry_delay_ms)).await;
+                }
+                Err(error) => return Err(ExhaustedRetries { attempts, error: error.into() }.into()),
+            }
+        }
+    }
+
+    /// Sends the turn, adding the usage of every request to `turn_usage`,
+    /// also of the requests of a turn that fails.
+    pub async fn send_request(&self, ix: &LlmInstruction, retries: usize, turn_usage: &mut Option<TurnUsage>) -> Result<StateDiff<StateValue>> {
+        let llm_config = self.llm_config(&ix.system_config_hash)?;
 
         let tools = llm_config.functions.iter()
             .map(|function| ChatCompletionToolArgs::default()
@@ -215,61 +870,186 @@ impl LlmRuntime {
             )
             .collect::<Vec<_>>();
 
-        let messages = self.prepare_messages(ix)?;
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&llm_config.openai_model)
-            .messages(messages)
-            .tools(tools)
-            .tool_choice(ChatCompletionToolChoiceOption::Auto)
-            .temperature(llm_config.openai_temperature)
-            .max_tokens(llm_config.openai_max_tokens)
-            .build()?;
+        let provider = self.provider(&llm_config)?;
+        let mut messages = self.prepare_messages(ix)?;
+        let mut tool_calls: Vec<ToolCallRecord> = Vec::new();
+        let mut tool_rounds = 0;
+        let mut attempts = 0;
 
-        //  Send request to OpenAI
-        let response = self.client
-            .chat()
-            .create(request)
-            .await?;
+        let (content, structured_output) = loop {
 
-        let content = response
-            .choices
-            .first()
-            .ok_or_else(|| anyhow!("No response from AI inference server"))?
-            .message
-            .content
-            .clone()
-            .unwrap_or_default();
+            let mut request = CreateChatCompletionRequestArgs::default();
+            request
+                .model(&llm_config.openai_model)
+                .messages(messages.clone())
+                .tools(tools.clone())
+                .tool_choice(ChatCompletionToolChoiceOption::Auto)
+                .temperature(llm_config.openai_temperature)
+                .max_tokens(llm_config.openai_max_tokens);
+            if let Some(format) = llm_config.response_format.as_ref().filter(|f| f.native) {
+                request.response_format(response_format(format));
+            }
+            let request = request.build()?;
 
-        let maybe_function_call = response
-            .choices
-            .first()
-            .ok_or_else(|| anyhow!("No response from AI inference server"))?
-            .message
-            .clone()
-            .tool_calls
-            .unwrap_or_default()
-            .into_iter()
-            .map(|tool_call| tool_call.function)
-            .collect::<Vec<_>>();
+            check_budget(&self.state, &llm_config, turn_usage.as_ref())?;
+            let response = self.complete(provider.as_ref(), &llm_config, retries, request).await?;
 
-        for maybe_function in maybe_function_call.iter() {
-            self.execute_function_call(maybe_function)?;
-        }
+            let message = response
+                .choices
+                .first()
+                .ok_or_else(|| anyhow!("No response from AI inference server"))?
+                .message
+                .clone();
 
-        let usage = response.usage.ok_or(|| {
-            tracing::warn!("Model {} returned no usage", llm_config.openai_model);
-        }).map_err(|_| anyhow!("Model {} returned no usage", llm_config.openai_model))?;
+            let usage = response.usage.ok_or(|| {
+                tracing::warn!("Model {} returned no usage", llm_config.openai_model);
+            }).map_err(|_| anyhow!("Model {} returned no usage", llm_config.openai_model))?;
+            let usage = TurnUsage::from_completion(&llm_config, &llm_config.openai_model, &usage);
+            match turn_usage.as_mut() {
+                Some(turn_usage) => turn_usage.absorb(&usage),
+                None => *turn_usage = Some(usage),
+            }
 
-        let state_diff = self.state_diff_from_response(
+            // Answer every tool call, including rejected ones, and let the model continue
+            let requested_calls = message.tool_calls.unwrap_or_default();
+            if !requested_calls.is_empty() {
+                if tool_rounds == llm_config.tool_execution.max_rounds {
+                    return Err(anyhow!(
+                        "Model {} still calling tools after {} rounds",
+                        llm_config.openai_model,
+                        llm_config.tool_execution.max_rounds
+                    ));
+                }
+                tool_rounds += 1;
+
+                let mut assistant = ChatCompletionRequestAssistantMessageArgs::default();
+                assistant.tool_calls(requested_calls.clone());
+                if let Some(content) = message.content {
+                    assistant.content(content);
+                }
+                messages.push(assistant.build()?.into());
+
+                if !self.quiet {
+                    for call in requested_calls.iter() {
+                        self.print_function_call(&call.function);
+                    }
+                }
+
+                let handlers = self.tool_handlers(&llm_config.id);
+                let executor = ToolExecutor {
+                    handlers: &handlers,
+                    config: &llm_config,
+                    approval: self.approval_hook.as_ref(),
+                };
+                for record in executor.execute_all(tool_rounds, &requested_calls).await {
+                    match &record.result {
+                        _ if self.quiet => {}
+                        ToolResult::Error(error) => {
+                            println!("   {} {}: {}", "Tool call failed".red().bold(), record.name, error);
+                        }
+                        ToolResult::Rejected(reason) => {
+                            println!("   {} {}: {}", "Tool call rejected".red().bold(), record.name, reason);
+                        }
+                        ToolResult::PendingApproval => {
+                            println!("   {} {}", "Tool call awaiting approval:".yellow().bold(), record.name);
+                        }
+                        ToolResult::Ok(_) => {}
+                    }
+                    messages.push(ChatCompletionRequestToolMessageArgs::default()
+                        .tool_call_id(record.id.clone())
+                        .content(record.result.content())
+                        .build()?
+                        .into());
+                    tool_calls.push(record);
+                }
+                continue;
+            }
+
+            let content = message.content.unwrap_or_default();
+            attempts += 1;
+
+            let Some(format) = &llm_config.response_format else {
+                break (content, None);
+            };
+
+            // Re-prompt with the validation errors until the answer conforms
+            match parse_structured_output(format, &content)? {
+                Ok(value) => break (content, Some(value)),
+                Err(errors) if attempts <= format.max_retries => {
+                    tracing::debug!("Structured output rejected: {}", errors.join("; "));
+                    messages.push(ChatCompletionRequestMessage::Assistant(content.into()));
+                    messages.push(ChatCompletionRequestMessage::User(retry_message(&errors).into()));
+                }
+                Err(errors) => return Err(StructuredOutputError::Mismatch {
+                    name: format.name.clone(),
+                    attempts,
+                    errors,
+                }.into()),
+            }
+        };
+
+        let usage = turn_usage.clone().expect("at least one request was sent");
+
+        let mut state_diff = self.state_diff_from_response(
             ix.new_message_index, 
             &ix.new_message, 
             &content
         )?;
+        state_diff.merge(attachment_diff(&self.state, ix.new_message_index, &ix.attachments)?);
+        state_diff.merge(usage_diff(&self.state, &llm_config, ix.new_message_index, &usage)?);
+        // Park calls whose reviewer has not decided yet
+        let mut pending_approvals = read_pending_approvals(&self.state)?;
+        let pending_count = pending_approvals.len();
+        for record in tool_calls.iter().filter(|record| record.result == ToolResult::PendingApproval) {
+            pending_approvals.insert(record.id.clone(), PendingApproval {
+                request: ApprovalRequest {
+                    tool_call_id: record.id.clone(),
+                    tool: record.name.clone(),
+                    arguments: serde_json::from_str(&record.arguments).unwrap_or_default(),
+                },
+                turn: ix.new_message_index,
+                system_config_hash: ix.system_config_hash.clone(),
+            });
+        }
+        if pending_approvals.len() != pending_count {
+            state_diff.update(pending_approvals_key(), pending_approvals.into());
+        }
 
-        Ok((state_diff, usage))
+        if !tool_calls.is_empty() {
+            state_diff.insert_by(Author::Tool, tool_call_key(ix.new_message_index), tool_calls.into());
+        }
+        if let Some(value) = structured_output {
+            state_diff.insert_by(Author::Model, structured_output_key(ix.new_message_index), value.into());
+        }
+
+        Ok(state_diff)
+    }
+
+    /// Runs `instruction` right away and deserializes its structured output.
+    ///
+    /// The agent must have a `response_format` configured.
+    pub async fn execute_typed<T: DeserializeOwned>(&mut self, instruction: LlmInstruction) -> Result<T> {
+        let mut ix = instruction;
+        ix.prepare(&self.state)?;
+        self.execute_one(&ix).await?;
+
+        let value = read_value::<Value>(&self.state, &structured_output_key(ix.new_message_index))?
+            .ok_or_else(|| anyhow!("Instruction produced no structured output"))?;
+        Ok(serde_json::from_value(value.clone())?)
+    }
+
+    /// Runs `prompt` as the next user turn of the agent and returns its answer.
+    pub async fn execute_prompt(&mut self, prompt: &str, system_config_hash: CryptoHash) -> Result<String> {
+        let mut ix = LlmInstruction::parse_from(prompt.into(), system_config_hash);
+        ix.prepare(&self.state)?;
+        self.execute_one(&ix).await?;
+
+        read_value::<String>(&self.state, &assistant_message_key(ix.new_message_index))?
+            .cloned()
+            .ok_or_else(|| anyhow!("Instruction produced no answer"))
     }
 
-    fn execute_function_call(&self, call: &FunctionCall) -> Result<()> {
+    fn print_function_call(&self, call: &FunctionCall) {
         let width = 80;
         let border_h = "═".repeat(width - 2);
         
@@ -287,9 +1067,10 @@ impl LlmRuntime {
         println!("║ 🔠 Arguments:                                             ║");
         println!("║ ┌──────────────────────────────────────────────────┐     ║");
         
-        // Pretty print the JSON arguments
-        let args_value: serde_json::Value = serde_json::from_str(&call.arguments).unwrap_or_default();
-        let pretty_args = serde_json::to_string_pretty(&args_value).unwrap_or_default();
+        // Pretty print the JSON arguments, or show them raw when they do not parse
+        let pretty_args = serde_json::from_str::<serde_json::Value>(&call.arguments)
+            .and_then(|args| serde_json::to_string_pretty(&args))
+            .unwrap_or_else(|_| call.arguments.clone());
         
         for line in pretty_args.lines() {
             println!("║ │ {:<54}│     ║", line.bright_green());
@@ -297,18 +1078,13 @@ impl LlmRuntime {
         
         println!("║ └──────────────────────────────────────────────────┘     ║");
         println!("╚══════════════════════════════════════════════════════════╝");
-        
-        Ok(())
     }
 
-    fn state_diff_from_response(&self, index: usize, request: &str, response: &str) -> Result<StateDiff<String>> {
+    fn state_diff_from_response(&self, index: usize, request: &str, response: &str) -> Result<StateDiff<StateValue>> {
         let mut state_diff = StateDiff::new();
 
-        let user_message_key = state_key!("user_message", index);
-        let assistant_message_key = state_key!("assistant_message", index);
-        
-        state_diff.storage_insert.insert(user_message_key, request.to_string());
-        state_diff.storage_insert.insert(assistant_message_key, response.to_string());
+        state_diff.insert_by(Author::User, user_message_key(index), request.into());
+        state_diff.insert_by(Author::Model, assistant_message_key(index), response.into());
         
         Ok(state_diff)
     }
@@ -0,0 +1,129 @@
+use waterfall_core::{ResponseFormatConfig, StateKey};
+
+use async_openai::types::{ResponseFormat, ResponseFormatJsonSchema};
+use serde_json::Value;
+
+use super::LLM_NAMESPACE;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum StructuredOutputError {
+    #[error("invalid JSON schema {name}: {reason}")]
+    InvalidSchema { name: String, reason: String },
+    #[error("response does not match schema {name} after {attempts} attempts: {}", .errors.join("; "))]
+    Mismatch { name: String, attempts: usize, errors: Vec<String> },
+}
+
+pub fn structured_output_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "structured_output", index)
+}
+
+/// Lists every way `instance` violates `schema`; empty when it is valid.
+///
+/// Fails only when the schema itself cannot be compiled.
+pub fn schema_violations(schema: &Value, instance: &Value) -> Result<Vec<String>, String> {
+    let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+
+    Ok(validator.iter_errors(instance)
+        .map(|error| {
+            let path = error.instance_path().to_string();
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("{} (at {})", error, path)
+            }
+        })
+        .collect())
+}
+
+pub fn response_format(config: &ResponseFormatConfig) -> ResponseFormat {
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: config.description.clone(),
+            name: config.name.clone(),
+            schema: Some(config.schema.clone()),
+            strict: Some(config.strict),
+        },
+    }
+}
+
+/// System prompt addition for providers that cannot enforce the schema.
+pub fn schema_instructions(config: &ResponseFormatConfig) -> String {
+    format!(
+        "Respond only with a JSON value, without any surrounding text, that matches this JSON Schema:\n{}",
+        serde_json::to_string_pretty(&config.schema).unwrap_or_default()
+    )
+}
+
+/// Follow-up prompt asking the model to fix an answer that failed validation.
+pub fn retry_message(errors: &[String]) -> String {
+    format!(
+        "Your previous response did not match the required JSON Schema:\n- {}\nRespond again with corrected JSON only.",
+        errors.join("\n- ")
+    )
+}
+
+/// The JSON inside a Markdown code fence, or the trimmed content without one.
+pub fn strip_code_fence(content: &str) -> &str {
+    let trimmed = content.trim();
+    trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed)
+}
+
+/// Parses the model answer and checks it against the configured schema.
+///
+/// Markdown code fences around the JSON are tolerated, since models add them
+/// even when asked not to.
+pub fn parse_structured_output(config: &ResponseFormatConfig, content: &str) -> Result<Result<Value, Vec<String>>, StructuredOutputError> {
+    let value = match serde_json::from_str::<Value>(strip_code_fence(content)) {
+        Ok(value) => value,
+        Err(e) => return Ok(Err(vec![format!("response is not valid JSON: {}", e)])),
+    };
+
+    let violations = schema_violations(&config.schema, &value)
+        .map_err(|reason| StructuredOutputError::InvalidSchema { name: config.name.clone(), reason })?;
+
+    Ok(if violations.is_empty() { Ok(value) } else { Err(violations) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> ResponseFormatConfig {
+        ResponseFormatConfig {
+            name: "answer".to_string(),
+            schema: json!({
+                "type": "object",
+                "properties": {
+                    "city": { "type": "string" },
+                    "population": { "type": "integer" }
+                },
+                "required": ["city", "population"],
+                "additionalProperties": false
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_valid_output() {
+        let value = parse_structured_output(&config(), "```json\n{\"city\": \"Oslo\", \"population\": 709000}\n```")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value["city"], "Oslo");
+    }
+
+    #[test]
+    fn test_invalid_output() {
+        let errors = parse_structured_output(&config(), "{\"city\": 3}").unwrap().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|e| e.contains("population")));
+
+        let errors = parse_structured_output(&config(), "Oslo").unwrap().unwrap_err();
+        assert!(errors[0].starts_with("response is not valid JSON"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::ops::Range;
+
+use waterfall_core::{State, StateKey, SummarizationConfig};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{read_value, StateValue, ToolCallRecord, LLM_NAMESPACE};
+
+const DEFAULT_SUMMARY_PROMPT: &str = "You maintain a running summary of a conversation between a user and an assistant. \
+Merge the previous summary with the new turns into one concise summary. Keep facts, decisions, \
+user preferences, tool results and open questions; drop small talk. Reply with the summary only.";
+
+/// Rolling summary of the oldest turns of the conversation.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ConversationSummary {
+    pub text: String,
+    /// Turns `0..covered_turns` are folded into `text` and no longer sent.
+    pub covered_turns: usize,
+}
+
+pub fn summary_key() -> StateKey {
+    StateKey::single(LLM_NAMESPACE, "conversation_summary")
+}
+
+pub fn read_summary(state: &State<StateValue>) -> Result<Option<ConversationSummary>> {
+    Ok(read_value::<ConversationSummary>(state, &summary_key())?.cloned())
+}
+
+/// Returns the turns that should be folded into the summary next, if the
+/// unsummarized history has grown past the threshold.
+pub fn turns_to_summarize(config: &SummarizationConfig, covered_turns: usize, turn_count: usize) -> Option<Range<usize>> {
+    let pending = turn_count.saturating_sub(covered_turns);
+    if pending <= config.threshold_turns {
+        return None;
+    }
+
+    // Keeping more recent turns than the threshold leaves nothing to fold in yet
+    let end = turn_count.saturating_sub(config.keep_recent_turns).max(covered_turns);
+    (end > covered_turns).then_some(covered_turns..end)
+}
+
+pub fn summary_system_prompt(config: &SummarizationConfig) -> String {
+    config.prompt.clone().unwrap_or_else(|| DEFAULT_SUMMARY_PROMPT.to_string())
+}
+
+/// Renders the previous summary and the turns to fold in as a single user message.
+pub fn summary_request(previous: Option<&ConversationSummary>, turns: &[(String, String, Option<Vec<ToolCallRecord>>)]) -> String {
+    let mut request = String::new();
+
+    if let Some(previous) = previous {
+        request.push_str("Previous summary:\n");
+        request.push_str(&previous.text);
+        request.push_str("\n\n");
+    }
+
+    request.push_str("New turns:\n");
+    for (user, assistant, _tool_call) in turns {
+        request.push_str(&format!("User: {}\nAssistant: {}\n", user, assistant));
+    }
+
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turns_to_summarize() {
+        let config = SummarizationConfig {
+            threshold_turns: 6,
+            keep_recent_turns: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(turns_to_summarize(&config, 0, 6), None);
+        assert_eq!(turns_to_summarize(&config, 0, 7), Some(0..5));
+        assert_eq!(turns_to_summarize(&config, 5, 11), None);
+        assert_eq!(turns_to_summarize(&config, 5, 12), Some(5..10));
+
+        // More turns kept than the threshold lets through
+        let config = SummarizationConfig { threshold_turns: 1, keep_recent_turns: 4, ..config };
+        assert_eq!(turns_to_summarize(&config, 0, 2), None);
+        assert_eq!(turns_to_summarize(&config, 3, 6), None);
+        assert_eq!(turns_to_summarize(&config, 0, 6), Some(0..2));
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use waterfall_core::{LLMConfig, State, StateKey};
+
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionToolType, FunctionCall, FunctionObject,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{read_value, schema_violations, ApprovalDecision, ApprovalHook, ApprovalRequest, StateValue, LLM_NAMESPACE};
+
+/// Executes a tool the model is allowed to call.
+///
+/// Tools declared in the config without a registered handler are only
+/// displayed, and the model is told the call was dispatched.
+#[async_trait::async_trait]
+pub trait ToolHandler: Send + Sync {
+    async fn call(&self, arguments: Value) -> Result<String>;
+}
+
+/// Handlers by tool name.
+pub type ToolRegistry = HashMap<String, Arc<dyn ToolHandler>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "status", content = "content", rename_all = "snake_case")]
+pub enum ToolResult {
+    Ok(String),
+    Error(String),
+    /// A reviewer refused the call; holds their reason.
+    Rejected(String),
+    /// The call is parked until a reviewer decides.
+    PendingApproval,
+}
+
+impl ToolResult {
+    /// What the model receives as the tool message.
+    pub fn content(&self) -> String {
+        match self {
+            ToolResult::Ok(content) => content.clone(),
+            ToolResult::Error(error) => json!({ "error": error }).to_string(),
+            ToolResult::Rejected(reason) => json!({ "rejected": reason }).to_string(),
+            ToolResult::PendingApproval => json!({
+                "status": "pending_approval",
+                "message": "The call is waiting for human approval; its result will be provided later."
+            }).to_string(),
+        }
+    }
+}
+
+/// One tool call made during a turn, stored under `tool_call:<index>`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolCallRecord {
+    pub id: String,
+    /// Calls requested by the same model response share a round.
+    pub round: usize,
+    pub name: String,
+    pub arguments: String,
+    pub result: ToolResult,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ToolArgumentError {
+    #[error("unknown tool {0}")]
+    UnknownTool(String),
+    #[error("arguments are not valid JSON: {0}")]
+    InvalidJson(String),
+    #[error("tool {name} has an invalid parameter schema: {reason}")]
+    InvalidSchema { name: String, reason: String },
+    #[error("arguments do not match the parameters of {name}: {}", .violations.join("; "))]
+    SchemaMismatch { name: String, violations: Vec<String> },
+}
+
+pub fn tool_call_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "tool_call", index)
+}
+
+pub fn read_tool_calls(state: &State<StateValue>, index: usize) -> Result<Vec<ToolCallRecord>> {
+    Ok(read_value::<Vec<ToolCallRecord>>(state, &tool_call_key(index))?.cloned().unwrap_or_default())
+}
+
+/// Parses the arguments of `call` and checks them against the parameters of
+/// the matching function. Strict functions additionally reject properties
+/// their schema does not declare.
+pub fn validate_tool_arguments(functions: &[FunctionObject], call: &FunctionCall) -> Result<Value, ToolArgumentError> {
+    let function = functions.iter()
+        .find(|function| function.name == call.name)
+        .ok_or_else(|| ToolArgumentError::UnknownTool(call.name.clone()))?;
+
+    // Some providers send an empty string for calls without arguments
+    let arguments = if call.arguments.trim().is_empty() { "{}" } else { &call.arguments };
+    let arguments = serde_json::from_str::<Value>(arguments)
+        .map_err(|e| ToolArgumentError::InvalidJson(e.to_string()))?;
+
+    let Some(schema) = &function.parameters else {
+        return Ok(arguments);
+    };
+    let schema = if function.strict.unwrap_or(false) { strict_schema(schema) } else { schema.clone() };
+
+    let violations = schema_violations(&schema, &arguments)
+        .map_err(|reason| ToolArgumentError::InvalidSchema { name: function.name.clone(), reason })?;
+
+    if violations.is_empty() {
+        Ok(arguments)
+    } else {
+        Err(ToolArgumentError::SchemaMismatch { name: function.name.clone(), violations })
+    }
+}
+
+/// Closes every object schema that does not say otherwise, matching the
+/// semantics of strict function calling. Only subschemas are visited, so
+/// parameters that happen to be named like keywords are left alone.
+fn strict_schema(schema: &Value) -> Value {
+    let Value::Object(map) = schema else {
+        return schema.clone();
+    };
+    let mut map = map.clone();
+
+    let is_object = match map.get("type") {
+        Some(Value::String(kind)) => kind == "object",
+        Some(Value::Array(kinds)) => kinds.iter().any(|kind| kind == "object"),
+        _ => false,
+    };
+    if is_object {
+        if let Some(Value::Object(properties)) = map.get_mut("properties") {
+            for property in properties.values_mut() {
+                *property = strict_schema(property);
+            }
+            if !map.contains_key("additionalProperties") {
+                map.insert("additionalProperties".to_string(), Value::Bool(false));
+            }
+        }
+    }
+
+    for keyword in ["items", "anyOf", "oneOf", "allOf"] {
+        match map.get_mut(keyword) {
+            Some(Value::Array(schemas)) => schemas.iter_mut().for_each(|schema| *schema = strict_schema(schema)),
+            Some(schema @ Value::Object(_)) => *schema = strict_schema(schema),
+            _ => {}
+        }
+    }
+    for keyword in ["$defs", "definitions"] {
+        if let Some(Value::Object(definitions)) = map.get_mut(keyword) {
+            definitions.values_mut().for_each(|schema| *schema = strict_schema(schema));
+        }
+    }
+
+    Value::Object(map)
+}
+
+/// Runs the tool calls of one model response.
+pub struct ToolExecutor<'a> {
+    pub handlers: &'a ToolRegistry,
+    pub config: &'a LLMConfig,
+    pub approval: &'a dyn ApprovalHook,
+}
+
+impl ToolExecutor<'_> {
+    /// Executes `calls` concurrently, at most `max_concurrency` at a time,
+    /// and returns their records in the order the model requested them.
+    pub async fn execute_all(&self, round: usize, calls: &[ChatCompletionMessageToolCall]) -> Vec<ToolCallRecord> {
+        // Futures are created up front; `buffered` only polls a window of them
+        let calls = calls.iter()
+            .map(|call| self.execute(round, call))
+            .collect::<Vec<_>>();
+
+        stream::iter(calls)
+            .buffered(self.config.tool_execution.max_concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Validates the arguments of `call`, asks for approval when the tool
+    /// requires it and runs its handler, turning every failure into a tool
+    /// error the model can react to.
+    pub async fn execute(&self, round: usize, call: &ChatCompletionMessageToolCall) -> ToolCallRecord {
+        ToolCallRecord {
+            id: call.id.clone(),
+            round,
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+            result: self.result(call).await,
+        }
+    }
+
+    async fn result(&self, call: &ChatCompletionMessageToolCall) -> ToolResult {
+        let arguments = match validate_tool_arguments(&self.config.functions, &call.function) {
+            Ok(arguments) => arguments,
+            Err(e) => return ToolResult::Error(e.to_string()),
+        };
+
+        let requires_approval = self.config.tool_settings.get(&call.function.name)
+            .map(|settings| settings.requires_approval)
+            .unwrap_or(false);
+
+        if requires_approval {
+            let request = ApprovalRequest {
+                tool_call_id: call.id.clone(),
+                tool: call.function.name.clone(),
+                arguments: arguments.clone(),
+            };
+
+            match self.approval.review(&request).await {
+                Ok(ApprovalDecision::Approved) => {}
+                Ok(ApprovalDecision::Rejected { reason }) => return ToolResult::Rejected(reason),
+                Ok(ApprovalDecision::Pending) => return ToolResult::PendingApproval,
+                Err(e) => return ToolResult::Error(format!("approval failed: {}", e)),
+            }
+        }
+
+        self.run(&call.function.name, arguments).await
+    }
+
+    /// Runs the handler of an already validated and approved call.
+    pub async fn run(&self, name: &str, arguments: Value) -> ToolResult {
+        let Some(handler) = self.handlers.get(name) else {
+            return ToolResult::Ok(json!({ "status": "dispatched" }).to_string());
+        };
+
+        let timeout_secs = self.config.tool_execution.timeout_secs;
+        match tokio::time::timeout(Duration::from_secs(timeout_secs), handler.call(arguments)).await {
+            Ok(Ok(output)) => ToolResult::Ok(output),
+            Ok(Err(e)) => ToolResult::Error(e.to_string()),
+            Err(_) => ToolResult::Error(format!("timed out after {}s", timeout_secs)),
+        }
+    }
+}
+
+/// Rebuilds the assistant tool-call and tool-result messages of a past turn.
+pub fn tool_history_messages(records: &[ToolCallRecord]) -> Result<Vec<ChatCompletionRequestMessage>> {
+    let mut messages = Vec::new();
+
+    let mut rounds = records.iter().map(|record| record.round).collect::<Vec<_>>();
+    rounds.dedup();
+
+    for round in rounds {
+        let calls = records.iter().filter(|record| record.round == round);
+
+        let tool_calls = calls.clone()
+            .map(|record| ChatCompletionMessageToolCall {
+                id: record.id.clone(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: record.name.clone(),
+                    arguments: record.arguments.clone(),
+                },
+            })
+            .collect::<Vec<_>>();
+
+        messages.push(ChatCompletionRequestAssistantMessageArgs::default()
+            .tool_calls(tool_calls)
+            .build()?
+            .into());
+
+        for record in calls {
+            messages.push(ChatCompletionRequestToolMessageArgs::default()
+                .tool_call_id(record.id.clone())
+                .content(record.result.content())
+                .build()?
+                .into());
+        }
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AutoApprove, AutoDeny};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use waterfall_core::{ToolExecutionConfig, ToolSettings};
+
+    fn functions() -> Vec<FunctionObject> {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "url": { "type": "string" }
+            },
+            "required": ["url"]
+        });
+
+        vec![
+            FunctionObject {
+                name: "open_tab".to_string(),
+                description: None,
+                parameters: Some(parameters.clone()),
+                strict: Some(true),
+            },
+            FunctionObject {
+                name: "open_tab_loose".to_string(),
+                description: None,
+                parameters: Some(parameters),
+                strict: Some(false),
+            },
+        ]
+    }
+
+    fn call(name: &str, arguments: &str) -> FunctionCall {
+        FunctionCall { name: name.to_string(), arguments: arguments.to_string() }
+    }
+
+    #[test]
+    fn test_valid_arguments() {
+        let arguments = validate_tool_arguments(&functions(), &call("open_tab", r#"{"url": "https://a.b"}"#)).unwrap();
+        assert_eq!(arguments["url"], "https://a.b");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let functions = functions();
+
+        assert_eq!(
+            validate_tool_arguments(&functions, &call("close_tab", "{}")),
+            Err(ToolArgumentError::UnknownTool("close_tab".to_string()))
+        );
+        assert!(matches!(
+            validate_tool_arguments(&functions, &call("open_tab", "{\"url\": ")),
+            Err(ToolArgumentError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            validate_tool_arguments(&functions, &call("open_tab", "")),
+            Err(ToolArgumentError::SchemaMismatch { .. })
+        ));
+    }
+
+    struct Sleep {
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl ToolHandler for Sleep {
+        async fn call(&self, arguments: Value) -> Result<String> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+
+            let millis = arguments["millis"].as_u64().unwrap_or_default();
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(millis.to_string())
+        }
+    }
+
+    fn sleep_call(id: &str, millis: u64) -> ChatCompletionMessageToolCall {
+        ChatCompletionMessageToolCall {
+            id: id.to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: call("sleep", &json!({ "millis": millis }).to_string()),
+        }
+    }
+
+    fn sleep_function() -> FunctionObject {
+        FunctionObject {
+            name: "sleep".to_string(),
+            description: None,
+            parameters: None,
+            strict: None,
+        }
+    }
+
+    fn sleep_registry() -> ToolRegistry {
+        let mut handlers = ToolRegistry::new();
+        handlers.insert("sleep".to_string(), Arc::new(Sleep {
+            running: Arc::new(AtomicUsize::new(0)),
+            peak: Arc::new(AtomicUsize::new(0)),
+        }));
+        handlers
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_execution_keeps_order() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut handlers = ToolRegistry::new();
+        handlers.insert("sleep".to_string(), Arc::new(Sleep {
+            running: Arc::new(AtomicUsize::new(0)),
+            peak: peak.clone(),
+        }));
+
+        let config = LLMConfig {
+            functions: vec![sleep_function()],
+            tool_execution: ToolExecutionConfig { max_concurrency: 2, timeout_secs: 1, max_rounds: 1 },
+            ..Default::default()
+        };
+        let executor = ToolExecutor { handlers: &handlers, config: &config, approval: &AutoDeny };
+
+        let calls = [sleep_call("a", 60), sleep_call("b", 10), sleep_call("c", 30), sleep_call("d", 1500)];
+        let records = executor.execute_all(1, &calls).await;
+
+        let ids = records.iter().map(|r| r.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b", "c", "d"]);
+        assert_eq!(records[1].result, ToolResult::Ok("10".to_string()));
+        assert_eq!(records[3].result, ToolResult::Error("timed out after 1s".to_string()));
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_strict_rejects_extra_properties() {
+        let functions = functions();
+        let arguments = r#"{"url": "https://a.b", "incognito": true}"#;
+
+        assert!(validate_tool_arguments(&functions, &call("open_tab", arguments)).is_err());
+        assert!(validate_tool_arguments(&functions, &call("open_tab_loose", arguments)).is_ok());
+    }
+
+    #[test]
+    fn test_strict_keeps_parameters_named_like_keywords() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "properties": { "type": "object", "properties": { "color": { "type": "string" } } },
+                "tags": { "type": "array", "items": { "type": "object", "properties": { "name": { "type": "string" } } } }
+            }
+        });
+        let strict = strict_schema(&schema);
+
+        assert_eq!(strict["additionalProperties"], false);
+        assert_eq!(strict["properties"]["properties"]["additionalProperties"], false);
+        assert!(strict["properties"].get("additionalProperties").is_none());
+        assert_eq!(strict["properties"]["tags"]["items"]["additionalProperties"], false);
+    }
+
+    struct Defer;
+
+    #[async_trait::async_trait]
+    impl ApprovalHook for Defer {
+        async fn review(&self, _request: &ApprovalRequest) -> Result<ApprovalDecision> {
+            Ok(ApprovalDecision::Pending)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_approval_gate() {
+        let handlers = sleep_registry();
+        let mut config = LLMConfig {
+            functions: vec![sleep_function()],
+            ..Default::default()
+        };
+        config.tool_settings.insert("sleep".to_string(), ToolSettings { requires_approval: true });
+        let call = sleep_call("a", 1);
+
+        let denied = ToolExecutor { handlers: &handlers, config: &config, approval: &AutoDeny };
+        assert_eq!(denied.execute(1, &call).await.result, ToolResult::Rejected("denied by policy".to_string()));
+
+        let approved = ToolExecutor { handlers: &handlers, config: &config, approval: &AutoApprove };
+        assert_eq!(approved.execute(1, &call).await.result, ToolResult::Ok("1".to_string()));
+
+        let deferred = ToolExecutor { handlers: &handlers, config: &config, approval: &Defer };
+        assert_eq!(deferred.execute(1, &call).await.result, ToolResult::PendingApproval);
+    }
+}
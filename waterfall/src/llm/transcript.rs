@@ -0,0 +1,429 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use waterfall_core::{CryptoHash, Instruction, LLMConfig, State};
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{ChatCompletionRequestMessage, FunctionObject};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{
+    assistant_message_key, read_attachments, read_branch_info, read_totals, read_value, read_value_at, session_usage_key,
+    tool_history_messages, turn_usage_key, user_message_content, Attachment, AttachmentKind, LlmInstruction, StateValue,
+    ToolCallRecord, ToolResult, TurnUsage, UsageTotals,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    Markdown,
+    /// A single page with its styles and images inlined.
+    Html,
+    /// One OpenAI fine-tuning example per turn: the conversation up to and
+    /// including the turn under `messages`, with the agent's `tools` once it
+    /// holds tool calls. `metadata` adds the usage and timestamp of the
+    /// turn, which makes the lines unfit for uploading.
+    Jsonl { metadata: bool },
+}
+
+impl FromStr for TranscriptFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "md" | "markdown" => Ok(TranscriptFormat::Markdown),
+            "html" | "htm" => Ok(TranscriptFormat::Html),
+            "jsonl" => Ok(TranscriptFormat::Jsonl { metadata: false }),
+            _ => Err(anyhow!("Unknown transcript format {}; expected md, html or jsonl", s)),
+        }
+    }
+}
+
+/// One completed turn, with its attachments loaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptTurn {
+    pub index: usize,
+    pub user: String,
+    pub attachments: Vec<Attachment>,
+    pub tool_calls: Vec<ToolCallRecord>,
+    pub assistant: String,
+    pub usage: Option<TurnUsage>,
+    /// When the answer was written, as a Unix timestamp in milliseconds.
+    pub answered_at: Option<u64>,
+}
+
+/// The active branch of a session in the order it was held.
+#[derive(Debug, Clone, Serialize)]
+pub struct Transcript {
+    pub branch: String,
+    pub agent: String,
+    pub system_prompt: String,
+    /// The functions the agent could call.
+    pub tools: Vec<FunctionObject>,
+    pub turns: Vec<TranscriptTurn>,
+    pub totals: UsageTotals,
+}
+
+impl Transcript {
+    /// Reads the turns of the active branch, as held with the config stored
+    /// under `config_id`.
+    pub fn from_state(state: &State<StateValue>, config_id: &CryptoHash) -> Result<Self> {
+        let config = read_value_at::<LLMConfig>(state, config_id)?
+            .ok_or_else(|| anyhow!("Config {} not found in state", config_id))?;
+
+        let mut history = LlmInstruction::default();
+        history.prepare(state)?;
+        let turns = history.memory.into_iter().enumerate()
+            .map(|(index, (user, assistant, tool_calls))| Ok(TranscriptTurn {
+                index,
+                user,
+                attachments: read_attachments(state, index)?,
+                tool_calls: tool_calls.unwrap_or_default(),
+                assistant,
+                usage: read_value::<TurnUsage>(state, &turn_usage_key(index))?.cloned(),
+                answered_at: state.meta(&assistant_message_key(index))
+                    .map(|meta| meta.updated_at)
+                    .filter(|updated_at| *updated_at > 0),
+            }))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            branch: read_branch_info(state)?.name,
+            agent: config.name.clone(),
+            system_prompt: config.system_prompt.clone(),
+            tools: config.functions.clone(),
+            turns,
+            totals: read_totals(state, &session_usage_key())?,
+        })
+    }
+
+    pub fn render(&self, format: TranscriptFormat) -> Result<String> {
+        match format {
+            TranscriptFormat::Markdown => Ok(self.to_markdown()),
+            TranscriptFormat::Html => Ok(self.to_html()),
+            TranscriptFormat::Jsonl { metadata } => self.to_jsonl(metadata),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# Transcript: {} ({} branch)\n", self.agent, self.branch);
+        let _ = writeln!(out, "## System prompt\n\n{}", fenced("text", &self.system_prompt));
+
+        for turn in &self.turns {
+            let _ = writeln!(out, "## Turn {}{}\n", turn.index, turn_time(turn).map(|time| format!(" · {}", time)).unwrap_or_default());
+            let _ = writeln!(out, "### User\n\n{}\n", turn.user);
+            for attachment in &turn.attachments {
+                let _ = writeln!(out, "- Attached {}", attachment_label(attachment));
+            }
+            if !turn.attachments.is_empty() {
+                out.push('\n');
+            }
+
+            for call in &turn.tool_calls {
+                let _ = writeln!(out, "### Tool call `{}` ({}, round {})\n", call.name, call.id, call.round);
+                let _ = writeln!(out, "Arguments:\n\n{}", fenced("json", &pretty_arguments(&call.arguments)));
+                let _ = writeln!(out, "Result ({}):\n\n{}", result_status(&call.result), fenced("text", &call.result.content()));
+            }
+
+            let _ = writeln!(out, "### Assistant\n\n{}\n", turn.assistant);
+            if let Some(usage) = &turn.usage {
+                let _ = writeln!(out, "_{}_\n", usage_line(usage));
+            }
+        }
+
+        let _ = writeln!(out, "## Totals\n\n{}", totals_line(&self.totals));
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut body = String::new();
+        let _ = writeln!(body, "<h1>Transcript: {} <small>({} branch)</small></h1>", escape_html(&self.agent), escape_html(&self.branch));
+        let _ = writeln!(body, "<section class=\"system\"><h2>System prompt</h2><pre>{}</pre></section>", escape_html(&self.system_prompt));
+
+        for turn in &self.turns {
+            let _ = writeln!(body, "<section class=\"turn\">");
+            let _ = writeln!(
+                body,
+                "<h2>Turn {}{}</h2>",
+                turn.index,
+                turn_time(turn).map(|time| format!(" <time>{}</time>", time)).unwrap_or_default(),
+            );
+            let _ = writeln!(body, "<div class=\"user\"><h3>User</h3><pre>{}</pre>", escape_html(&turn.user));
+            for attachment in &turn.attachments {
+                match (attachment.kind, &attachment.data) {
+                    (AttachmentKind::Image, Some(data)) => {
+                        let _ = writeln!(
+                            body,
+                            "<figure><img src=\"data:{};base64,{}\" alt=\"{}\"><figcaption>{}</figcaption></figure>",
+                            escape_html(&attachment.media_type),
+                            data,
+                            escape_html(attachment.name.as_deref().unwrap_or("image")),
+                            escape_html(&attachment_label(attachment)),
+                        );
+                    }
+                    _ => {
+                        let _ = writeln!(body, "<p class=\"attachment\">Attached {}</p>", escape_html(&attachment_label(attachment)));
+                    }
+                }
+            }
+            let _ = writeln!(body, "</div>");
+
+            for call in &turn.tool_calls {
+                let _ = writeln!(
+                    body,
+                    "<details class=\"tool\" open><summary>Tool call <code>{}</code> ({}, round {}) · {}</summary>\
+                     <h4>Arguments</h4><pre>{}</pre><h4>Result</h4><pre>{}</pre></details>",
+                    escape_html(&call.name),
+                    escape_html(&call.id),
+                    call.round,
+                    result_status(&call.result),
+                    escape_html(&pretty_arguments(&call.arguments)),
+                    escape_html(&call.result.content()),
+                );
+            }
+
+            let _ = writeln!(body, "<div class=\"assistant\"><h3>Assistant</h3><pre>{}</pre></div>", escape_html(&turn.assistant));
+            if let Some(usage) = &turn.usage {
+                let _ = writeln!(body, "<p class=\"usage\">{}</p>", escape_html(&usage_line(usage)));
+            }
+            let _ = writeln!(body, "</section>");
+        }
+        let _ = writeln!(body, "<footer>{}</footer>", escape_html(&totals_line(&self.totals)));
+
+        format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>Transcript: {}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+            escape_html(&self.agent),
+            HTML_STYLE,
+            body,
+        )
+    }
+
+    pub fn to_jsonl(&self, metadata: bool) -> Result<String> {
+        let mut messages = vec![ChatCompletionRequestMessage::System(self.system_prompt.clone().into())];
+        let tools = self.tools.iter()
+            .map(|function| json!({ "type": "function", "function": function }))
+            .collect::<Vec<_>>();
+        let mut has_tool_calls = false;
+        let mut out = String::new();
+
+        for turn in &self.turns {
+            messages.push(ChatCompletionRequestMessage::User(user_message_content(&turn.user, &turn.attachments)?.into()));
+            messages.extend(tool_history_messages(&turn.tool_calls)?);
+            messages.push(ChatCompletionRequestMessage::Assistant(turn.assistant.clone().into()));
+            has_tool_calls |= !turn.tool_calls.is_empty();
+
+            let mut line = json!({ "messages": messages });
+            if has_tool_calls {
+                line["tools"] = json!(tools);
+            }
+            if metadata {
+                line["metadata"] = json!({
+                    "agent": self.agent,
+                    "branch": self.branch,
+                    "turn": turn.index,
+                    "recorded_at": turn_time(turn),
+                    "usage": turn.usage,
+                });
+            }
+            out.push_str(&serde_json::to_string(&line)?);
+            out.push('\n');
+        }
+
+        Ok(out)
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:52rem;margin:2rem auto;padding:0 1rem;color:#1f2328}\
+pre{white-space:pre-wrap;word-break:break-word;background:#f6f8fa;padding:.75rem;border-radius:6px}\
+section{border-top:1px solid #d0d7de;margin-top:1.5rem}time,small,.usage,footer,.attachment{color:#656d76;font-size:.9em}\
+.user h3{color:#0969da}.assistant h3{color:#8250df}.tool{margin:.5rem 0;border-left:3px solid #d4a72c;padding-left:.75rem}\
+img{max-width:100%}";
+
+/// Entries written before metadata only have the time of their usage.
+fn turn_time(turn: &TranscriptTurn) -> Option<String> {
+    turn.answered_at
+        .or_else(|| turn.usage.as_ref().map(|usage| usage.recorded_at))
+        .map(format_timestamp)
+}
+
+/// Formats a Unix timestamp in milliseconds as UTC, e.g. `2024-05-01 09:30:00 UTC`.
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, time) = (secs / 86_400, secs % 86_400);
+
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+fn usage_line(usage: &TurnUsage) -> String {
+    let mut line = format!(
+        "{} · {} prompt + {} completion = {} tokens",
+        usage.model, usage.prompt_tokens, usage.completion_tokens, usage.total_tokens,
+    );
+    if let Some(cost) = usage.cost {
+        let _ = write!(line, " · ${:.4}", cost);
+    }
+    line
+}
+
+fn totals_line(totals: &UsageTotals) -> String {
+    format!(
+        "{} requests · {} prompt + {} completion = {} tokens · ${:.4}",
+        totals.requests, totals.prompt_tokens, totals.completion_tokens, totals.total_tokens, totals.cost,
+    )
+}
+
+fn attachment_label(attachment: &Attachment) -> String {
+    format!("{} ({})", attachment.name.as_deref().unwrap_or("untitled"), attachment.media_type)
+}
+
+fn result_status(result: &ToolResult) -> &'static str {
+    match result {
+        ToolResult::Ok(_) => "ok",
+        ToolResult::Error(_) => "error",
+        ToolResult::Rejected(_) => "rejected",
+        ToolResult::PendingApproval => "pending approval",
+    }
+}
+
+/// Arguments are stored as the model sent them; pretty-print them when they parse.
+fn pretty_arguments(arguments: &str) -> String {
+    serde_json::from_str::<Value>(arguments)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| arguments.to_string())
+}
+
+/// A code block whose fence is longer than any backtick run in `text`.
+fn fenced(language: &str, text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or_default();
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, language, text.trim_end(), fence)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use waterfall_core::{config_key, StateDiff};
+
+    use super::*;
+    use crate::{assistant_message_key, attachment_diff, tool_call_key, usage_diff, user_message_key};
+
+    fn session() -> (State<StateValue>, CryptoHash) {
+        let config = LLMConfig {
+            id: config_key("agent").hash(),
+            name: "agent".to_string(),
+            system_prompt: "Be <brief>.".to_string(),
+            functions: vec![FunctionObject {
+                name: "weather".to_string(),
+                description: Some("Current weather in a city".to_string()),
+                parameters: Some(json!({ "type": "object", "properties": { "city": { "type": "string" } } })),
+                strict: None,
+            }],
+            ..Default::default()
+        };
+        let usage = TurnUsage {
+            model: "gpt-4o".to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+            cost: None,
+            recorded_at: 1_714_555_800_000,
+        };
+        let call = ToolCallRecord {
+            id: "call_1".to_string(),
+            round: 0,
+            name: "weather".to_string(),
+            arguments: "{\"city\":\"Oslo\"}".to_string(),
+            result: ToolResult::Ok("rain".to_string()),
+        };
+        let notes = Attachment::from_bytes(b"packing list", "text/plain", Some("notes.txt".to_string())).unwrap();
+
+        let mut state = State::default();
+        state.insert(config_key("agent"), config.clone().into()).unwrap();
+        let mut diff = attachment_diff(&state, 0, &[notes]).unwrap();
+        diff.insert(user_message_key(0), "Weather in Oslo?".into());
+        diff.insert(tool_call_key(0), vec![call].into());
+        diff.insert(assistant_message_key(0), "Rain, bring an umbrella.".into());
+        diff.merge(usage_diff(&state, &config, 0, &usage).unwrap());
+        diff.insert(user_message_key(1), "Thanks".into());
+        diff.insert(assistant_message_key(1), "You're welcome.".into());
+        diff.apply(&mut state).unwrap();
+        state.storage.get_mut(&assistant_message_key(0).hash()).unwrap().meta.updated_at = 1_714_555_800_000;
+
+        // A turn still waiting for its answer is not part of the transcript
+        let mut pending = StateDiff::new();
+        pending.insert(user_message_key(2), "One more".into());
+        pending.apply(&mut state).unwrap();
+
+        (state, config.id)
+    }
+
+    #[test]
+    fn test_transcript_formats() {
+        let (state, config_id) = session();
+        let transcript = Transcript::from_state(&state, &config_id).unwrap();
+        assert_eq!(transcript.turns.len(), 2);
+        assert_eq!(transcript.totals.total_tokens, 15);
+
+        let markdown = transcript.to_markdown();
+        assert!(markdown.contains("## Turn 0 · 2024-05-01 09:30:00 UTC"));
+        assert!(markdown.contains("### Tool call `weather` (call_1, round 0)"));
+        assert!(markdown.contains("\"city\": \"Oslo\""));
+        assert!(markdown.contains("- Attached notes.txt (text/plain)"));
+        assert!(markdown.contains("_gpt-4o · 10 prompt + 5 completion = 15 tokens_"));
+
+        let html = transcript.to_html();
+        assert!(html.contains("Be &lt;brief&gt;."));
+        assert!(!html.contains("<brief>"));
+
+        let jsonl = |metadata| transcript.to_jsonl(metadata).unwrap().lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let lines = jsonl(false);
+        assert_eq!(lines.len(), 2);
+        let roles = lines[1]["messages"].as_array().unwrap().iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(roles, ["system", "user", "assistant", "tool", "assistant", "user", "assistant"]);
+        assert_eq!(lines[0]["messages"][2]["tool_calls"][0]["function"]["name"], "weather");
+        assert_eq!(lines[0]["tools"][0]["function"]["name"], "weather");
+        // Fine-tuning lines hold nothing but the example
+        assert!(lines.iter().all(|line| line.get("metadata").is_none()));
+
+        let lines = jsonl(true);
+        assert_eq!(lines[0]["metadata"]["usage"]["total_tokens"], 15);
+        assert!(lines[1]["metadata"]["usage"].is_null());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_714_555_800_000), "2024-05-01 09:30:00 UTC");
+    }
+}
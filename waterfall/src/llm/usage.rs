@@ -0,0 +1,282 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use waterfall_core::{CryptoHash, LLMConfig, State, StateDiff, StateKey};
+
+use anyhow::Result;
+use async_openai::types::CompletionUsage;
+use serde::{Deserialize, Serialize};
+
+use super::{read_value, StateValue, LLM_NAMESPACE};
+
+/// Token usage of a single turn, stored next to its user and assistant messages.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct TurnUsage {
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// `None` when the config has no pricing for `model`.
+    pub cost: Option<f64>,
+    /// Unix timestamp in milliseconds.
+    pub recorded_at: u64,
+}
+
+impl TurnUsage {
+    pub fn from_completion(config: &LLMConfig, model: &str, usage: &CompletionUsage) -> Self {
+        let prompt_tokens = usage.prompt_tokens as u64;
+        let completion_tokens = usage.completion_tokens as u64;
+
+        Self {
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: usage.total_tokens as u64,
+            cost: config.pricing.get(model)
+                .map(|pricing| pricing.cost(prompt_tokens, completion_tokens)),
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Adds `other` into this usage, for turns that needed several requests.
+    pub fn absorb(&mut self, other: &TurnUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+        };
+        self.recorded_at = self.recorded_at.max(other.recorded_at);
+    }
+}
+
+/// Cumulative usage, kept once per session and once per config.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct UsageTotals {
+    /// Number of model requests, including summarization.
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Sum of every priced turn; unpriced turns only count towards tokens.
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: &TurnUsage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+        self.cost += usage.cost.unwrap_or_default();
+    }
+
+    /// Adds totals kept elsewhere, e.g. in another copy of the state.
+    pub fn merge(&mut self, other: &UsageTotals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cost += other.cost;
+    }
+
+    /// What was added to these totals since they were `earlier`.
+    pub fn since(&self, earlier: &UsageTotals) -> UsageTotals {
+        UsageTotals {
+            requests: self.requests.saturating_sub(earlier.requests),
+            prompt_tokens: self.prompt_tokens.saturating_sub(earlier.prompt_tokens),
+            completion_tokens: self.completion_tokens.saturating_sub(earlier.completion_tokens),
+            total_tokens: self.total_tokens.saturating_sub(earlier.total_tokens),
+            cost: self.cost - earlier.cost,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum BudgetError {
+    #[error("token budget exceeded: {used} of {limit} tokens used")]
+    TokensExceeded { used: u64, limit: u64 },
+    #[error("cost budget exceeded: ${used:.4} of ${limit:.4} spent")]
+    CostExceeded { used: f64, limit: f64 },
+}
+
+pub fn turn_usage_key(index: usize) -> StateKey {
+    StateKey::indexed(LLM_NAMESPACE, "usage", index)
+}
+
+pub fn session_usage_key() -> StateKey {
+    StateKey::single(LLM_NAMESPACE, "usage_total")
+}
+
+pub fn config_usage_key(config_id: &CryptoHash) -> StateKey {
+    StateKey::hashed(LLM_NAMESPACE, "usage_total", config_id)
+}
+
+pub fn read_totals(state: &State<StateValue>, key: &StateKey) -> Result<UsageTotals> {
+    Ok(read_value::<UsageTotals>(state, key)?.cloned().unwrap_or_default())
+}
+
+/// Records the usage of turn `index` and rolls it into the session and config totals.
+pub fn usage_diff(
+    state: &State<StateValue>,
+    config: &LLMConfig,
+    index: usize,
+    usage: &TurnUsage,
+) -> Result<StateDiff<StateValue>> {
+    let mut state_diff = totals_diff(state, config, usage)?;
+    state_diff.insert(turn_usage_key(index), usage.clone().into());
+    Ok(state_diff)
+}
+
+/// Rolls `usage` into the session and config totals without attributing it to a turn.
+pub fn totals_diff(state: &State<StateValue>, config: &LLMConfig, usage: &TurnUsage) -> Result<StateDiff<StateValue>> {
+    let mut state_diff = StateDiff::new();
+
+    for key in [session_usage_key(), config_usage_key(&config.id)] {
+        let mut totals = read_totals(state, &key)?;
+        totals.add(usage);
+        state_diff.update(key, totals.into());
+    }
+
+    Ok(state_diff)
+}
+
+/// Fails once the usage accumulated under `config`, with `in_turn` spent by
+/// the running turn and not yet in the totals, has reached its budget.
+pub fn check_budget(state: &State<StateValue>, config: &LLMConfig, in_turn: Option<&TurnUsage>) -> Result<()> {
+    let Some(budget) = &config.budget else {
+        return Ok(());
+    };
+
+    let mut totals = read_totals(state, &config_usage_key(&config.id))?;
+    if let Some(usage) = in_turn {
+        totals.add(usage);
+    }
+
+    if let Some(limit) = budget.max_total_tokens {
+        if totals.total_tokens >= limit {
+            return Err(BudgetError::TokensExceeded { used: totals.total_tokens, limit }.into());
+        }
+    }
+
+    if let Some(limit) = budget.max_cost {
+        if totals.cost >= limit {
+            return Err(BudgetError::CostExceeded { used: totals.cost, limit }.into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use waterfall_core::{config_key, Budget, ModelPricing};
+
+    fn config() -> LLMConfig {
+        let mut config = LLMConfig {
+            id: config_key("test").hash(),
+            openai_model: "model".to_string(),
+            ..Default::default()
+        };
+        config.pricing.insert("model".to_string(), ModelPricing {
+            prompt_per_million: 2.0,
+            completion_per_million: 10.0,
+        });
+        config
+    }
+
+    fn completion(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    #[test]
+    fn test_turn_cost() {
+        let config = config();
+
+        let priced = TurnUsage::from_completion(&config, "model", &completion(500_000, 100_000));
+        assert_eq!(priced.cost, Some(2.0));
+
+        let unpriced = TurnUsage::from_completion(&config, "other", &completion(10, 10));
+        assert_eq!(unpriced.cost, None);
+    }
+
+    #[test]
+    fn test_totals_accumulate() {
+        let config = config();
+        let mut state = State::default();
+
+        for index in 0..3 {
+            let usage = TurnUsage::from_completion(&config, "model", &completion(100, 50));
+            usage_diff(&state, &config, index, &usage).unwrap().apply(&mut state).unwrap();
+        }
+
+        let session = read_totals(&state, &session_usage_key()).unwrap();
+        assert_eq!(session.requests, 3);
+        assert_eq!(session.total_tokens, 450);
+        assert_eq!(session, read_totals(&state, &config_usage_key(&config.id)).unwrap());
+        assert!(state.get(&turn_usage_key(2)).is_some());
+    }
+
+    #[test]
+    fn test_budget_exceeded() {
+        let mut config = config();
+        config.budget = Some(Budget { max_total_tokens: Some(200), max_cost: None });
+        let mut state = State::default();
+
+        assert!(check_budget(&state, &config, None).is_ok());
+
+        // What the running turn spent counts before it reaches the totals
+        let usage = TurnUsage::from_completion(&config, "model", &completion(150, 50));
+        assert!(check_budget(&state, &config, Some(&usage)).is_err());
+
+        usage_diff(&state, &config, 0, &usage).unwrap().apply(&mut state).unwrap();
+
+        let error = check_budget(&state, &config, None).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BudgetError>(),
+            Some(&BudgetError::TokensExceeded { used: 200, limit: 200 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_budget_checked_before_each_request() {
+        use crate::{assistant_message_key, read_value, stand_in, LlmInstruction, LlmRuntime};
+        use waterfall_core::{Instruction, ProviderConfig, Runtime, RuntimeError, SummarizationConfig};
+
+        // Every request of the stand-in spends 4 tokens
+        let config = LLMConfig {
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            provider: ProviderConfig::Ollama {
+                base_url: Some(stand_in::ollama(&["completion", "tools"], |_| Ok("Hello".to_string())).await),
+                tool_calls: Default::default(),
+            },
+            summarization: Some(SummarizationConfig { model: "llama3".to_string(), threshold_turns: 1, keep_recent_turns: 0, ..Default::default() }),
+            budget: Some(Budget { max_total_tokens: Some(12), max_cost: None }),
+            ..Default::default()
+        };
+        let mut runtime = LlmRuntime::new();
+        runtime.set_quiet(true);
+        runtime.inject_system_config(&config).await.unwrap();
+        for message in ["one", "two"] {
+            runtime.push_instruction(LlmInstruction::parse_from(message.into(), config.id.clone())).unwrap();
+            runtime.execute().await.unwrap();
+        }
+
+        // The third turn is within budget until its summary spends the rest
+        runtime.push_instruction(LlmInstruction::parse_from("three".into(), config.id.clone())).unwrap();
+        assert!(matches!(runtime.execute().await, Err(RuntimeError::Stopped(_))));
+        assert_eq!(read_totals(&runtime.state, &config_usage_key(&config.id)).unwrap().requests, 3);
+        assert!(read_value::<String>(&runtime.state, &assistant_message_key(2)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, HashMap};
+
+use waterfall_core::{config_key, state_key, CryptoHash, LLMConfig, State, StateKey};
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    assistant_message_key, attachment_content_key, attachments_key, config_usage_key, pending_approvals_key,
+    session_usage_key, structured_output_key, summary_key, tool_call_key, turn_usage_key, user_message_key,
+    Attachment, BranchInfo, ConversationSummary, InstructionFailure, PendingApproval, ToolCallRecord, TurnUsage, UsageTotals, WorkflowRun,
+};
+
+/// Namespace of the state keys written by the LLM runtime.
+pub const LLM_NAMESPACE: &str = "llm";
+
+/// A value the LLM runtime keeps in state, stored as its own type so it is
+/// read back without parsing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum StateValue {
+    Config(Box<LLMConfig>),
+    /// User and assistant messages, and any other plain text.
+    Text(String),
+    ToolCalls(Vec<ToolCallRecord>),
+    Usage(TurnUsage),
+    UsageTotals(UsageTotals),
+    Summary(ConversationSummary),
+    PendingApprovals(BTreeMap<String, PendingApproval>),
+    Attachments(Vec<Attachment>),
+    /// Content of an attachment, kept under its hash.
+    Blob(#[serde(with = "base64_bytes")] Vec<u8>),
+    /// Structured output of a turn.
+    Json(Value),
+    Branch(BranchInfo),
+    Failure(InstructionFailure),
+    Workflow(WorkflowRun),
+}
+
+impl StateValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            StateValue::Config(_) => "config",
+            StateValue::Text(_) => "text",
+            StateValue::ToolCalls(_) => "tool calls",
+            StateValue::Usage(_) => "usage",
+            StateValue::UsageTotals(_) => "usage totals",
+            StateValue::Summary(_) => "summary",
+            StateValue::PendingApprovals(_) => "pending approvals",
+            StateValue::Attachments(_) => "attachments",
+            StateValue::Blob(_) => "blob",
+            StateValue::Json(_) => "JSON",
+            StateValue::Branch(_) => "branch",
+            StateValue::Failure(_) => "failure",
+            StateValue::Workflow(_) => "workflow run",
+        }
+    }
+
+    /// Text as is; anything else as its JSON.
+    pub fn preview(&self) -> String {
+        match self {
+            StateValue::Text(text) => text.clone(),
+            StateValue::Blob(bytes) => format!("<{} bytes>", bytes.len()),
+            other => serde_json::to_value(other)
+                .map(|value| value["value"].to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The value without what differs on every run, such as timestamps
+    /// and generated tool call ids, for comparing reruns.
+    pub fn without_volatile_fields(&self) -> StateValue {
+        match self {
+            StateValue::Usage(usage) => StateValue::Usage(TurnUsage { recorded_at: 0, ..usage.clone() }),
+            StateValue::ToolCalls(records) => StateValue::ToolCalls(records.iter()
+                .map(|record| ToolCallRecord { id: String::new(), ..record.clone() })
+                .collect()),
+            StateValue::PendingApprovals(pending) => {
+                // Keyed by call id; rekey them in an order that does not depend on it
+                let mut calls: Vec<_> = pending.values()
+                    .map(|approval| {
+                        let mut approval = approval.clone();
+                        approval.request.tool_call_id.clear();
+                        approval
+                    })
+                    .collect();
+                calls.sort_by_cached_key(|approval| serde_json::to_string(approval).unwrap_or_default());
+                StateValue::PendingApprovals(calls.into_iter().enumerate().map(|(n, approval)| (n.to_string(), approval)).collect())
+            }
+            other => other.clone(),
+        }
+    }
+
+    pub fn into_text(self) -> String {
+        match self {
+            StateValue::Text(text) => text,
+            other => other.preview(),
+        }
+    }
+}
+
+impl From<&str> for StateValue {
+    fn from(text: &str) -> Self {
+        StateValue::Text(text.to_string())
+    }
+}
+
+/// Types stored as one variant of `StateValue`.
+pub trait StateType: Sized {
+    const TYPE_NAME: &'static str;
+
+    fn from_value(value: &StateValue) -> Option<&Self>;
+    fn into_value(self) -> StateValue;
+}
+
+macro_rules! state_type {
+    ($ty:ty, $variant:ident, $name:literal) => {
+        impl StateType for $ty {
+            const TYPE_NAME: &'static str = $name;
+
+            fn from_value(value: &StateValue) -> Option<&Self> {
+                match value {
+                    StateValue::$variant(inner) => Some(std::borrow::Borrow::borrow(inner)),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> StateValue {
+                StateValue::$variant(self.into())
+            }
+        }
+
+        impl From<$ty> for StateValue {
+            fn from(value: $ty) -> Self {
+                value.into_value()
+            }
+        }
+    };
+}
+
+state_type!(LLMConfig, Config, "config");
+state_type!(String, Text, "text");
+state_type!(Vec<ToolCallRecord>, ToolCalls, "tool calls");
+state_type!(TurnUsage, Usage, "usage");
+state_type!(UsageTotals, UsageTotals, "usage totals");
+state_type!(ConversationSummary, Summary, "summary");
+state_type!(BTreeMap<String, PendingApproval>, PendingApprovals, "pending approvals");
+state_type!(Vec<Attachment>, Attachments, "attachments");
+state_type!(Vec<u8>, Blob, "blob");
+state_type!(Value, Json, "JSON");
+state_type!(BranchInfo, Branch, "branch");
+state_type!(InstructionFailure, Failure, "failure");
+state_type!(WorkflowRun, Workflow, "workflow run");
+
+/// Reads the value under `key`; fails when it holds another type.
+pub fn read_value<'a, T: StateType>(state: &'a State<StateValue>, key: &StateKey) -> Result<Option<&'a T>> {
+    read_value_at(state, &key.hash())
+}
+
+/// `read_value` for entries addressed by hash, such as configs.
+pub fn read_value_at<'a, T: StateType>(state: &'a State<StateValue>, hash: &CryptoHash) -> Result<Option<&'a T>> {
+    match state.get_hash(hash) {
+        Some(value) => T::from_value(value)
+            .map(Some)
+            .ok_or_else(|| {
+                let key = state.key_of(hash).map(ToString::to_string).unwrap_or_else(|| hash.to_string());
+                anyhow!("State entry {} holds {}, not {}", key, value.type_name(), T::TYPE_NAME)
+            }),
+        None => Ok(None),
+    }
+}
+
+type Parser = fn(&str) -> Result<StateValue>;
+
+fn parse<T: StateType + DeserializeOwned>(raw: &str) -> Result<StateValue> {
+    Ok(serde_json::from_str::<T>(raw)?.into_value())
+}
+
+fn text(raw: &str) -> Result<StateValue> {
+    Ok(StateValue::Text(raw.to_string()))
+}
+
+fn blob(raw: &str) -> Result<StateValue> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    Ok(StateValue::Blob(STANDARD.decode(raw)?))
+}
+
+/// Converts a state from before typed values and keys, when every entry was
+/// a string under a `state_key!` hash and structured entries were JSON.
+///
+/// Entries are recognized by their old key and moved to their typed key.
+/// Configs are recognized by being stored under their own id; named ones
+/// move to their `config_key`, which becomes their id. Attachment content is
+/// recognized by being referenced. Anything else is kept as text under its
+/// old hash.
+pub fn migrate_string_state(state: &State<String>) -> Result<State<StateValue>> {
+    let mut legacy: HashMap<CryptoHash, (StateKey, Parser)> = HashMap::from([
+        (state_key!("conversation_summary"), (summary_key(), parse::<ConversationSummary> as Parser)),
+        (state_key!("pending_approvals"), (pending_approvals_key(), parse::<BTreeMap<String, PendingApproval>>)),
+        (state_key!("usage_total"), (session_usage_key(), parse::<UsageTotals>)),
+    ]);
+    for index in 0.. {
+        let turn_keys: [(CryptoHash, (StateKey, Parser)); 6] = [
+            (state_key!("user_message", index), (user_message_key(index), text)),
+            (state_key!("assistant_message", index), (assistant_message_key(index), text)),
+            (state_key!("tool_call", index), (tool_call_key(index), parse::<Vec<ToolCallRecord>>)),
+            (state_key!("usage", index), (turn_usage_key(index), parse::<TurnUsage>)),
+            (state_key!("user_attachments", index), (attachments_key(index), parse::<Vec<Attachment>>)),
+            (state_key!("structured_output", index), (structured_output_key(index), parse::<Value>)),
+        ];
+        if !turn_keys.iter().any(|(hash, _)| state.contains(hash)) {
+            break;
+        }
+        legacy.extend(turn_keys);
+    }
+
+    let mut migrated = State { id: state.id.clone(), ..State::default() };
+    // Old config ids to new ones
+    let mut config_ids = HashMap::new();
+    let mut unknown = Vec::new();
+    for (hash, raw) in state.iter() {
+        if let Some((key, parse)) = legacy.get(hash) {
+            migrated.insert(key.clone(), migrate_entry(hash, raw, *parse)?)?;
+            continue;
+        }
+
+        match serde_json::from_str::<LLMConfig>(raw) {
+            Ok(mut config) if config.id == *hash && !config.name.is_empty() => {
+                let key = config_key(&config.name);
+                config.id = key.hash();
+                config_ids.insert(hash.clone(), config.id.clone());
+                migrated.insert(key, config.into_value())?;
+            }
+            Ok(config) if config.id == *hash => {
+                config_ids.insert(hash.clone(), hash.clone());
+                migrated.insert_hash(hash.clone(), config.into_value());
+            }
+            _ => unknown.push((hash, raw)),
+        }
+    }
+
+    // Entries only recognizable through the ones above
+    let mut derived: HashMap<CryptoHash, (StateKey, Parser)> = config_ids.iter()
+        .map(|(old, new)| (state_key!("usage_total", old), (config_usage_key(new), parse::<UsageTotals> as Parser)))
+        .collect();
+    for (_, value) in migrated.iter() {
+        if let StateValue::Attachments(attachments) = value {
+            derived.extend(attachments.iter().map(|attachment| (
+                attachment.hash.clone(),
+                (attachment_content_key(&attachment.hash), blob as Parser),
+            )));
+        }
+    }
+    for (hash, raw) in unknown {
+        match derived.get(hash) {
+            Some((key, parse)) => migrated.insert(key.clone(), migrate_entry(hash, raw, *parse)?)?,
+            None => {
+                migrated.insert_hash(hash.clone(), StateValue::Text(raw.clone()));
+            }
+        }
+    }
+
+    let pending_approvals = migrated.storage.get_mut(&pending_approvals_key().hash()).map(|entry| &mut entry.value);
+    if let Some(StateValue::PendingApprovals(pending)) = pending_approvals {
+        for approval in pending.values_mut() {
+            if let Some(id) = config_ids.get(&approval.system_config_hash) {
+                approval.system_config_hash = id.clone();
+            }
+        }
+    }
+
+    migrated.sub_states = state.sub_states.iter()
+        .map(|(id, sub_state)| Ok((id.clone(), migrate_string_state(sub_state)?)))
+        .collect::<Result<_>>()?;
+    Ok(migrated)
+}
+
+fn migrate_entry(hash: &CryptoHash, raw: &str, parse: Parser) -> Result<StateValue> {
+    parse(raw).map_err(|e| anyhow!("Cannot migrate state entry {}: {}", hash, e))
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD.decode(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ToolResult;
+    use waterfall_core::{export_snapshot, import_snapshot, SnapshotFormat, SnapshotOptions};
+
+    #[test]
+    fn test_migrate_string_state() {
+        let config = LLMConfig { id: state_key!("agent"), name: "agent".to_string(), ..Default::default() };
+        let attachment = Attachment::from_bytes(b"notes", "text/plain", None).unwrap();
+        let call = ToolCallRecord {
+            id: "call_1".to_string(),
+            round: 0,
+            name: "weather".to_string(),
+            arguments: "{}".to_string(),
+            result: ToolResult::Ok("rain".to_string()),
+        };
+
+        let mut old = State::<String>::default();
+        old.insert_hash(config.id.clone(), serde_json::to_string(&config).unwrap());
+        old.insert_hash(state_key!("user_message", 0), "{\"looks\": \"like JSON\"}".to_string());
+        old.insert_hash(state_key!("assistant_message", 0), "Rain".to_string());
+        old.insert_hash(state_key!("tool_call", 0), serde_json::to_string(&vec![call.clone()]).unwrap());
+        old.insert_hash(state_key!("user_attachments", 0), serde_json::to_string(&vec![Attachment { data: None, ..attachment.clone() }]).unwrap());
+        old.insert_hash(attachment.hash.clone(), attachment.data.clone().unwrap());
+        old.insert_hash(state_key!("usage_total", config.id), serde_json::to_string(&UsageTotals::default()).unwrap());
+        old.insert_hash(state_key!("custom"), "kept".to_string());
+
+        let state = migrate_string_state(&old).unwrap();
+        assert_eq!(state.storage.len(), old.storage.len());
+        assert_eq!(state.keys.len(), old.storage.len() - 1);
+
+        let config_id = config_key("agent").hash();
+        assert_eq!(read_value_at::<LLMConfig>(&state, &config_id).unwrap().unwrap().id, config_id);
+        assert_eq!(read_value::<String>(&state, &user_message_key(0)).unwrap().unwrap(), "{\"looks\": \"like JSON\"}");
+        assert_eq!(read_value::<Vec<ToolCallRecord>>(&state, &tool_call_key(0)).unwrap().unwrap(), &vec![call]);
+        assert_eq!(read_value::<Vec<u8>>(&state, &attachment_content_key(&attachment.hash)).unwrap().unwrap(), b"notes");
+        assert!(read_value::<UsageTotals>(&state, &config_usage_key(&config_id)).unwrap().is_some());
+        assert_eq!(read_value_at::<String>(&state, &state_key!("custom")).unwrap().unwrap(), "kept");
+
+        // Reading an entry as the wrong type is an error, not a miss
+        assert!(read_value_at::<TurnUsage>(&state, &state_key!("custom")).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_formats() {
+        let config = LLMConfig { id: config_key("agent").hash(), name: "agent".to_string(), ..Default::default() };
+        let mut state = State::<StateValue>::default();
+        state.insert(config_key("agent"), config.into()).unwrap();
+        state.insert(user_message_key(0), "hello".into()).unwrap();
+        state.insert(attachment_content_key(&CryptoHash::default()), vec![0u8, 159, 146, 150].into()).unwrap();
+        state.insert(StateKey::single("app", "json"), StateValue::Json(serde_json::json!({ "a": [1, null] }))).unwrap();
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Cbor, SnapshotFormat::MessagePack] {
+            let options = SnapshotOptions { format, compress: true, ..Default::default() };
+            let snapshot = export_snapshot(&state, &options).unwrap();
+            let restored = import_snapshot::<StateValue>(&snapshot, None).unwrap();
+            for (hash, value) in state.iter() {
+                let restored = serde_json::to_value(restored.get_hash(hash)).unwrap();
+                assert_eq!(restored, serde_json::to_value(value).unwrap(), "{} in {}", state.key_of(hash).unwrap(), format);
+            }
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use waterfall_core::{config_key, CryptoHash, Instruction, KeyId, Runtime, State, StateDiff, StateKey, StepAction, WorkflowConfig, WorkflowStep};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    assistant_message_key, read_value, structured_output_key, LlmInstruction, LlmRuntime, StateValue, ToolResult,
+};
+
+pub const WORKFLOW_NAMESPACE: &str = "workflow";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkflowStatus {
+    Running,
+    Failed,
+    Completed,
+}
+
+/// Progress of the latest run of a workflow. A run that did not complete
+/// resumes after the steps it finished.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkflowRun {
+    pub workflow: String,
+    pub status: WorkflowStatus,
+    /// Paths of finished steps and iterations, e.g. `review[1]/draft`.
+    pub completed: BTreeSet<String>,
+    /// Arm taken by each branch step that started, by path.
+    pub branches: BTreeMap<String, bool>,
+    pub error: Option<String>,
+}
+
+pub fn workflow_run_key(workflow: &str) -> StateKey {
+    StateKey::named(WORKFLOW_NAMESPACE, "run", workflow)
+}
+
+pub fn workflow_var_key(workflow: &str, name: &str) -> StateKey {
+    StateKey::named(WORKFLOW_NAMESPACE, "var", &format!("{}/{}", workflow, name))
+}
+
+pub fn read_workflow_run(state: &State<StateValue>, workflow: &str) -> Result<Option<WorkflowRun>> {
+    Ok(read_value::<WorkflowRun>(state, &workflow_run_key(workflow))?.cloned())
+}
+
+pub fn read_workflow_var(state: &State<StateValue>, workflow: &str, name: &str) -> Result<Option<Value>> {
+    Ok(read_value::<Value>(state, &workflow_var_key(workflow, name))?.cloned())
+}
+
+impl LlmRuntime {
+    /// Runs `workflow`, or resumes its latest run if that did not complete;
+    /// `inputs` then stay as they were. A new run starts from `inputs` alone,
+    /// without the variables of earlier runs. Progress is kept in the state,
+    /// so a run also resumes from a snapshot of it.
+    pub async fn run_workflow(&mut self, workflow: &WorkflowConfig, inputs: Map<String, Value>) -> Result<WorkflowRun> {
+        let resumed = read_workflow_run(&self.state, &workflow.name)?
+            .filter(|run| run.status != WorkflowStatus::Completed);
+        let mut engine = WorkflowEngine {
+            run: WorkflowRun {
+                status: WorkflowStatus::Running,
+                error: None,
+                ..resumed.clone().unwrap_or_else(|| WorkflowRun {
+                    workflow: workflow.name.clone(),
+                    status: WorkflowStatus::Running,
+                    completed: BTreeSet::new(),
+                    branches: BTreeMap::new(),
+                    error: None,
+                })
+            },
+            runtime: self,
+            workflow,
+        };
+        let inputs = match resumed {
+            Some(_) => Vec::new(),
+            None => {
+                engine.clear_vars()?;
+                inputs.into_iter().collect()
+            }
+        };
+        engine.save(&[], inputs)?;
+
+        let result = engine.run_steps(&workflow.steps, String::new()).await;
+        engine.run.status = match &result {
+            Ok(()) => WorkflowStatus::Completed,
+            Err(e) => {
+                engine.run.error = Some(e.to_string());
+                WorkflowStatus::Failed
+            }
+        };
+        engine.save(&[], Vec::new())?;
+        result.map(|_| engine.run)
+    }
+}
+
+struct WorkflowEngine<'a> {
+    runtime: &'a mut LlmRuntime,
+    workflow: &'a WorkflowConfig,
+    run: WorkflowRun,
+}
+
+impl<'a> WorkflowEngine<'a> {
+    fn run_steps<'b>(&'b mut self, steps: &'a [WorkflowStep], prefix: String) -> BoxFuture<'b, Result<()>> {
+        Box::pin(async move {
+            for step in steps {
+                let path = match prefix.is_empty() {
+                    true => step.id.clone(),
+                    false => format!("{}/{}", prefix, step.id),
+                };
+                if !self.run.completed.contains(&path) {
+                    self.run_step(step, path).await?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn run_step(&mut self, step: &'a WorkflowStep, path: String) -> Result<()> {
+        match &step.action {
+            StepAction::Llm { agent, prompt, output } => {
+                let mut instruction = LlmInstruction::parse_from(self.render(prompt)?.into(), self.agent(agent)?);
+                instruction.prepare(&self.runtime.state)?;
+                self.runtime.execute_one(&instruction).await?;
+
+                let index = instruction.new_message_index;
+                let answer = match read_value::<Value>(&self.runtime.state, &structured_output_key(index))? {
+                    Some(structured) => structured.clone(),
+                    None => Value::String(read_value::<String>(&self.runtime.state, &assistant_message_key(index))?.cloned().unwrap_or_default()),
+                };
+                self.save(&[path], output.iter().map(|name| (name.clone(), answer.clone())).collect())
+            }
+            StepAction::Tool { agent, name, arguments, output } => {
+                let arguments = self.render_value(arguments)?;
+                let result = match self.runtime.call_tool(&self.agent(agent)?, name, arguments).await? {
+                    // Tools answer with text, which is often JSON
+                    ToolResult::Ok(content) => serde_json::from_str(&content).unwrap_or(Value::String(content)),
+                    other => return Err(anyhow!("Tool {} of step {} failed: {}", name, path, other.content())),
+                };
+                self.save(&[path], output.iter().map(|name| (name.clone(), result.clone())).collect())
+            }
+            StepAction::Branch { condition, then, otherwise } => {
+                // Decided once, so a resumed run continues in the same arm
+                let take_then = match self.run.branches.get(&path) {
+                    Some(take_then) => *take_then,
+                    None => {
+                        let take_then = condition.holds(&|name| self.var(name));
+                        self.run.branches.insert(path.clone(), take_then);
+                        take_then
+                    }
+                };
+                self.run_steps(if take_then { then } else { otherwise }, path.clone()).await?;
+                self.save(&[path], Vec::new())
+            }
+            StepAction::Loop { until, max_iterations, steps } => {
+                for iteration in 0..*max_iterations {
+                    let iteration_path = format!("{}[{}]", path, iteration);
+                    if self.run.completed.contains(&iteration_path) {
+                        continue;
+                    }
+                    self.run_steps(steps, iteration_path.clone()).await?;
+                    if until.holds(&|name| self.var(name)) {
+                        return self.save(&[iteration_path, path], Vec::new());
+                    }
+                    self.save(&[iteration_path], Vec::new())?;
+                }
+                Err(anyhow!("Loop {} did not finish within {} iterations", path, max_iterations))
+            }
+            StepAction::Map { over, item, steps, collect, output } => {
+                let items = match self.var(over) {
+                    Some(Value::Array(items)) => items,
+                    _ => return Err(anyhow!("Map {} needs a list in {}", path, over)),
+                };
+                for (index, value) in items.into_iter().enumerate() {
+                    let iteration_path = format!("{}[{}]", path, index);
+                    if self.run.completed.contains(&iteration_path) {
+                        continue;
+                    }
+                    self.save(&[], vec![(item.clone(), value)])?;
+                    self.run_steps(steps, iteration_path.clone()).await?;
+
+                    let mut vars = Vec::new();
+                    if let Some(output) = output {
+                        let mut collected = match (index, self.var(output)) {
+                            (1.., Some(Value::Array(collected))) => collected,
+                            _ => Vec::new(),
+                        };
+                        collected.push(self.var(collect.as_ref().unwrap_or(item)).unwrap_or(Value::Null));
+                        vars.push((output.clone(), Value::Array(collected)));
+                    }
+                    self.save(&[iteration_path], vars)?;
+                }
+                self.save(&[path], Vec::new())
+            }
+        }
+    }
+
+    /// Writes `vars` and the run with `paths` marked finished in one diff.
+    fn save(&mut self, paths: &[String], vars: Vec<(String, Value)>) -> Result<()> {
+        self.run.completed.extend(paths.iter().cloned());
+
+        let mut diff = StateDiff::new();
+        for (name, value) in vars {
+            diff.update(workflow_var_key(&self.workflow.name, &name), value.into());
+        }
+        diff.update(workflow_run_key(&self.workflow.name), self.run.clone().into());
+        diff.set_instruction(format!("workflow[{}]", self.workflow.name));
+        diff.apply(&mut self.runtime.state)
+    }
+
+    fn clear_vars(&mut self) -> Result<()> {
+        let prefix = format!("{}/", self.workflow.name);
+        let state = &self.runtime.state;
+        let mut diff = StateDiff::new();
+        for key in state.keys_in(WORKFLOW_NAMESPACE) {
+            if matches!(&key.id, KeyId::Name(name) if key.kind == "var" && name.starts_with(&prefix)) && state.contains(&key.hash()) {
+                diff.delete(key);
+            }
+        }
+        diff.set_instruction(format!("workflow[{}]", self.workflow.name));
+        diff.apply(&mut self.runtime.state)
+    }
+
+    fn agent(&self, agent: &Option<String>) -> Result<CryptoHash> {
+        let name = agent.as_ref().or(self.workflow.agent.as_ref())
+            .ok_or_else(|| anyhow!("Workflow {} has a step without an agent and no default agent", self.workflow.name))?;
+        Ok(config_key(name).hash())
+    }
+
+    /// The variable, or with a dotted name like `item.title` or `list.0`,
+    /// a field or element of it.
+    fn var(&self, name: &str) -> Option<Value> {
+        let mut parts = name.split('.');
+        let mut value = read_workflow_var(&self.runtime.state, &self.workflow.name, parts.next()?).ok()??;
+        for part in parts {
+            value = match value {
+                Value::Object(mut fields) => fields.remove(part)?,
+                Value::Array(mut items) => {
+                    let index = part.parse::<usize>().ok().filter(|index| *index < items.len())?;
+                    items.swap_remove(index)
+                }
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    /// Replaces each `{{name}}` with the variable; strings as they are,
+    /// other values as JSON.
+    fn render(&self, template: &str) -> Result<String> {
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}").ok_or_else(|| anyhow!("Unclosed {{{{ in {:?}", template))? + start;
+            let name = rest[start + 2..end].trim();
+            rendered.push_str(&rest[..start]);
+            match self.var(name).ok_or_else(|| anyhow!("Variable {} is not set", name))? {
+                Value::String(text) => rendered.push_str(&text),
+                value => rendered.push_str(&value.to_string()),
+            }
+            rest = &rest[end + 2..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+
+    fn render_value(&self, value: &Value) -> Result<Value> {
+        Ok(match value {
+            Value::String(text) => {
+                let name = text.trim().strip_prefix("{{").and_then(|inner| inner.strip_suffix("}}"));
+                match name.filter(|name| !name.contains("{{")) {
+                    Some(name) => self.var(name.trim()).ok_or_else(|| anyhow!("Variable {} is not set", name.trim()))?,
+                    None => Value::String(self.render(text)?),
+                }
+            }
+            Value::Array(items) => Value::Array(items.iter().map(|item| self.render_value(item)).collect::<Result<_>>()?),
+            Value::Object(fields) => Value::Object(fields.iter()
+                .map(|(key, value)| Ok((key.clone(), self.render_value(value)?)))
+                .collect::<Result<_>>()?),
+            other => other.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use async_openai::types::FunctionObject;
+    use serde_json::json;
+    use waterfall_core::{ConfigReader, LLMConfig, ProviderConfig};
+    use crate::{stand_in, ToolHandler};
+
+    const WORKFLOW: &str = r#"
+workflows:
+  research:
+    agent: agent
+    steps:
+      - id: outline
+        llm:
+          prompt: "Outline {{topic}}"
+          output: outline
+      - id: sections
+        tool:
+          name: split
+          arguments: { text: "{{outline}}" }
+          output: sections
+      - id: each
+        map:
+          over: sections
+          as: section
+          collect: note
+          output: notes
+          steps:
+            - id: note
+              tool:
+                name: note
+                arguments: { section: "{{section}}" }
+                output: note
+      - id: check
+        branch:
+          if: { var: notes, contains: "note on a" }
+          then:
+            - id: polish
+              loop:
+                until: { var: round, equals: 2 }
+                max_iterations: 5
+                steps:
+                  - id: count
+                    tool: { name: count, output: round }
+"#;
+
+    /// Counts its calls and answers with `answer` of the arguments and
+    /// the number of the call.
+    struct Handler {
+        calls: AtomicUsize,
+        answer: fn(&Value, usize) -> Result<String>,
+    }
+
+    #[async_trait::async_trait]
+    impl ToolHandler for Handler {
+        async fn call(&self, arguments: Value) -> Result<String> {
+            (self.answer)(&arguments, self.calls.fetch_add(1, Ordering::SeqCst))
+        }
+    }
+
+    /// Serves an Ollama model that counts the requests it answered.
+    async fn counting_model(requests: Arc<AtomicUsize>) -> String {
+        stand_in::ollama(&["completion", "tools"], move |body| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let prompt = body["messages"].as_array().unwrap().last().unwrap()["content"].as_str().unwrap();
+            Ok(format!("a,b for {}", prompt))
+        }).await
+    }
+
+    #[tokio::test]
+    async fn test_workflow_resumes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), WORKFLOW).unwrap();
+        let workflow = ConfigReader::workflows(file.path()).unwrap().remove(0);
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let function = |name: &str| FunctionObject {
+            name: name.to_string(),
+            description: None,
+            parameters: Some(json!({ "type": "object" })),
+            strict: None,
+        };
+        let config = LLMConfig {
+            id: config_key("agent").hash(),
+            name: "agent".to_string(),
+            openai_model: "llama3".to_string(),
+            functions: vec![function("split"), function("note"), function("count")],
+            provider: ProviderConfig::Ollama { base_url: Some(counting_model(requests.clone()).await), tool_calls: Default::default() },
+            ..Default::default()
+        };
+
+        let mut runtime = LlmRuntime::new();
+        runtime.set_quiet(true);
+        runtime.inject_system_config(&config).await.unwrap();
+        runtime.register_tool("split", Handler {
+            calls: AtomicUsize::new(0),
+            answer: |arguments, _| Ok(json!(arguments["text"].as_str().unwrap().split(' ').next().unwrap().split(',').collect::<Vec<_>>()).to_string()),
+        });
+        // Fails on its second call, the first time it sees `b`
+        runtime.register_tool("note", Handler {
+            calls: AtomicUsize::new(0),
+            answer: |arguments, call| match call {
+                1 => Err(anyhow!("rate limited")),
+                _ => Ok(format!("note on {}", arguments["section"].as_str().unwrap())),
+            },
+        });
+        runtime.register_tool("count", Handler { calls: AtomicUsize::new(0), answer: |_, call| Ok((call + 1).to_string()) });
+
+        let inputs = json!({ "topic": "rivers" }).as_object().unwrap().clone();
+        let error = runtime.run_workflow(&workflow, inputs.clone()).await.unwrap_err();
+        assert!(error.to_string().contains("rate limited"));
+        let run = read_workflow_run(&runtime.state, "research").unwrap().unwrap();
+        assert_eq!(run.status, WorkflowStatus::Failed);
+        assert!(run.completed.contains("each[0]/note") && !run.completed.contains("each[1]"));
+
+        // Resuming skips what finished, the model call included
+        let run = runtime.run_workflow(&workflow, inputs).await.unwrap();
+        assert_eq!(run.status, WorkflowStatus::Completed);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let var = |name: &str| read_workflow_var(&runtime.state, "research", name).unwrap().unwrap();
+        assert_eq!(var("outline"), "a,b for Outline rivers");
+        assert_eq!(var("notes"), json!(["note on a", "note on b"]));
+        assert_eq!(var("round"), json!(2));
+        assert!(run.completed.contains("check/polish[1]/count"));
+
+        // A new run does not see the variables of the last one
+        let error = runtime.run_workflow(&workflow, Map::new()).await.unwrap_err();
+        assert!(error.to_string().contains("Variable topic is not set"));
+        assert!(read_workflow_var(&runtime.state, "research", "round").unwrap().is_none());
+    }
+}
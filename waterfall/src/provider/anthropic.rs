@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse};
+use serde_json::{json, Value};
+
+use super::{completion_chunk, event_stream, unix_now, CompletionStream, LlmProvider, ProviderError};
+
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Used when the request does not set a token limit, which Anthropic requires.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// The Anthropic Messages API.
+pub struct AnthropicProvider {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    /// `base_url` defaults to the Anthropic API.
+    pub fn new(http: reqwest::Client, base_url: Option<&str>, api_key: &str) -> Self {
+        Self {
+            http,
+            base_url: base_url.unwrap_or("https://api.anthropic.com").trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http.request(method, format!("{}{}", self.base_url, path))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    async fn send(&self, body: &Value) -> Result<reqwest::Response> {
+        let response = self.request(reqwest::Method::POST, "/v1/messages").json(body).send().await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let error = response.json::<Value>().await.unwrap_or_default();
+        Err(ProviderError::Status {
+            provider: "Anthropic API",
+            status,
+            message: error["error"]["message"].as_str().unwrap_or("no details").to_string(),
+        }.into())
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        let response = self.send(&anthropic_request(&request)?).await?;
+        openai_response(&response.json().await?)
+    }
+
+    async fn stream(&self, request: CreateChatCompletionRequest) -> Result<CompletionStream> {
+        let mut body = anthropic_request(&request)?;
+        body["stream"] = json!(true);
+        let response = self.send(&body).await?;
+
+        let mut translator = StreamTranslator::default();
+        Ok(event_stream(response, b"\n\n", move |event| translator.translate(event)))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let mut models = Vec::new();
+        let mut after: Option<String> = None;
+
+        loop {
+            let mut request = self.request(reqwest::Method::GET, "/v1/models").query(&[("limit", "1000")]);
+            if let Some(after) = &after {
+                request = request.query(&[("after_id", after)]);
+            }
+            let page = request.send().await?.error_for_status()?.json::<Value>().await?;
+
+            models.extend(page["data"].as_array().into_iter().flatten()
+                .filter_map(|model| model["id"].as_str().map(|id| id.to_string())));
+
+            after = page["last_id"].as_str().map(|id| id.to_string());
+            if !page["has_more"].as_bool().unwrap_or(false) || after.is_none() {
+                return Ok(models);
+            }
+        }
+    }
+}
+
+/// Translates a chat completion request into a Messages API request.
+///
+/// System messages are joined into the system prompt, tool calls and tool
+/// messages become `tool_use` and `tool_result` blocks, and consecutive
+/// messages of one role are merged, since the API requires alternation.
+pub fn anthropic_request(request: &CreateChatCompletionRequest) -> Result<Value> {
+    let openai = serde_json::to_value(request)?;
+
+    if openai["response_format"]["type"].as_str().is_some_and(|kind| kind != "text") {
+        return Err(anyhow!("The Anthropic provider cannot enforce response_format; set native: false on the response format"));
+    }
+
+    let mut system = Vec::new();
+    let mut messages: Vec<Value> = Vec::new();
+    for message in openai["messages"].as_array().into_iter().flatten() {
+        let (role, blocks) = match message["role"].as_str().unwrap_or_default() {
+            "system" | "developer" => {
+                system.push(content_text(&message["content"]));
+                continue;
+            }
+            "user" => ("user", user_blocks(&message["content"])),
+            "assistant" => ("assistant", assistant_blocks(message)),
+            "tool" => ("user", vec![json!({
+                "type": "tool_result",
+                "tool_use_id": message["tool_call_id"],
+                "content": content_text(&message["content"]),
+            })]),
+            other => return Err(anyhow!("The Anthropic provider does not support {} messages", other)),
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+        match messages.last_mut() {
+            Some(last) if last["role"] == role => {
+                last["content"].as_array_mut().expect("content is built as an array").extend(blocks);
+            }
+            _ => messages.push(json!({ "role": role, "content": blocks })),
+        }
+    }
+
+    let max_tokens = openai["max_completion_tokens"].as_u64()
+        .or_else(|| openai["max_tokens"].as_u64())
+        .unwrap_or(DEFAULT_MAX_TOKENS);
+
+    let mut body = json!({
+        "model": openai["model"],
+        "max_tokens": max_tokens,
+        "messages": messages,
+    });
+    if !system.is_empty() {
+        body["system"] = json!(system.join("\n\n"));
+    }
+    for field in ["temperature", "top_p"] {
+        if !openai[field].is_null() {
+            body[field] = openai[field].clone();
+        }
+    }
+    match &openai["stop"] {
+        Value::String(stop) => body["stop_sequences"] = json!([stop]),
+        Value::Array(stop) => body["stop_sequences"] = json!(stop),
+        _ => {}
+    }
+
+    let tools = openai["tools"].as_array().cloned().unwrap_or_default();
+    if !tools.is_empty() {
+        body["tools"] = tools.iter()
+            .map(|tool| json!({
+                "name": tool["function"]["name"],
+                "description": tool["function"]["description"].as_str().unwrap_or_default(),
+                "input_schema": match &tool["function"]["parameters"] {
+                    Value::Null => json!({ "type": "object" }),
+                    parameters => parameters.clone(),
+                },
+            }))
+            .collect();
+
+        body["tool_choice"] = match &openai["tool_choice"] {
+            Value::String(choice) if choice == "none" => json!({ "type": "none" }),
+            Value::String(choice) if choice == "required" => json!({ "type": "any" }),
+            Value::Object(choice) => json!({ "type": "tool", "name": choice["function"]["name"] }),
+            _ => json!({ "type": "auto" }),
+        };
+    }
+
+    Ok(body)
+}
+
+/// Text of a message content, given as a string or as text parts.
+fn content_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn user_blocks(content: &Value) -> Vec<Value> {
+    let Value::Array(parts) = content else {
+        return text_block(&content_text(content)).into_iter().collect();
+    };
+
+    parts.iter()
+        .filter_map(|part| match part["type"].as_str() {
+            Some("text") => text_block(part["text"].as_str().unwrap_or_default()),
+            Some("image_url") => Some(image_block(part["image_url"]["url"].as_str().unwrap_or_default())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn assistant_blocks(message: &Value) -> Vec<Value> {
+    let mut blocks: Vec<Value> = text_block(&content_text(&message["content"])).into_iter().collect();
+
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        // Arguments the model garbled were already reported back as an error
+        let input = call["function"]["arguments"].as_str()
+            .and_then(|arguments| serde_json::from_str::<Value>(arguments).ok())
+            .filter(|input| input.is_object())
+            .unwrap_or_else(|| json!({}));
+
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": call["id"],
+            "name": call["function"]["name"],
+            "input": input,
+        }));
+    }
+
+    blocks
+}
+
+/// The API rejects empty text blocks.
+fn text_block(text: &str) -> Option<Value> {
+    (!text.is_empty()).then(|| json!({ "type": "text", "text": text }))
+}
+
+/// Data URLs are sent inline; other URLs are fetched by the API.
+fn image_block(url: &str) -> Value {
+    let inline = url.strip_prefix("data:")
+        .and_then(|data| data.split_once(";base64,"));
+
+    match inline {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": { "type": "base64", "media_type": media_type, "data": data },
+        }),
+        None => json!({ "type": "image", "source": { "type": "url", "url": url } }),
+    }
+}
+
+/// Translates a Messages API response into a chat completion response.
+pub fn openai_response(response: &Value) -> Result<CreateChatCompletionResponse> {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in response["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => text.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": { "name": block["name"], "arguments": block["input"].to_string() },
+            })),
+            _ => {}
+        }
+    }
+
+    let content = if text.is_empty() { Value::Null } else { json!(text) };
+    let mut message = json!({ "role": "assistant", "content": content });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = json!(tool_calls);
+    }
+
+    Ok(serde_json::from_value(json!({
+        "id": response["id"],
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": response["model"],
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": finish_reason(&response["stop_reason"]),
+        }],
+        "usage": usage(&response["usage"], None),
+    }))?)
+}
+
+fn finish_reason(stop_reason: &Value) -> Value {
+    match stop_reason.as_str() {
+        Some("max_tokens") => json!("length"),
+        Some("tool_use") => json!("tool_calls"),
+        Some("refusal") => json!("content_filter"),
+        Some(_) => json!("stop"),
+        None => Value::Null,
+    }
+}
+
+/// Prompt tokens include cached input, which Anthropic reports separately.
+fn usage(usage: &Value, prompt_tokens: Option<u64>) -> Value {
+    let prompt_tokens = prompt_tokens.unwrap_or_else(|| input_tokens(usage));
+    let completion_tokens = usage["output_tokens"].as_u64().unwrap_or_default();
+
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+fn input_tokens(usage: &Value) -> u64 {
+    ["input_tokens", "cache_creation_input_tokens", "cache_read_input_tokens"].iter()
+        .filter_map(|field| usage[field].as_u64())
+        .sum()
+}
+
+/// Turns Messages API stream events into chat completion chunks.
+#[derive(Default)]
+struct StreamTranslator {
+    id: String,
+    model: String,
+    input_tokens: u64,
+    /// Tool call index by content block index.
+    tool_calls: HashMap<u64, usize>,
+}
+
+impl StreamTranslator {
+    fn translate(&mut self, event: &str) -> Result<Option<CreateChatCompletionStreamResponse>> {
+        let data = event.lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect::<Vec<_>>()
+            .join("\n");
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let event = serde_json::from_str::<Value>(&data)?;
+
+        let block = event["index"].as_u64().unwrap_or_default();
+        match event["type"].as_str().unwrap_or_default() {
+            "message_start" => {
+                self.id = event["message"]["id"].as_str().unwrap_or_default().to_string();
+                self.model = event["message"]["model"].as_str().unwrap_or_default().to_string();
+                self.input_tokens = input_tokens(&event["message"]["usage"]);
+                self.chunk(json!({ "role": "assistant" }), Value::Null, Value::Null).map(Some)
+            }
+            "content_block_start" if event["content_block"]["type"] == "tool_use" => {
+                let index = self.tool_calls.len();
+                self.tool_calls.insert(block, index);
+                self.chunk(json!({ "tool_calls": [{
+                    "index": index,
+                    "id": event["content_block"]["id"],
+                    "type": "function",
+                    "function": { "name": event["content_block"]["name"], "arguments": "" },
+                }] }), Value::Null, Value::Null).map(Some)
+            }
+            "content_block_delta" => match event["delta"]["type"].as_str() {
+                Some("text_delta") => self.chunk(json!({ "content": event["delta"]["text"] }), Value::Null, Value::Null).map(Some),
+                Some("input_json_delta") => {
+                    let index = self.tool_calls.get(&block)
+                        .ok_or_else(|| anyhow!("Tool input for unknown content block {}", block))?;
+                    self.chunk(json!({ "tool_calls": [{
+                        "index": index,
+                        "function": { "arguments": event["delta"]["partial_json"] },
+                    }] }), Value::Null, Value::Null).map(Some)
+                }
+                _ => Ok(None),
+            },
+            "message_delta" => {
+                let usage = usage(&event["usage"], Some(self.input_tokens));
+                self.chunk(json!({}), finish_reason(&event["delta"]["stop_reason"]), usage).map(Some)
+            }
+            "error" => Err(anyhow!("Anthropic stream error: {}", event["error"]["message"].as_str().unwrap_or("no details"))),
+            _ => Ok(None),
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Value, usage: Value) -> Result<CreateChatCompletionStreamResponse> {
+        completion_chunk(&self.id, &self.model, delta, finish_reason, usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::FinishReason;
+
+    fn request(value: Value) -> CreateChatCompletionRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_request_translation() {
+        let body = anthropic_request(&request(json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 1000,
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Weather in Oslo?" },
+                { "role": "assistant", "content": null, "tool_calls": [
+                    { "id": "call_1", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" } }
+                ] },
+                { "role": "tool", "tool_call_id": "call_1", "content": "rain" },
+                { "role": "system", "content": "Answer in English." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "And this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+                ] }
+            ],
+            "tools": [{ "type": "function", "function": { "name": "weather", "parameters": { "type": "object" } } }],
+            "tool_choice": "auto"
+        }))).unwrap();
+
+        assert_eq!(body["system"], "Be brief.\n\nAnswer in English.");
+        assert_eq!(body["max_tokens"], 1000);
+        assert_eq!(body["tools"][0]["input_schema"], json!({ "type": "object" }));
+        assert_eq!(body["tool_choice"], json!({ "type": "auto" }));
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["input"], json!({ "city": "Oslo" }));
+        // The tool result and the next user message share one user turn
+        assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+        assert_eq!(messages[2]["content"][1]["text"], "And this?");
+        assert_eq!(messages[2]["content"][2]["source"]["media_type"], "image/png");
+    }
+
+    #[test]
+    fn test_response_translation() {
+        let response = openai_response(&json!({
+            "id": "msg_1",
+            "model": "claude-sonnet-4",
+            "content": [
+                { "type": "text", "text": "Checking." },
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } }
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 10, "cache_read_input_tokens": 5, "output_tokens": 7 }
+        })).unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(choice.message.content.as_deref(), Some("Checking."));
+        assert_eq!(choice.message.tool_calls.as_ref().unwrap()[0].function.arguments, "{\"city\":\"Oslo\"}");
+
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (15, 7, 22));
+    }
+
+    #[test]
+    fn test_stream_translation() {
+        let mut translator = StreamTranslator::default();
+        let events = [
+            r#"data: {"type":"message_start","message":{"id":"msg_1","model":"claude","usage":{"input_tokens":4}}}"#,
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"weather"}}"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\""}}"#,
+            r#"data: {"type":"ping"}"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":9}}"#,
+        ];
+        let chunks = events.iter()
+            .filter_map(|event| translator.translate(&format!("event: x\n{}", event)).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(chunks.len(), 4);
+        let call = &chunks[1].choices[0].delta.tool_calls.as_ref().unwrap()[0];
+        assert_eq!((call.index, call.id.as_deref()), (0, Some("toolu_1")));
+        assert_eq!(chunks[3].choices[0].finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(chunks[3].usage.as_ref().unwrap().total_tokens, 13);
+
+        assert!(translator.translate(r#"data: {"type":"error","error":{"message":"overloaded"}}"#).is_err());
+    }
+}
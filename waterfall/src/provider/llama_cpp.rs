@@ -0,0 +1,107 @@
+use std::sync::Mutex;
+
+use waterfall_core::ToolCallMode;
+
+use anyhow::Result;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use serde_json::Value;
+
+use super::{
+    parse_prompted_tool_calls, plan_request, response_stream, tools_as_prompt, CompletionStream, LlmProvider,
+    ModelCapabilities, OpenAiProvider, ProviderError,
+};
+
+/// A llama.cpp server, through its OpenAI-compatible endpoints.
+///
+/// A server runs a single model, so capabilities are read once from `/props`.
+pub struct LlamaCppProvider {
+    inner: OpenAiProvider,
+    http: reqwest::Client,
+    base_url: String,
+    tool_calls: ToolCallMode,
+    capabilities: Mutex<Option<ModelCapabilities>>,
+}
+
+impl LlamaCppProvider {
+    /// `base_url` defaults to the server's local address. `api_key` is only
+    /// needed when the server was started with `--api-key`.
+    pub fn new(http: reqwest::Client, base_url: Option<&str>, api_key: Option<&str>, tool_calls: ToolCallMode) -> Self {
+        let base_url = base_url.unwrap_or("http://localhost:8080").trim_end_matches('/').to_string();
+
+        Self {
+            inner: OpenAiProvider::new(http.clone(), Some(&format!("{}/v1", base_url)), api_key.unwrap_or("no-key")),
+            http,
+            base_url,
+            tool_calls,
+            capabilities: Mutex::new(None),
+        }
+    }
+
+    async fn prepare(&self, request: CreateChatCompletionRequest) -> Result<(CreateChatCompletionRequest, bool)> {
+        let capabilities = self.capabilities(&request.model).await?;
+        if plan_request(&request, &capabilities, self.tool_calls)? {
+            Ok((tools_as_prompt(&request)?, true))
+        } else {
+            Ok((request, false))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for LlamaCppProvider {
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        let (request, prompted) = self.prepare(request).await?;
+        let response = self.inner.complete(request).await?;
+
+        if prompted {
+            parse_prompted_tool_calls(response)
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn stream(&self, request: CreateChatCompletionRequest) -> Result<CompletionStream> {
+        let (request, prompted) = self.prepare(request).await?;
+
+        // Prompted tool calls can only be recognized in the whole answer
+        if prompted {
+            return response_stream(parse_prompted_tool_calls(self.inner.complete(request).await?)?);
+        }
+        self.inner.stream(request).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        self.inner.list_models().await
+    }
+
+    /// Tool calling needs a chat template that handles tools, which the
+    /// server only applies when started with `--jinja`.
+    async fn capabilities(&self, _model: &str) -> Result<ModelCapabilities> {
+        if let Some(capabilities) = *self.capabilities.lock().unwrap() {
+            return Ok(capabilities);
+        }
+
+        let props = self.http.get(format!("{}/props", self.base_url)).send().await
+            .map_err(|source| ProviderError::Unreachable { provider: "llama.cpp", base_url: self.base_url.clone(), source })?
+            .error_for_status()?
+            .json::<Value>().await?;
+
+        let capabilities = llama_cpp_capabilities(&props);
+        *self.capabilities.lock().unwrap() = Some(capabilities);
+        Ok(capabilities)
+    }
+}
+
+/// Reads capabilities from a `/props` response. Servers that do not report
+/// template capabilities are judged by whether their template mentions tools.
+pub fn llama_cpp_capabilities(props: &Value) -> ModelCapabilities {
+    let caps = &props["chat_template_caps"];
+    let tools = caps["supports_tool_calls"].as_bool()
+        .or_else(|| caps["supports_tools"].as_bool())
+        .unwrap_or_else(|| props["chat_template"].as_str().is_some_and(|template| template.contains("tools")));
+
+    ModelCapabilities {
+        tools,
+        vision: props["modalities"]["vision"].as_bool().unwrap_or(false),
+    }
+}
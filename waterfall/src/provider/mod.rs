@@ -0,0 +1,243 @@
+mod anthropic;
+mod llama_cpp;
+mod ollama;
+mod openai;
+mod tool_prompt;
+
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use waterfall_core::{ProviderConfig, ToolCallMode};
+
+use anyhow::{anyhow, Result};
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub use anthropic::*;
+pub use llama_cpp::*;
+pub use ollama::*;
+pub use openai::*;
+pub use tool_prompt::*;
+
+pub type CompletionStream = BoxStream<'static, Result<CreateChatCompletionStreamResponse>>;
+
+/// What a model can do beyond plain text chat.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    pub tools: bool,
+    pub vision: bool,
+}
+
+impl ModelCapabilities {
+    pub const ALL: Self = Self { tools: true, vision: true };
+}
+
+/// An inference API the runtime can send turns to.
+///
+/// Requests and responses use the OpenAI chat completion types, which every
+/// provider translates to and from its own wire format.
+#[async_trait::async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse>;
+
+    /// Streams the response as OpenAI-style chunks.
+    async fn stream(&self, request: CreateChatCompletionRequest) -> Result<CompletionStream>;
+
+    /// Ids of the models the provider serves.
+    async fn list_models(&self) -> Result<Vec<String>>;
+
+    /// Hosted APIs are assumed to support everything the runtime sends.
+    async fn capabilities(&self, _model: &str) -> Result<ModelCapabilities> {
+        Ok(ModelCapabilities::ALL)
+    }
+}
+
+/// Builds the provider an agent is configured for, sending through `http`.
+pub fn build_provider(config: &ProviderConfig, http: &reqwest::Client) -> Result<Arc<dyn LlmProvider>> {
+    let provider: Arc<dyn LlmProvider> = match config {
+        ProviderConfig::Default => {
+            let base_url = env::var("OPENAI_BASE_URL").ok();
+            Arc::new(OpenAiProvider::new(http.clone(), base_url.as_deref(), &api_key("OPENAI_API_KEY")?))
+        }
+        ProviderConfig::OpenAi { base_url, api_key_env } => {
+            let key = api_key(api_key_env.as_deref().unwrap_or("OPENAI_API_KEY"))?;
+            Arc::new(OpenAiProvider::new(http.clone(), base_url.as_deref(), &key))
+        }
+        ProviderConfig::Anthropic { base_url, api_key_env } => {
+            let key = api_key(api_key_env.as_deref().unwrap_or("ANTHROPIC_API_KEY"))?;
+            Arc::new(AnthropicProvider::new(http.clone(), base_url.as_deref(), &key))
+        }
+        ProviderConfig::Ollama { base_url, tool_calls } => {
+            Arc::new(OllamaProvider::new(http.clone(), base_url.as_deref(), *tool_calls))
+        }
+        ProviderConfig::LlamaCpp { base_url, api_key_env, tool_calls } => {
+            let key = api_key_env.as_deref().map(api_key).transpose()?;
+            Arc::new(LlamaCppProvider::new(http.clone(), base_url.as_deref(), key.as_deref(), *tool_calls))
+        }
+    };
+
+    Ok(provider)
+}
+
+/// A provider call that failed before the model answered.
+#[derive(Debug, thiserror::Error)]
+pub enum ProviderError {
+    #[error("{provider} server at {base_url} is not reachable: {source}")]
+    Unreachable { provider: &'static str, base_url: String, source: reqwest::Error },
+    #[error("{provider} error {status}: {message}")]
+    Status { provider: &'static str, status: reqwest::StatusCode, message: String },
+}
+
+/// Whether sending the request again may succeed: the server could not be
+/// reached, timed out, was overloaded or rate limited the request.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(error) = cause.downcast_ref::<ProviderError>() {
+            return match error {
+                ProviderError::Unreachable { .. } => true,
+                ProviderError::Status { status, .. } => transient_status(*status),
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return error.is_connect() || error.is_timeout() || error.status().is_some_and(transient_status);
+        }
+        // The OpenAI client retries rate limits itself and reports no status
+        matches!(
+            cause.downcast_ref::<OpenAIError>(),
+            Some(OpenAIError::ApiError(error)) if error.r#type.as_deref() == Some("server_error")
+        )
+    })
+}
+
+fn transient_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn api_key(variable: &str) -> Result<String> {
+    env::var(variable).map_err(|_| anyhow!("Environment variable {} with the API key is not set", variable))
+}
+
+/// Rejects requests the model cannot serve and decides how tools are
+/// offered. Returns true when they must be described in the prompt.
+pub fn plan_request(request: &CreateChatCompletionRequest, capabilities: &ModelCapabilities, mode: ToolCallMode) -> Result<bool> {
+    let has_images = request.messages.iter().any(|message| matches!(
+        message,
+        ChatCompletionRequestMessage::User(user) if matches!(
+            &user.content,
+            ChatCompletionRequestUserMessageContent::Array(parts)
+                if parts.iter().any(|part| matches!(part, ChatCompletionRequestUserMessageContentPart::ImageUrl(_)))
+        )
+    ));
+    if has_images && !capabilities.vision {
+        return Err(anyhow!("Model {} does not accept image input", request.model));
+    }
+
+    if request.tools.as_ref().is_none_or(|tools| tools.is_empty()) {
+        return Ok(false);
+    }
+
+    match mode {
+        ToolCallMode::Auto => Ok(!capabilities.tools),
+        ToolCallMode::Prompt => Ok(true),
+        ToolCallMode::Native if capabilities.tools => Ok(false),
+        ToolCallMode::Native => Err(anyhow!(
+            "Model {} does not support native tool calling; use tool_calls: auto or prompt",
+            request.model
+        )),
+    }
+}
+
+/// Ids for tool calls of providers that do not assign their own. Unique
+/// within the process, since results are matched by id across rounds.
+pub(crate) fn tool_call_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("call_{:x}_{}", unix_now(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+pub(crate) fn completion_chunk(id: &str, model: &str, delta: Value, finish_reason: Value, usage: Value) -> Result<CreateChatCompletionStreamResponse> {
+    Ok(serde_json::from_value(json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": unix_now(),
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        "usage": usage,
+    }))?)
+}
+
+/// A whole response as a stream, for providers that cannot stream a request.
+pub(crate) fn response_stream(response: CreateChatCompletionResponse) -> Result<CompletionStream> {
+    let response = serde_json::to_value(&response)?;
+    let message = &response["choices"][0]["message"];
+
+    let mut delta = json!({ "role": "assistant", "content": message["content"] });
+    if let Some(calls) = message["tool_calls"].as_array() {
+        delta["tool_calls"] = calls.iter().enumerate()
+            .map(|(index, call)| {
+                let mut call = call.clone();
+                call["index"] = json!(index);
+                call
+            })
+            .collect();
+    }
+
+    let chunk = completion_chunk(
+        response["id"].as_str().unwrap_or_default(),
+        response["model"].as_str().unwrap_or_default(),
+        delta,
+        response["choices"][0]["finish_reason"].clone(),
+        response["usage"].clone(),
+    );
+    Ok(Box::pin(futures::stream::iter([chunk])))
+}
+
+/// Splits a streamed body at `separator` and translates each event,
+/// skipping those that translate to nothing. Carriage returns are dropped.
+pub(crate) fn event_stream<T, F>(response: reqwest::Response, separator: &'static [u8], translate: F) -> BoxStream<'static, Result<T>>
+where
+    T: Send + 'static,
+    F: FnMut(&str) -> Result<Option<T>> + Send + 'static,
+{
+    let state = (Some(response), Vec::new(), translate);
+    Box::pin(futures::stream::unfold(state, move |(mut response, mut buffer, mut translate)| async move {
+        loop {
+            let event = match buffer.windows(separator.len()).position(|w| w == separator) {
+                Some(end) => {
+                    let event = String::from_utf8_lossy(&buffer[..end]).into_owned();
+                    buffer.drain(..end + separator.len());
+                    event
+                }
+                // The body ended; what is left is the last event
+                None if response.is_none() && !buffer.is_empty() => {
+                    String::from_utf8_lossy(&std::mem::take(&mut buffer)).into_owned()
+                }
+                None => {
+                    match response.as_mut()?.chunk().await {
+                        Ok(Some(bytes)) => buffer.extend(bytes.iter().filter(|b| **b != b'\r')),
+                        Ok(None) => response = None,
+                        Err(e) => return Some((Err(e.into()), (None, Vec::new(), translate))),
+                    }
+                    continue;
+                }
+            };
+
+            match translate(&event) {
+                Ok(Some(item)) => return Some((Ok(item), (response, buffer, translate))),
+                Ok(None) => continue,
+                Err(e) => return Some((Err(e), (None, Vec::new(), translate))),
+            }
+        }
+    }))
+}
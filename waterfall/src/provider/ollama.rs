@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use waterfall_core::ToolCallMode;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use serde_json::{json, Value};
+
+use super::{
+    completion_chunk, event_stream, parse_prompted_tool_calls, plan_request, response_stream, tool_call_id,
+    tools_as_prompt, unix_now, CompletionStream, LlmProvider, ModelCapabilities, ProviderError,
+};
+
+/// A local Ollama server, through its native chat API.
+pub struct OllamaProvider {
+    http: reqwest::Client,
+    base_url: String,
+    tool_calls: ToolCallMode,
+    /// Capabilities by model, looked up once.
+    capabilities: Mutex<HashMap<String, ModelCapabilities>>,
+}
+
+impl OllamaProvider {
+    /// `base_url` defaults to Ollama's local address.
+    pub fn new(http: reqwest::Client, base_url: Option<&str>, tool_calls: ToolCallMode) -> Self {
+        Self {
+            http,
+            base_url: base_url.unwrap_or("http://localhost:11434").trim_end_matches('/').to_string(),
+            tool_calls,
+            capabilities: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the request against the model and rewrites the tools into the
+    /// prompt when needed. Returns the request and whether it was rewritten.
+    async fn prepare(&self, request: CreateChatCompletionRequest) -> Result<(CreateChatCompletionRequest, bool)> {
+        let capabilities = self.capabilities(&request.model).await?;
+        if plan_request(&request, &capabilities, self.tool_calls)? {
+            Ok((tools_as_prompt(&request)?, true))
+        } else {
+            Ok((request, false))
+        }
+    }
+
+    async fn send(&self, body: &Value) -> Result<reqwest::Response> {
+        let response = self.http.post(format!("{}/api/chat", self.base_url)).json(body).send().await
+            .map_err(|source| ProviderError::Unreachable { provider: "Ollama", base_url: self.base_url.clone(), source })?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let error = response.json::<Value>().await.unwrap_or_default();
+        Err(ProviderError::Status {
+            provider: "Ollama",
+            status,
+            message: error["error"].as_str().unwrap_or("no details").to_string(),
+        }.into())
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        let (request, prompted) = self.prepare(request).await?;
+        let response = self.send(&ollama_request(&request, false)?).await?;
+        let response = from_ollama_response(&response.json().await?)?;
+
+        if prompted {
+            parse_prompted_tool_calls(response)
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn stream(&self, request: CreateChatCompletionRequest) -> Result<CompletionStream> {
+        let (request, prompted) = self.prepare(request).await?;
+
+        // Prompted tool calls can only be recognized in the whole answer
+        if prompted {
+            let response = self.send(&ollama_request(&request, false)?).await?;
+            return response_stream(parse_prompted_tool_calls(from_ollama_response(&response.json().await?)?)?);
+        }
+
+        let response = self.send(&ollama_request(&request, true)?).await?;
+        let id = format!("ollama-{}", unix_now());
+        let mut tool_calls = 0;
+
+        Ok(event_stream(response, b"\n", move |line| {
+            if line.trim().is_empty() {
+                return Ok(None);
+            }
+            let line = serde_json::from_str::<Value>(line)?;
+            if let Some(error) = line["error"].as_str() {
+                return Err(anyhow!("Ollama stream error: {}", error));
+            }
+
+            let mut delta = json!({});
+            if let Some(content) = line["message"]["content"].as_str().filter(|c| !c.is_empty()) {
+                delta["content"] = json!(content);
+            }
+            if let Some(calls) = line["message"]["tool_calls"].as_array() {
+                delta["tool_calls"] = calls.iter()
+                    .map(|call| {
+                        let mut call = openai_tool_call(call);
+                        call["index"] = json!(tool_calls);
+                        tool_calls += 1;
+                        call
+                    })
+                    .collect();
+            }
+
+            let done = line["done"].as_bool().unwrap_or(false);
+            if delta.as_object().is_some_and(|d| d.is_empty()) && !done {
+                return Ok(None);
+            }
+
+            let (finish_reason, usage) = if done {
+                (finish_reason(&line, tool_calls > 0), usage(&line))
+            } else {
+                (Value::Null, Value::Null)
+            };
+            completion_chunk(&id, line["model"].as_str().unwrap_or_default(), delta, finish_reason, usage).map(Some)
+        }))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let tags = self.http.get(format!("{}/api/tags", self.base_url)).send().await?
+            .error_for_status()?
+            .json::<Value>().await?;
+
+        Ok(tags["models"].as_array().into_iter().flatten()
+            .filter_map(|model| model["name"].as_str().map(|name| name.to_string()))
+            .collect())
+    }
+
+    /// Read from `/api/show`. Older servers do not list capabilities, so the
+    /// chat template and projector are inspected instead.
+    async fn capabilities(&self, model: &str) -> Result<ModelCapabilities> {
+        if let Some(capabilities) = self.capabilities.lock().unwrap().get(model) {
+            return Ok(*capabilities);
+        }
+
+        let response = self.http.post(format!("{}/api/show", self.base_url))
+            .json(&json!({ "model": model }))
+            .send().await
+            .map_err(|source| ProviderError::Unreachable { provider: "Ollama", base_url: self.base_url.clone(), source })?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!("Model {} is not available on the Ollama server; pull it first", model));
+        }
+        let show = response.error_for_status()?.json::<Value>().await?;
+
+        let capabilities = match show["capabilities"].as_array() {
+            Some(listed) => ModelCapabilities {
+                tools: listed.iter().any(|c| c == "tools"),
+                vision: listed.iter().any(|c| c == "vision"),
+            },
+            None => ModelCapabilities {
+                tools: show["template"].as_str().is_some_and(|template| template.contains(".Tools")),
+                vision: !show["projector_info"].is_null(),
+            },
+        };
+
+        self.capabilities.lock().unwrap().insert(model.to_string(), capabilities);
+        Ok(capabilities)
+    }
+}
+
+/// Translates a chat completion request into an `/api/chat` request.
+pub fn ollama_request(request: &CreateChatCompletionRequest, stream: bool) -> Result<Value> {
+    let openai = serde_json::to_value(request)?;
+
+    // Tool messages name their tool, which Ollama expects instead of an id
+    let mut tool_names = HashMap::new();
+    let mut messages = Vec::new();
+    for message in openai["messages"].as_array().into_iter().flatten() {
+        let role = message["role"].as_str().unwrap_or_default();
+        let mut translated = json!({ "role": role, "content": "" });
+
+        match &message["content"] {
+            Value::String(text) => translated["content"] = json!(text),
+            Value::Array(parts) => {
+                let mut text = Vec::new();
+                let mut images = Vec::new();
+                for part in parts {
+                    match part["type"].as_str() {
+                        Some("image_url") => images.push(inline_image(part["image_url"]["url"].as_str().unwrap_or_default())?),
+                        _ => text.extend(part["text"].as_str()),
+                    }
+                }
+                translated["content"] = json!(text.join("\n"));
+                if !images.is_empty() {
+                    translated["images"] = json!(images);
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(calls) = message["tool_calls"].as_array() {
+            translated["tool_calls"] = calls.iter()
+                .map(|call| {
+                    tool_names.insert(call["id"].as_str().unwrap_or_default().to_string(), call["function"]["name"].clone());
+                    json!({ "function": {
+                        "name": call["function"]["name"],
+                        "arguments": call["function"]["arguments"].as_str()
+                            .and_then(|arguments| serde_json::from_str::<Value>(arguments).ok())
+                            .unwrap_or_else(|| json!({})),
+                    } })
+                })
+                .collect();
+        }
+        if role == "tool" {
+            if let Some(name) = tool_names.get(message["tool_call_id"].as_str().unwrap_or_default()) {
+                translated["tool_name"] = name.clone();
+            }
+        }
+
+        messages.push(translated);
+    }
+
+    let mut options = json!({});
+    for (from, to) in [("temperature", "temperature"), ("top_p", "top_p"), ("seed", "seed")] {
+        if !openai[from].is_null() {
+            options[to] = openai[from].clone();
+        }
+    }
+    if let Some(max_tokens) = openai["max_completion_tokens"].as_u64().or_else(|| openai["max_tokens"].as_u64()) {
+        options["num_predict"] = json!(max_tokens);
+    }
+    match &openai["stop"] {
+        Value::String(stop) => options["stop"] = json!([stop]),
+        Value::Array(stop) => options["stop"] = json!(stop),
+        _ => {}
+    }
+
+    let mut body = json!({
+        "model": openai["model"],
+        "messages": messages,
+        "stream": stream,
+        "options": options,
+    });
+    if let Some(tools) = openai["tools"].as_array().filter(|tools| !tools.is_empty()) {
+        body["tools"] = json!(tools);
+    }
+    match openai["response_format"]["type"].as_str() {
+        Some("json_schema") => body["format"] = openai["response_format"]["json_schema"]["schema"].clone(),
+        Some("json_object") => body["format"] = json!("json"),
+        _ => {}
+    }
+
+    Ok(body)
+}
+
+/// Ollama only takes images as bare base64.
+fn inline_image(url: &str) -> Result<String> {
+    url.strip_prefix("data:")
+        .and_then(|data| data.split_once(";base64,"))
+        .map(|(_, data)| data.to_string())
+        .ok_or_else(|| anyhow!("Ollama only accepts inline images, not {}", url))
+}
+
+/// Translates an `/api/chat` response into a chat completion response.
+pub fn from_ollama_response(response: &Value) -> Result<CreateChatCompletionResponse> {
+    let tool_calls = response["message"]["tool_calls"].as_array().cloned().unwrap_or_default();
+    let content = response["message"]["content"].as_str().filter(|c| !c.is_empty());
+
+    let mut message = json!({ "role": "assistant", "content": content });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = tool_calls.iter().map(openai_tool_call).collect();
+    }
+
+    Ok(serde_json::from_value(json!({
+        "id": format!("ollama-{}", unix_now()),
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": response["model"],
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": finish_reason(response, !tool_calls.is_empty()),
+        }],
+        "usage": usage(response),
+    }))?)
+}
+
+fn openai_tool_call(call: &Value) -> Value {
+    json!({
+        "id": tool_call_id(),
+        "type": "function",
+        "function": {
+            "name": call["function"]["name"],
+            "arguments": call["function"]["arguments"].to_string(),
+        },
+    })
+}
+
+fn finish_reason(response: &Value, tool_calls: bool) -> Value {
+    match response["done_reason"].as_str() {
+        _ if tool_calls => json!("tool_calls"),
+        Some("length") => json!("length"),
+        _ => json!("stop"),
+    }
+}
+
+fn usage(response: &Value) -> Value {
+    let prompt_tokens = response["prompt_eval_count"].as_u64().unwrap_or_default();
+    let completion_tokens = response["eval_count"].as_u64().unwrap_or_default();
+
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+
+    fn request(value: Value) -> CreateChatCompletionRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn weather_request() -> CreateChatCompletionRequest {
+        request(json!({
+            "model": "llama3",
+            "messages": [{ "role": "user", "content": "Weather in Oslo?" }],
+            "tools": [{ "type": "function", "function": { "name": "weather", "parameters": { "type": "object" } } }]
+        }))
+    }
+
+    /// Serves a model without native tool calling that answers with a
+    /// prompted tool call.
+    async fn prompting_model() -> String {
+        stand_in::ollama(&["completion"], |body| {
+            assert!(body.get("tools").is_none());
+            assert!(body["messages"][0]["content"].as_str().unwrap().contains("- weather"));
+            Ok("{\"tool_calls\": [{\"name\": \"weather\", \"arguments\": {\"city\": \"Oslo\"}}]}".to_string())
+        }).await
+    }
+
+    #[tokio::test]
+    async fn test_prompted_tool_calls() {
+        let base_url = prompting_model().await;
+
+        let provider = OllamaProvider::new(reqwest::Client::new(), Some(&base_url), ToolCallMode::Auto);
+        let response = provider.complete(weather_request()).await.unwrap();
+        let call = &response.choices[0].message.tool_calls.as_ref().unwrap()[0];
+        assert_eq!(call.function.name, "weather");
+        assert_eq!(call.function.arguments, "{\"city\":\"Oslo\"}");
+        assert_eq!(response.usage.unwrap().total_tokens, 4);
+
+        let provider = OllamaProvider::new(reqwest::Client::new(), Some(&base_url), ToolCallMode::Native);
+        let error = provider.complete(weather_request()).await.unwrap_err();
+        assert!(error.to_string().contains("does not support native tool calling"));
+    }
+
+    #[test]
+    fn test_request_translation() {
+        let body = ollama_request(&request(json!({
+            "model": "llava",
+            "max_tokens": 100,
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+                ] },
+                { "role": "assistant", "tool_calls": [
+                    { "id": "call_1", "type": "function", "function": { "name": "lookup", "arguments": "{\"q\":\"cat\"}" } }
+                ] },
+                { "role": "tool", "tool_call_id": "call_1", "content": "a cat" }
+            ]
+        })), false).unwrap();
+
+        assert_eq!(body["options"]["num_predict"], 100);
+        assert_eq!(body["messages"][0]["images"], json!(["AAAA"]));
+        assert_eq!(body["messages"][1]["tool_calls"][0]["function"]["arguments"], json!({ "q": "cat" }));
+        assert_eq!(body["messages"][2]["tool_name"], "lookup");
+
+        let remote = request(json!({
+            "model": "llava",
+            "messages": [{ "role": "user", "content": [{ "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } }] }]
+        }));
+        assert!(ollama_request(&remote, false).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_openai::{config::OpenAIConfig, Client};
+use futures::StreamExt;
+
+use super::{CompletionStream, LlmProvider};
+
+/// Any server implementing the OpenAI chat completions API.
+pub struct OpenAiProvider {
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAiProvider {
+    /// `base_url` defaults to the OpenAI API.
+    pub fn new(http: reqwest::Client, base_url: Option<&str>, api_key: &str) -> Self {
+        let mut config = OpenAIConfig::new().with_api_key(api_key);
+        if let Some(base_url) = base_url {
+            config = config.with_api_base(base_url);
+        }
+
+        Self { client: Client::build(http, config, Default::default()) }
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        Ok(self.client.chat().create(request).await?)
+    }
+
+    async fn stream(&self, mut request: CreateChatCompletionRequest) -> Result<CompletionStream> {
+        request.stream = Some(true);
+        let stream = self.client.chat().create_stream(request).await?;
+        Ok(stream.map(|chunk| chunk.map_err(Into::into)).boxed())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let models = self.client.models().list().await?;
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+}
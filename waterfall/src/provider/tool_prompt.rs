@@ -0,0 +1,178 @@
+use anyhow::Result;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse, FinishReason};
+use serde_json::{json, Value};
+
+use crate::strip_code_fence;
+use super::tool_call_id;
+
+/// Rewrites a request for a model without native tool calling: the tools
+/// are described in the system prompt, earlier calls become the JSON the
+/// model is asked to write, and tool results become user messages.
+pub fn tools_as_prompt(request: &CreateChatCompletionRequest) -> Result<CreateChatCompletionRequest> {
+    let mut value = serde_json::to_value(request)?;
+    let object = value.as_object_mut().expect("requests serialize to objects");
+    let tools = object.remove("tools").unwrap_or_default();
+    object.remove("tool_choice");
+    object.remove("parallel_tool_calls");
+
+    let mut messages: Vec<Value> = Vec::new();
+    for message in value["messages"].as_array().into_iter().flatten() {
+        let message = match message["role"].as_str() {
+            Some("assistant") if message["tool_calls"].is_array() => {
+                let calls = message["tool_calls"].as_array().into_iter().flatten()
+                    .map(|call| json!({
+                        "name": call["function"]["name"],
+                        "arguments": call["function"]["arguments"].as_str()
+                            .and_then(|arguments| serde_json::from_str::<Value>(arguments).ok())
+                            .unwrap_or_else(|| json!({})),
+                    }))
+                    .collect::<Vec<_>>();
+                json!({ "role": "assistant", "content": json!({ "tool_calls": calls }).to_string() })
+            }
+            Some("tool") => json!({
+                "role": "user",
+                "content": format!("Result of tool call {}:\n{}", message["tool_call_id"].as_str().unwrap_or_default(), text(&message["content"])),
+            }),
+            _ => message.clone(),
+        };
+
+        // Many local chat templates require roles to alternate
+        match messages.last_mut() {
+            Some(last) if last["role"] == "user" && message["role"] == "user" && last["content"].is_string() && message["content"].is_string() => {
+                last["content"] = json!(format!("{}\n\n{}", text(&last["content"]), text(&message["content"])));
+            }
+            _ => messages.push(message),
+        }
+    }
+
+    let instructions = tool_instructions(&tools);
+    match messages.first_mut() {
+        Some(first) if first["role"] == "system" => {
+            first["content"] = json!(format!("{}\n\n{}", text(&first["content"]), instructions));
+        }
+        _ => messages.insert(0, json!({ "role": "system", "content": instructions })),
+    }
+    value["messages"] = json!(messages);
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// System prompt section listing the tools and the call format.
+pub fn tool_instructions(tools: &Value) -> String {
+    let listing = tools.as_array().into_iter().flatten()
+        .map(|tool| format!(
+            "- {}: {}\n  parameters: {}",
+            tool["function"]["name"].as_str().unwrap_or_default(),
+            tool["function"]["description"].as_str().unwrap_or_default(),
+            tool["function"]["parameters"],
+        ))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "You can call these tools:\n{}\n\nTo call tools, reply with only a JSON object of the form \
+        {{\"tool_calls\": [{{\"name\": \"<tool>\", \"arguments\": {{...}}}}]}} and nothing else. \
+        The results will be sent to you in the next message. When no tool is needed, answer normally.",
+        listing
+    )
+}
+
+/// Turns answers written in the format of `tool_instructions` into tool calls.
+pub fn parse_prompted_tool_calls(response: CreateChatCompletionResponse) -> Result<CreateChatCompletionResponse> {
+    let mut value = serde_json::to_value(&response)?;
+
+    for choice in value["choices"].as_array_mut().into_iter().flatten() {
+        let Some(content) = choice["message"]["content"].as_str() else {
+            continue;
+        };
+        let Ok(answer) = serde_json::from_str::<Value>(strip_code_fence(content)) else {
+            continue;
+        };
+        let Some(calls) = answer["tool_calls"].as_array().filter(|calls| {
+            !calls.is_empty() && calls.iter().all(|call| call["name"].is_string())
+        }) else {
+            continue;
+        };
+
+        choice["message"]["tool_calls"] = calls.iter()
+            .map(|call| json!({
+                "id": tool_call_id(),
+                "type": "function",
+                "function": {
+                    "name": call["name"],
+                    "arguments": match &call["arguments"] {
+                        Value::Null => "{}".to_string(),
+                        arguments => arguments.to_string(),
+                    },
+                },
+            }))
+            .collect();
+        choice["message"]["content"] = Value::Null;
+        choice["finish_reason"] = json!(FinishReason::ToolCalls);
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+fn text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tools_as_prompt() {
+        let request = serde_json::from_value::<CreateChatCompletionRequest>(json!({
+            "model": "phi",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Weather in Oslo and Bergen?" },
+                { "role": "assistant", "tool_calls": [
+                    { "id": "a", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" } },
+                    { "id": "b", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Bergen\"}" } }
+                ] },
+                { "role": "tool", "tool_call_id": "a", "content": "rain" },
+                { "role": "tool", "tool_call_id": "b", "content": "sun" }
+            ],
+            "tools": [{ "type": "function", "function": { "name": "weather", "description": "Current weather", "parameters": { "type": "object" } } }],
+            "tool_choice": "auto"
+        })).unwrap();
+
+        let prompted = serde_json::to_value(tools_as_prompt(&request).unwrap()).unwrap();
+        assert!(prompted.get("tools").is_none());
+
+        let messages = prompted["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0]["content"].as_str().unwrap().starts_with("Be brief.\n\nYou can call these tools:\n- weather: Current weather"));
+        assert_eq!(messages[2]["content"], json!({ "tool_calls": [
+            { "name": "weather", "arguments": { "city": "Oslo" } },
+            { "name": "weather", "arguments": { "city": "Bergen" } }
+        ] }).to_string());
+        assert_eq!(messages[3]["content"], "Result of tool call a:\nrain\n\nResult of tool call b:\nsun");
+    }
+
+    #[test]
+    fn test_parse_prompted_tool_calls() {
+        let response = |content: &str| serde_json::from_value::<CreateChatCompletionResponse>(json!({
+            "id": "1", "object": "chat.completion", "created": 0, "model": "phi",
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }]
+        })).unwrap();
+
+        let parsed = parse_prompted_tool_calls(response("```json\n{\"tool_calls\": [{\"name\": \"weather\"}]}\n```")).unwrap();
+        let call = &parsed.choices[0].message.tool_calls.as_ref().unwrap()[0];
+        assert_eq!((call.function.name.as_str(), call.function.arguments.as_str()), ("weather", "{}"));
+        assert_eq!(parsed.choices[0].finish_reason, Some(FinishReason::ToolCalls));
+
+        let plain = parse_prompted_tool_calls(response("It is raining.")).unwrap();
+        assert!(plain.choices[0].message.tool_calls.is_none());
+    }
+}
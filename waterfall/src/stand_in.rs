@@ -0,0 +1,41 @@
+//! Local servers standing in for the services tests talk to.
+
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
+use serde_json::{json, Value};
+
+/// Serves `app` on a random local port and returns its base URL.
+pub(crate) async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    format!("http://{}", address)
+}
+
+/// Serves an Ollama model with `capabilities` that answers each chat request
+/// body with `answer`, or fails it with the returned status.
+pub(crate) async fn ollama<F>(capabilities: &'static [&'static str], answer: F) -> String
+where
+    F: Fn(&Value) -> Result<String, StatusCode> + Send + Sync + 'static,
+{
+    let answer = Arc::new(answer);
+    let app = Router::new()
+        .route("/api/show", post(move || async move { Json(json!({ "capabilities": capabilities })) }))
+        .route("/api/chat", post(move |Json(body): Json<Value>| {
+            let response = match answer(&body) {
+                Ok(content) => Json(json!({
+                    "model": body["model"],
+                    "message": { "role": "assistant", "content": content },
+                    "done": true,
+                    "prompt_eval_count": 3,
+                    "eval_count": 1
+                })).into_response(),
+                Err(status) => (status, "stand-in failure").into_response(),
+            };
+            async move { response }
+        }));
+
+    serve(app).await
+}